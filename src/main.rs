@@ -1,4 +1,4 @@
-/**
+/*!
  * Echo Macro - OpenDeck Plugin
  * 
  * This plugin types pre-recorded text when a Stream Deck button is pressed.
@@ -9,290 +9,7397 @@
 
 use openaction::{
     init_plugin,
+    OUTBOUND_EVENT_MANAGER,
     ActionEventHandler,
     GlobalEventHandler,
     KeyEvent,
     AppearEvent,
     DidReceiveSettingsEvent,
+    DidReceiveGlobalSettingsEvent,
     OutboundEventManager,
     EventHandlerResult,
 };
 use serde::{Deserialize, Serialize};
-use std::process::Command;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::collections::HashMap;
 use std::env;
-use log::{info, debug, error};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::future::Future;
+use std::pin::Pin;
+use log::{info, debug, warn, error};
 use anyhow::Result;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+use zeroize::Zeroizing;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+/// Plugin UUID, the prefix of every action UUID
+/// Rebranded builds set ECHO_MACRO_PLUGIN_ID at compile time - manifest.json must match
+const PLUGIN_ID: &str = match option_env!("ECHO_MACRO_PLUGIN_ID") {
+    Some(id) => id,
+    None => "net.ashurtech.echo-macro",
+};
+
+/// Action names - PLUGIN_ID plus the name must match the "UUID" entries in manifest.json
+const TYPE_TEXT_ACTION: &str = "type-text";
+/// Toggle button that blocks all typing while on, e.g. during screen shares
+const PRESENTATION_MODE_ACTION: &str = "presentation-mode";
+/// Checks the setup on press without typing anything
+const HEALTH_CHECK_ACTION: &str = "health-check";
+
+/// Flatpak app id of OpenDeck, used in permission hints
+/// Set ECHO_MACRO_HOST_APP_ID at compile time to change the default
+const DEFAULT_HOST_APP_ID: &str = match option_env!("ECHO_MACRO_HOST_APP_ID") {
+    Some(id) => id,
+    None => "me.amankhanna.opendeck",
+};
 
 /**
  * Settings struct - Configuration data for our action.
  */
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 struct TypeTextSettings {
+    /// None when the text was never set - the button then types the default
+    /// text. A text cleared to "" types nothing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    /// Title shown on the button - a preview of the text when not set
+    #[serde(default)]
+    label: Option<String>,
+    /// Show how often the button was pressed as its title, instead of the label
+    #[serde(default)]
+    show_count: bool,
+    /// Typed right before the text, whatever its source - e.g. "`" or "```\n"
+    #[serde(default)]
+    prefix: String,
+    /// Typed right after the text
+    #[serde(default)]
+    suffix: String,
+    /// Typed instead of everything else when the button is held for
+    /// long_press_ms - the button then types on release
+    #[serde(default)]
+    long_press_text: Option<String>,
+    /// How long a press must last to count as long, in ms - 0 means DEFAULT_LONG_PRESS_MS
+    #[serde(default)]
+    long_press_ms: u32,
+    /// How the text is delivered - "type" (default) or "clipboard_only"
+    #[serde(default)]
+    method: TypeMethod,
+    /// Key pressed right before typing, e.g. "escape" in editors where that
+    /// closes the current undo group - the typed block then undoes on its own
+    #[serde(default)]
+    pre_undo_boundary_key: Option<String>,
+    /// How long each key is held down before release, in milliseconds
+    /// Some games and slow targets miss keys that are released immediately
+    #[serde(default)]
+    key_hold_ms: u32,
+    /// Where the text comes from - when set this replaces the inline text
+    #[serde(default)]
+    source: Option<TextSource>,
+    /// A text file whose contents are typed, read on every press - `~` and
+    /// `$HOME` are expanded. Replaces the inline text; a `source` still wins
+    #[serde(default)]
+    file_path: Option<String>,
+    /// Replacements applied to the text before it is delivered, e.g.
+    /// {"©": "(c)"} for targets that can't render the symbol
+    #[serde(default)]
+    substitutions: HashMap<String, String>,
+    /// Check that a window has input focus before typing (X11 only, best effort)
+    #[serde(default)]
+    check_input_target: bool,
+    /// Skip typing when the X11 primary selection already equals the text
+    #[serde(default)]
+    skip_if_selection_matches: bool,
+    /// Collect presses within this window (ms) and type them in a single
+    /// ydotool call once it closes - 0 types every press immediately
+    #[serde(default)]
+    batch_window_ms: u32,
+    /// Debug aid: save a screenshot here right after typing (grim/scrot)
+    /// Nothing is ever captured unless this is set
+    #[serde(default)]
+    debug_screenshot_dir: Option<String>,
+    /// Name of a snippet in the shared snippets file - takes precedence over `text`
+    #[serde(default)]
+    snippet_ref: Option<String>,
+    /// Play a sound after a successful press, for when the deck isn't in view
+    #[serde(default)]
+    bell_on_success: bool,
+    /// Play a (different) sound when a press fails
+    #[serde(default)]
+    bell_on_failure: bool,
+    /// Key held down in timed_hold mode, e.g. "right"
+    #[serde(default)]
+    hold_key: Option<String>,
+    /// How long timed_hold keeps the key down, in milliseconds
+    #[serde(default)]
+    hold_duration_ms: u32,
+    /// Collapse runs of spaces and tabs into a single space before typing
+    #[serde(default)]
+    collapse_whitespace: bool,
+    /// With collapse_whitespace, treat newlines as whitespace too (joins lines)
+    #[serde(default)]
+    collapse_newlines: bool,
+    /// Put this before every line of the text, e.g. "> " to quote in chat
+    /// or "// " to comment out code
+    #[serde(default)]
+    line_prefix: Option<String>,
+    /// Whether empty lines get the line prefix too
+    #[serde(default)]
+    empty_lines: EmptyLinePolicy,
+    /// Break words longer than this many characters, for fields that reject
+    /// or can't wrap long unbroken tokens - 0 (the default) leaves words alone
+    #[serde(default)]
+    max_word_length: u32,
+    /// What goes between the pieces of a long word - unset means a zero-width space
+    #[serde(default)]
+    word_break: Option<String>,
+    /// Terminal device for the virtual_terminal method, e.g. "/dev/tty3"
+    #[serde(default)]
+    vt_device: Option<String>,
+    /// After a successful press, lock the button for this long (ms): its title
+    /// shows a lock and presses are ignored. Guards against double submission.
+    #[serde(default)]
+    post_type_lock_ms: u32,
+    /// Presses closer than this to the one before (ms) are ignored, against
+    /// bouncing buttons - None means DEFAULT_DEBOUNCE_MS, 0 turns it off.
+    /// Presses while the last one is still typing are always ignored
+    #[serde(default)]
+    debounce_ms: Option<u32>,
+    /// Flash the alert when a press is ignored by the debounce
+    #[serde(default)]
+    alert_on_ignored_press: bool,
+    /// What clipboard_only does when the clipboard can't be set
+    #[serde(default)]
+    on_clipboard_failure: ClipboardFailurePolicy,
+    /// Randomly vary each configured delay (key hold, hold duration) by up to
+    /// this percentage, for targets that flag perfectly regular timing
+    #[serde(default)]
+    jitter_pct: u8,
+    /// Replace `{date}`, `{time}`, `{datetime}` and `{clipboard}` in the text
+    /// at press time - `{{` and `}}` type literal braces
+    #[serde(default)]
+    expand_variables: bool,
+    /// strftime-style format for `{date}` and the date part of `{datetime}`
+    /// Unset means ISO 8601, e.g. 2026-10-14
+    #[serde(default)]
+    date_format: Option<String>,
+    /// strftime-style format for `{time}` and the time part of `{datetime}`
+    /// Unset means ISO 8601, e.g. 09:30:00
+    #[serde(default)]
+    time_format: Option<String>,
+    /// Treat `{name}` / `{name:03}` in the text as counters that advance on
+    /// every press, e.g. "item-{n:03}" types item-001, item-002, ...
+    #[serde(default)]
+    counter_template: bool,
+    /// Keep counter values in the button's settings so they survive restarts
+    /// Otherwise counters start over whenever the button disappears
+    #[serde(default)]
+    persist_counters: bool,
+    /// Last value of each counter - written by the plugin when persisting
+    #[serde(default)]
+    counter_state: HashMap<String, u64>,
+    /// Start counters over whenever the button appears - by default a repeated
+    /// appear (e.g. a profile switch) keeps them
+    #[serde(default)]
+    reset_state_on_appear: bool,
+    /// Check that ydotoold's socket exists before each ydotool press, to fail
+    /// fast with a clear hint instead of spawning ydotool against no daemon
+    #[serde(default)]
+    preflight_daemon_check: bool,
+    /// Command line used to type, per backend, with `{text}` standing for the
+    /// text - e.g. {"ydotool": "ydotool type --key-delay 20 {text}"}
+    #[serde(default)]
+    arg_templates: HashMap<String, String>,
+    /// Enter characters ydotool can't type (emoji, most accents) with the
+    /// GTK/IBus Ctrl+Shift+U sequence instead. Only GTK/IBus targets support it.
+    #[serde(default)]
+    gtk_unicode_input: bool,
+    /// What the "type" method does with text ydotool can't type: type it
+    /// anyway ("type"), paste it ("paste"), or paste unless gtk_unicode_input
+    /// handles it ("auto")
+    #[serde(default)]
+    unicode_strategy: UnicodeStrategy,
+    /// Delay between typed characters in milliseconds (ydotool --key-delay)
+    /// Fast targets like games and terminals drop characters typed instantly
+    #[serde(default)]
+    delay_ms: Option<u32>,
+    /// Send ydotoold a no-op when the button appears, so the first press
+    /// after idle doesn't pay for waking it up
+    #[serde(default)]
+    warm_daemon_on_appear: bool,
+    /// What a FIFO source press does when no message (or an empty one) arrives
+    #[serde(default)]
+    on_empty_source: EmptySourcePolicy,
+    /// Press Enter once the text has been typed or pasted, e.g. to send a
+    /// chat message or run a command - only if the text went through
+    #[serde(default)]
+    append_enter: bool,
+    /// Local URL told about every press (masked text, length, outcome) -
+    /// the same allowlist as URL sources applies
+    #[serde(default)]
+    webhook_url: Option<String>,
+    /// Key combination pressed before anything is typed, e.g. "ctrl+shift+t"
+    /// Keys come first, then the text; with no text, source or snippet set,
+    /// only the keys are sent (instead of the "Hello World" default)
+    #[serde(default)]
+    keys: Option<String>,
+    /// The order the keys of a combination are let go in, for `keys` and
+    /// key steps - some editors act differently when Ctrl goes up first
+    #[serde(default)]
+    chord_release: ChordRelease,
+    /// Move the pointer to this absolute position, e.g. [960, 540], before
+    /// typing or pasting - for focus-follows-mouse window managers
+    #[serde(default)]
+    pre_type_mouse_move: Option<(i32, i32)>,
+    /// With pre_type_mouse_move, put the pointer back where it was afterwards
+    #[serde(default)]
+    restore_mouse_position: bool,
+    /// Type when the button is pressed ("down") or released ("up")
+    #[serde(default)]
+    trigger_on: TriggerOn,
+    /// Log what the press would do instead of doing it - nothing is typed,
+    /// pressed, pasted or copied
+    #[serde(default)]
+    dry_run: bool,
+    /// Show the checkmark after every successful press, not just clipboard_only ones
+    #[serde(default)]
+    confirm_success: bool,
+    /// Wait this long before delivering anything, to refocus a window after the press
+    #[serde(default)]
+    pre_delay_ms: u32,
+    /// When set, multiline text is typed one line per backend call, with an
+    /// explicit Enter press and then this pause between lines
+    #[serde(default)]
+    line_delay_ms: u32,
+    /// Keep typing the text for as long as the button is held
+    /// Takes over from trigger_on and batching
     #[serde(default)]
-    text: String,
+    repeat: bool,
+    /// Pause between repeats in ms - 0 means DEFAULT_REPEAT_INTERVAL_MS
+    #[serde(default)]
+    repeat_interval_ms: u32,
+    /// This button types something private: logs and the webhook only ever
+    /// get the length, never a masked preview, and its settings aren't logged
+    #[serde(default)]
+    sensitive: bool,
+    /// Canned texts typed in turn, one per press, wrapping around - used
+    /// instead of `text` when not empty
+    #[serde(default)]
+    snippets: Vec<String>,
+    /// How the next entry of `snippets` is chosen - in turn or at random
+    #[serde(default)]
+    selection: SnippetSelection,
+    /// A macro run in order instead of typing the text - when not empty, the
+    /// text, sources, snippets, keys, append_enter and method don't apply
+    #[serde(default)]
+    steps: Vec<MacroStep>,
 }
 
-/// Mask text for privacy in logs
-/// - If <= 10 chars: show first char only (e.g., "H...")
-/// - If > 10 chars: show first + 15 asterisks + last (e.g., "H***************d")
-/// 
-/// NOTE: This is best-effort only. Debug mode may expose raw text via SDK logging.
-fn mask_text(text: &str) -> String {
-    let len = text.chars().count();
-    if len == 0 {
-        return "(empty)".to_string();
+impl TypeTextSettings {
+    /// Whether anything to type is configured, as opposed to the default text
+    fn has_text(&self) -> bool {
+        !self.steps.is_empty()
+            || self.text.as_deref().is_some_and(|t| !t.is_empty())
+            || self.source.is_some()
+            || self.file_path().is_some()
+            || self.snippet_ref.as_deref().is_some_and(|r| !r.trim().is_empty())
+            || !self.snippets.is_empty()
     }
-    if len <= 10 {
-        let first = text.chars().next().unwrap();
-        return format!("{}... ({} chars)", first, len);
+    
+    /// Whether a long press types something else, which makes the button type on release
+    fn has_long_press(&self) -> bool {
+        self.long_press_text.as_deref().is_some_and(|t| !t.is_empty())
+    }
+    
+    /// The file to type, when file_path is set to something
+    fn file_path(&self) -> Option<&str> {
+        self.file_path.as_deref().filter(|path| !path.trim().is_empty())
+    }
+    
+    /// The settings a long press types with: only long_press_text, in place
+    /// of any steps, source, snippet or text
+    fn for_long_press(&self) -> TypeTextSettings {
+        TypeTextSettings {
+            text: Some(self.long_press_text.clone().unwrap_or_default()),
+            source: None,
+            file_path: None,
+            snippet_ref: None,
+            snippets: Vec::new(),
+            steps: Vec::new(),
+            ..self.clone()
+        }
+    }
+    
+    /// The text that will actually be used - falls back to `default`
+    /// ("Hello World" unless --default-text says otherwise) when the text was
+    /// never set, and None when it was cleared
+    fn effective_text<'a>(&'a self, default: &'a str) -> Option<&'a str> {
+        match self.text.as_deref() {
+            None => Some(default),
+            Some("") => None,
+            Some(text) => Some(text),
+        }
+    }
+    
+    /// What makes these settings unusable, if anything obvious - checked as
+    /// they arrive, so a misconfigured button says so before it is pressed
+    fn problem(&self) -> Option<String> {
+        // Steps replace everything checked below
+        if !self.steps.is_empty() {
+            return None;
+        }
+        if self.method == TypeMethod::TimedHold {
+            return match self.hold_key.as_deref().filter(|k| !k.trim().is_empty()) {
+                None => Some("timed_hold needs a hold_key".to_string()),
+                Some(key) if keycode(key).is_none() => Some(format!("unknown hold key '{}'", key)),
+                Some(_) => None,
+            };
+        }
+        match self.keys.as_deref().filter(|k| !k.trim().is_empty()) {
+            Some(spec) => parse_chord(spec).err().map(|e| format!("invalid key combination: {}", e)),
+            None if !self.has_text() && self.text.is_some() => Some("the text is empty and nothing else is set".to_string()),
+            None => None,
+        }
     }
-    // > 10 chars: first + 15 asterisks + last
-    let first = text.chars().next().unwrap();
-    let last = text.chars().last().unwrap();
-    format!("{}***************{} ({} chars)", first, last, len)
 }
 
-struct EchoMacroHandler {
-    is_flatpak: bool,
+/// External text sources resolved at press time
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum TextSource {
+    /// A secret stored in the Secret Service (gnome-keyring, KWallet, ...)
+    /// `collection` is an alias ("default", "login") or a collection label,
+    /// `item` is the label of the item inside it. Never written to settings.
+    SecretService { collection: String, item: String },
+    /// The body of an http:// URL, fetched on every press - only hosts on the
+    /// allowlist (this machine by default) can be fetched from
+    Url(String),
+    /// One line read from a named pipe (FIFO), for tools that stream text to
+    /// the deck - each press consumes one message
+    Fifo(String),
 }
 
-impl EchoMacroHandler {
-    fn new() -> Self {
-        // Detect if running inside Flatpak sandbox
-        let has_flatpak_id = env::var("FLATPAK_ID").is_ok();
-        let has_flatpak_info = std::path::Path::new("/.flatpak-info").exists();
-        let is_flatpak = has_flatpak_id || has_flatpak_info;
-        
-        debug!("FLATPAK_ID present: {}, /.flatpak-info exists: {}", has_flatpak_id, has_flatpak_info);
-        
-        if is_flatpak {
-            info!("Echo Macro handler created (Flatpak mode - using flatpak-spawn)");
-        } else {
-            info!("Echo Macro handler created (native mode - using ydotool directly)");
-        }
-        
-        EchoMacroHandler { is_flatpak }
+/// Reaction to a FIFO source that has nothing to type
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum EmptySourcePolicy {
+    /// Fail the press and show an alert on the button
+    #[default]
+    Alert,
+    /// Do nothing - the press counts as handled
+    Skip,
+}
+
+/// Which half of a press types the text
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum TriggerOn {
+    /// As soon as the button goes down
+    #[default]
+    Down,
+    /// When the button is let go
+    Up,
+}
+
+/// Presses this close together (ms) are taken for a bouncing button when
+/// a button doesn't set debounce_ms - too short for two real presses
+const DEFAULT_DEBOUNCE_MS: u32 = 30;
+
+/// How long a press must last to type long_press_text when none is configured
+const DEFAULT_LONG_PRESS_MS: u32 = 500;
+
+/// Pause between repeats of a held button when none is configured
+const DEFAULT_REPEAT_INTERVAL_MS: u32 = 250;
+/// Shortest pause between repeats, so a held button can't flood the backend
+const MIN_REPEAT_INTERVAL_MS: u32 = 20;
+
+/// One step of a macro, e.g. {"type": "Hello"}, {"key": "ctrl+l"} or {"delay": 200}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum MacroStep {
+    /// Type text as it is - no variables, counters or substitutions
+    Type(String),
+    /// Press a key combination, written like `keys`
+    Key(String),
+    /// Wait this many milliseconds
+    Delay(u32),
+}
+
+/// The order the keys of a combination are released in - they are always
+/// pressed in the order written
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ChordRelease {
+    /// Last pressed first: ctrl+shift+k releases k, then shift, then ctrl
+    #[default]
+    Reverse,
+    /// In press order, modifiers before the key: ctrl, shift, then k
+    InOrder,
+}
+
+/// How text with characters outside ydotool's reach is delivered
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum UnicodeStrategy {
+    /// Type it regardless, with a warning that characters may be dropped
+    #[default]
+    Type,
+    /// Paste it through the clipboard
+    Paste,
+    /// Paste it when a clipboard tool was found and gtk_unicode_input is off
+    Auto,
+}
+
+/// Whether a "type" button should paste `text` instead, per its unicode_strategy
+/// Only ydotool needs this - xdotool types any character
+fn should_paste_unicode(strategy: UnicodeStrategy, text: &str, backend: Backend, gtk_unicode_input: bool, has_clipboard: bool) -> bool {
+    if backend != Backend::Ydotool || text.chars().all(is_ydotool_typable) {
+        return false;
     }
+    match strategy {
+        UnicodeStrategy::Type => false,
+        UnicodeStrategy::Paste => true,
+        UnicodeStrategy::Auto => !gtk_unicode_input && has_clipboard,
+    }
+}
+
+/// How a button picks the next entry of its snippets list
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum SnippetSelection {
+    /// One after the other, wrapping around
+    #[default]
+    Sequential,
+    /// Any entry but the one typed last
+    Random,
+}
+
+/// What line_prefix does with empty lines
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum EmptyLinePolicy {
+    /// Prefix them too, without the prefix's trailing whitespace ("> " becomes ">")
+    #[default]
+    Prefix,
+    /// Leave them empty
+    Skip,
+}
+
+/// Delivery method for the configured text
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum TypeMethod {
+    /// Send the text as keystrokes through ydotool
+    #[default]
+    Type,
+    /// Only load the text onto the clipboard - no keystrokes are sent
+    ClipboardOnly,
+    /// Load the text onto the clipboard and press Ctrl+V, then put the
+    /// previous clipboard text back. Instant for long text, and Unicode-safe.
+    Paste,
+    /// Hold `hold_key` down for `hold_duration_ms`, then release it - the
+    /// text is ignored. For scrubbing/media controls that react to held keys.
+    TimedHold,
+    /// Experimental: inject the text into the Linux virtual terminal named by
+    /// `vt_device` (e.g. "/dev/tty3"). Needs the `vt-backend` build feature.
+    VirtualTerminal,
+}
+
+/// Reaction to clipboard_only failing to load the clipboard
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ClipboardFailurePolicy {
+    /// Fail the press and show an alert on the button
+    #[default]
+    Alert,
+    /// Type the text with ydotool instead, so the button still works
+    FallbackToType,
+}
+
+/// Replace every occurrence of the map's keys with their values
+/// At each position the longest matching key wins, and replaced output is
+/// never scanned again, so {"a": "b", "b": "c"} turns "ab" into "bc"
+fn apply_substitutions(text: &str, substitutions: &HashMap<String, String>) -> String {
+    let mut keys: Vec<(&str, &str)> = substitutions
+        .iter()
+        .filter(|(from, _)| !from.is_empty())
+        .map(|(from, to)| (from.as_str(), to.as_str()))
+        .collect();
+    // Longest first so "<<" beats "<" - ties broken by key for a stable result
+    keys.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(b.0)));
     
-    /// Type text using ydotool (works on both Wayland and X11)
-    /// Returns true on success, false on failure
-    fn type_text(&self, settings: &TypeTextSettings) -> bool {
-        // Use default text if none configured
-        let text = if settings.text.is_empty() {
-            "Hello World"
-        } else {
-            &settings.text
-        };
-        
-        // Mask text for privacy in logs
-        let masked = mask_text(text);
-        info!("Typing: {}", masked);
-        
-        // Type with ydotool
-        match self.type_with_ydotool(text) {
-            Ok(()) => {
-                info!("Finished typing successfully");
-                true
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        match keys.iter().find(|(from, _)| rest.starts_with(from)) {
+            Some((from, to)) => {
+                result.push_str(to);
+                rest = &rest[from.len()..];
             }
-            Err(_) => {
-                error!("Failed to type text - ydotool error");
-                false
+            None => {
+                result.push(c);
+                rest = &rest[c.len_utf8()..];
             }
         }
     }
-    
-    /// Spawn ydotool to type text
-    /// Uses flatpak-spawn --host when running inside Flatpak
-    /// Returns Ok(()) on success, Err(()) on failure
-    fn type_with_ydotool(&self, text: &str) -> Result<(), ()> {
-        // Note: ydotool doesn't have a per-character delay option like xdotool
-        // It types all at once. For now we ignore delay_ms.
-        let output = if self.is_flatpak {
-            // Running inside Flatpak - use flatpak-spawn to access host binaries
-            Command::new("flatpak-spawn")
-                .args(&["--host", "ydotool", "type", text])
-                .output()
-        } else {
-            // Native mode - run ydotool directly
-            Command::new("ydotool")
-                .args(&["type", text])
-                .output()
-        };
-            
-        match output {
-            Ok(result) => {
-                if !result.status.success() {
-                    let stderr = String::from_utf8_lossy(&result.stderr);
-                    error!("ydotool failed: {}", stderr);
-                    
-                    if stderr.contains("ydotoold") || stderr.contains("socket") || stderr.contains("connection") {
-                        error!("ydotoold daemon may not be running!");
-                        error!("Try: systemctl start ydotoold (or run ydotoold in a terminal)");
-                    }
-                    if stderr.contains("flatpak-spawn") || stderr.contains("not found") {
-                        error!("flatpak-spawn may not be available!");
-                        error!("The Flatpak needs --talk-name=org.freedesktop.Flatpak permission");
-                    }
-                    Err(())
-                } else {
-                    debug!("ydotool completed successfully");
-                    Ok(())
-                }
-            }
-            Err(e) => {
-                error!("Failed to spawn ydotool: {}", e);
-                if self.is_flatpak {
-                    error!("Make sure ydotool is installed on the HOST system");
-                    error!("Also check: flatpak override --user --talk-name=org.freedesktop.Flatpak me.amankhanna.opendeck");
-                } else {
-                    error!("Make sure ydotool is installed: sudo apt install ydotool");
-                }
-                Err(())
+    result
+}
+
+/// Collapse each run of spaces/tabs into a single space
+/// Newlines are kept unless `newlines` is set, in which case they join the
+/// surrounding run so multi-line text becomes a single line
+fn collapse_whitespace(text: &str, newlines: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut in_run = false;
+    for c in text.chars() {
+        let collapsible = c == ' ' || c == '\t' || (newlines && (c == '\n' || c == '\r'));
+        if collapsible {
+            if !in_run {
+                result.push(' ');
+                in_run = true;
             }
+        } else {
+            result.push(c);
+            in_run = false;
         }
     }
+    result
 }
 
-impl ActionEventHandler for EchoMacroHandler {
-    fn key_down(
-        &self,
-        event: KeyEvent,
-        outbound: &mut OutboundEventManager,
-    ) -> impl std::future::Future<Output = EventHandlerResult> + Send {
-        let settings: TypeTextSettings = serde_json::from_value(event.payload.settings)
-            .unwrap_or_default();
-        let context = event.context;
-        
-        async move {
-            info!("Key pressed!");
-            debug!("Settings: {:?}", settings);
-            
-            if !self.type_text(&settings) {
-                // Show alert indicator on the action button
-                if let Err(e) = outbound.show_alert(context).await {
-                    error!("Failed to show alert: {}", e);
-                }
+/// Put `prefix` before every line of `text`
+/// A trailing newline doesn't start another line, and `\r\n` endings are kept
+fn prefix_lines(text: &str, prefix: &str, empty_lines: EmptyLinePolicy) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut lines = text.split('\n').peekable();
+    while let Some(line) = lines.next() {
+        let last = lines.peek().is_none();
+        if last && line.is_empty() && !text.is_empty() {
+            break;
+        }
+        if line.trim_end_matches('\r').is_empty() {
+            if empty_lines == EmptyLinePolicy::Prefix {
+                result.push_str(prefix.trim_end());
             }
-            
-            Ok(())
+        } else {
+            result.push_str(prefix);
+        }
+        result.push_str(line);
+        if !last {
+            result.push('\n');
         }
     }
+    result
+}
 
-    fn key_up(
-        &self,
-        _event: KeyEvent,
-        _outbound: &mut OutboundEventManager,
-    ) -> impl std::future::Future<Output = EventHandlerResult> + Send {
-        async move { Ok(()) }
-    }
+/// Inserted into long words when no word_break is set - invisible, but lets the target wrap
+const DEFAULT_WORD_BREAK: &str = "\u{200B}";
 
-    fn will_appear(
-        &self,
-        event: AppearEvent,
-        _outbound: &mut OutboundEventManager,
-    ) -> impl std::future::Future<Output = EventHandlerResult> + Send {
-        let context = event.context;
-        async move {
-            info!("Action appeared: {}", context);
-            Ok(())
+/// Insert `separator` into every word longer than `max` characters, after
+/// each `max` characters. Words are runs of non-whitespace; a word of
+/// exactly `max` characters is left as it is
+fn break_long_words(text: &str, max: usize, separator: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut run = 0;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            run = 0;
+        } else {
+            if run == max {
+                result.push_str(separator);
+                run = 0;
+            }
+            run += 1;
         }
+        result.push(c);
     }
+    result
+}
 
-    fn will_disappear(
-        &self,
-        event: AppearEvent,
-        _outbound: &mut OutboundEventManager,
-    ) -> impl std::future::Future<Output = EventHandlerResult> + Send {
-        let context = event.context;
-        async move {
-            info!("Action disappeared: {}", context);
-            Ok(())
-        }
-    }
+/// Map a key name to its Linux input event keycode (as used by `ydotool key`)
+/// Names are case-insensitive; returns None for unknown keys
+fn keycode(name: &str) -> Option<u16> {
+    let code = match name.trim().to_lowercase().as_str() {
+        "esc" | "escape" => 1,
+        "1" => 2, "2" => 3, "3" => 4, "4" => 5, "5" => 6,
+        "6" => 7, "7" => 8, "8" => 9, "9" => 10, "0" => 11,
+        "minus" | "-" => 12,
+        "equal" | "=" => 13,
+        "backspace" => 14,
+        "tab" => 15,
+        "q" => 16, "w" => 17, "e" => 18, "r" => 19, "t" => 20,
+        "y" => 21, "u" => 22, "i" => 23, "o" => 24, "p" => 25,
+        "leftbrace" | "[" => 26,
+        "rightbrace" | "]" => 27,
+        "enter" | "return" => 28,
+        "ctrl" | "control" | "leftctrl" => 29,
+        "a" => 30, "s" => 31, "d" => 32, "f" => 33, "g" => 34,
+        "h" => 35, "j" => 36, "k" => 37, "l" => 38,
+        "semicolon" | ";" => 39,
+        "apostrophe" | "'" => 40,
+        "grave" | "`" => 41,
+        "shift" | "leftshift" => 42,
+        "backslash" | "\\" => 43,
+        "z" => 44, "x" => 45, "c" => 46, "v" => 47, "b" => 48,
+        "n" => 49, "m" => 50,
+        "comma" | "," => 51,
+        "dot" | "period" | "." => 52,
+        "slash" | "/" => 53,
+        "rightshift" => 54,
+        "alt" | "leftalt" => 56,
+        "space" => 57,
+        "capslock" => 58,
+        "f1" => 59, "f2" => 60, "f3" => 61, "f4" => 62, "f5" => 63,
+        "f6" => 64, "f7" => 65, "f8" => 66, "f9" => 67, "f10" => 68,
+        "f11" => 87, "f12" => 88,
+        "rightctrl" => 97,
+        "print" | "printscreen" | "sysrq" => 99,
+        "rightalt" | "altgr" => 100,
+        "home" => 102,
+        "up" => 103,
+        "pageup" | "pgup" => 104,
+        "left" => 105,
+        "right" => 106,
+        "end" => 107,
+        "down" => 108,
+        "pagedown" | "pgdn" => 109,
+        "insert" | "ins" => 110,
+        "delete" | "del" => 111,
+        "pause" => 119,
+        "super" | "meta" | "win" | "leftmeta" => 125,
+        "rightmeta" => 126,
+        "menu" | "compose" => 127,
+        _ => return None,
+    };
+    Some(code)
+}
 
-    fn did_receive_settings(
-        &self,
-        event: DidReceiveSettingsEvent,
-        _outbound: &mut OutboundEventManager,
-    ) -> impl std::future::Future<Output = EventHandlerResult> + Send {
-        let context = event.context;
-        async move {
-            debug!("Received new settings for: {}", context);
-            Ok(())
-        }
+/// Parse a key combination like "ctrl+shift+t" into keycodes, in press order
+/// Names are those `keycode` knows; Err names the first one it doesn't
+fn parse_chord(spec: &str) -> Result<Vec<u16>, String> {
+    let codes: Vec<u16> = spec
+        .split('+')
+        .map(|name| {
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(format!("empty key name in '{}'", spec));
+            }
+            keycode(name).ok_or_else(|| format!("unknown key '{}'", name))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(codes)
+}
+
+/// Key events for a chord: every key down in order, then up as `release` says
+fn chord_events(codes: &[u16], release: ChordRelease) -> KeyEvents {
+    let downs = codes.iter().map(|&code| (code, true));
+    match release {
+        ChordRelease::Reverse => downs.chain(codes.iter().rev().map(|&code| (code, false))).collect(),
+        ChordRelease::InOrder => downs.chain(codes.iter().map(|&code| (code, false))).collect(),
     }
 }
 
-struct EchoMacroGlobalHandler;
+/// Key events as ydotool writes them, e.g. "29:1 37:1 37:0 29:0", for the log
+fn describe_key_events(events: &[(u16, bool)]) -> String {
+    events.iter()
+        .map(|(code, down)| format!("{}:{}", code, u8::from(*down)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
-impl GlobalEventHandler for EchoMacroGlobalHandler {
-    fn plugin_ready(
-        &self,
-        _outbound: &mut OutboundEventManager,
-    ) -> impl std::future::Future<Output = EventHandlerResult> + Send {
-        async move {
-            let is_flatpak = env::var("FLATPAK_ID").is_ok() 
-                || std::path::Path::new("/.flatpak-info").exists();
-            
-            if is_flatpak {
-                info!("Echo Macro plugin connected! Running in Flatpak mode.");
-                info!("Will use flatpak-spawn --host to access ydotool");
-            } else {
-                info!("Echo Macro plugin connected! Running in native mode.");
-            }
-            info!("Using ydotool for Wayland/X11 compatibility.");
-            
-            // Test if ydotool is available (ydotool doesn't have --version, use 'help')
-            let test_cmd = if is_flatpak {
-                Command::new("flatpak-spawn")
-                    .args(&["--host", "ydotool", "help"])
-                    .output()
-            } else {
-                Command::new("ydotool")
-                    .arg("help")
-                    .output()
-            };
-            
-            match test_cmd {
-                Ok(result) => {
-                    if result.status.success() {
-                        info!("ydotool is available");
-                    } else {
-                        let stderr = String::from_utf8_lossy(&result.stderr);
-                        error!("ydotool returned error: {}", stderr);
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to run ydotool: {}", e);
-                    if is_flatpak {
-                        error!("Make sure ydotool is installed on the HOST system");
-                        error!("You may also need to grant Flatpak permission:");
-                        error!("  flatpak override --user --talk-name=org.freedesktop.Flatpak me.amankhanna.opendeck");
-                    } else {
-                        error!("Install ydotool: sudo apt install ydotool");
-                    }
-                }
-            }
-            
-            Ok(())
-        }
+/// Read a boolean environment flag ("1", "true" or "yes" enable it)
+fn env_flag(name: &str) -> bool {
+    env_flag_or(name, false)
+}
+
+/// Full UUID of one of this plugin's actions
+fn action_uuid(name: &str) -> String {
+    format!("{}.{}", PLUGIN_ID, name)
+}
+
+/// Decode a button's settings. Err (logged) means they don't match what this
+/// version expects, e.g. after an update - never treat them as unconfigured
+fn parse_settings(context: &str, raw: serde_json::Value) -> Result<TypeTextSettings, ()> {
+    serde_json::from_value(raw).map_err(|e| {
+        error!("Can't read the settings of {}: {}", context, e);
+    })
+}
+
+/// Whether host tools have to go through flatpak-spawn --host
+/// `forced` - the --use-flatpak-spawn / ECHO_MACRO_USE_FLATPAK_SPAWN choice -
+/// skips detection, for sandboxes that don't look like Flatpak
+fn detect_flatpak(forced: Option<bool>) -> bool {
+    if let Some(is_flatpak) = forced {
+        return is_flatpak;
     }
+    let has_flatpak_id = env::var("FLATPAK_ID").is_ok();
+    let has_flatpak_info = std::path::Path::new("/.flatpak-info").exists();
+    debug!("FLATPAK_ID present: {}, /.flatpak-info exists: {}", has_flatpak_id, has_flatpak_info);
+    has_flatpak_id || has_flatpak_info
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    simplelog::SimpleLogger::init(
-        simplelog::LevelFilter::Debug,
-        simplelog::Config::default()
-    )?;
+/// Whether an action UUID from OpenDeck is the named action of this plugin
+fn is_action(uuid: &str, name: &str) -> bool {
+    uuid.strip_prefix(PLUGIN_ID).and_then(|rest| rest.strip_prefix('.')) == Some(name)
+}
+
+/// Which of this plugin's actions an event is for - None for a UUID it
+/// doesn't have, e.g. a manifest entry without a matching handler
+fn known_action(uuid: &str) -> Option<&'static str> {
+    [TYPE_TEXT_ACTION, PRESENTATION_MODE_ACTION, HEALTH_CHECK_ACTION]
+        .into_iter()
+        .find(|name| is_action(uuid, name))
+}
+
+/// App id of the Flatpak we run in - Flatpak sets FLATPAK_ID for the app,
+/// which covers forks of OpenDeck that use their own id
+fn host_app_id() -> String {
+    host_app_id_with(|name| env::var_os(name))
+}
+
+/// `host_app_id` with the environment lookup supplied by the caller
+fn host_app_id_with(var: impl Fn(&str) -> Option<std::ffi::OsString>) -> String {
+    var("FLATPAK_ID")
+        .map(|id| id.to_string_lossy().into_owned())
+        .filter(|id| !id.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_HOST_APP_ID.to_string())
+}
+
+/// The command that grants the host app access to a D-Bus name
+fn flatpak_override_hint(talk_name: &str, app_id: &str) -> String {
+    format!("flatpak override --user --talk-name={} {}", talk_name, app_id)
+}
+
+/// Like env_flag, but `default` applies when the variable isn't set
+fn env_flag_or(name: &str, default: bool) -> bool {
+    env::var(name)
+        .map(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(default)
+}
+
+/// Binaries an argument template may name - a template can only add
+/// arguments, never run anything else
+const TEMPLATE_BACKENDS: [&str; 2] = ["ydotool", "xdotool"];
+
+/// Expand an argument template for `backend` into the arguments to pass it
+/// The template is split on whitespace, must start with the backend's own
+/// binary and must end with `{text}`, its only occurrence. The text always
+/// goes after `--` (added if the template leaves it out), so text like
+/// `key` or `--file /etc/passwd` is typed instead of read as a subcommand or option
+fn expand_arg_template(backend: &str, template: &str, text: &str) -> Result<Vec<String>, String> {
+    if !TEMPLATE_BACKENDS.contains(&backend) {
+        return Err(format!("'{}' is not a supported backend", backend));
+    }
+    let mut words = template.split_whitespace();
+    match words.next() {
+        Some(binary) if binary == backend => {}
+        Some(binary) => return Err(format!("template must run {}, not '{}'", backend, binary)),
+        None => return Err("template is empty".to_string()),
+    }
+    let mut args: Vec<String> = words.map(str::to_string).collect();
+    if let Some(unknown) = args.iter().find(|arg| *arg != "{text}" && arg.contains('{')) {
+        return Err(format!("unknown placeholder in '{}' - only {{text}} is supported", unknown));
+    }
+    if args.iter().filter(|arg| *arg == "{text}").count() != 1 {
+        return Err("template must contain {text} exactly once, as its own argument".to_string());
+    }
+    if args.last().map(String::as_str) != Some("{text}") {
+        return Err("{text} must be the last argument".to_string());
+    }
+    args.pop();
+    if args.last().map(String::as_str) != Some("--") {
+        args.push("--".to_string());
+    }
+    args.push(text.to_string());
+    Ok(args)
+}
+
+/// Whether `ydotool type` can type the character - it maps text onto a US
+/// keyboard layout, so anything outside printable ASCII comes out mangled
+fn is_ydotool_typable(c: char) -> bool {
+    c.is_ascii_graphic() || matches!(c, ' ' | '\n' | '\t')
+}
+
+/// A piece of text to type, for gtk_unicode_input
+#[derive(Debug, PartialEq, Eq)]
+enum TypeSegment<'a> {
+    /// A run of characters `ydotool type` handles
+    Plain(&'a str),
+    /// A character that needs the Ctrl+Shift+U sequence
+    Unicode(char),
+}
+
+/// Split text into plain runs and the characters ydotool can't type
+fn split_for_gtk_unicode(text: &str) -> Vec<TypeSegment<'_>> {
+    let mut segments = Vec::new();
+    let mut plain_start = 0;
+    for (i, c) in text.char_indices() {
+        if !is_ydotool_typable(c) {
+            if plain_start < i {
+                segments.push(TypeSegment::Plain(&text[plain_start..i]));
+            }
+            segments.push(TypeSegment::Unicode(c));
+            plain_start = i + c.len_utf8();
+        }
+    }
+    if plain_start < text.len() {
+        segments.push(TypeSegment::Plain(&text[plain_start..]));
+    }
+    segments
+}
+
+/// How `c` is entered through GTK's Unicode input: the key events for
+/// Ctrl+Shift+U, the code point to type in hex (e.g. "1f600" for 😀), and the
+/// key events for the space that commits it
+fn gtk_unicode_sequence(c: char) -> (KeyEvents, String, KeyEvents) {
+    let (ctrl, shift, u, space) = (29, 42, 22, 57);
+    (
+        vec![(ctrl, true), (shift, true), (u, true), (u, false), (shift, false), (ctrl, false)],
+        format!("{:x}", c as u32),
+        vec![(space, true), (space, false)],
+    )
+}
+
+/// Minimum time between daemon warm-ups, however many buttons appear
+const DAEMON_WARMUP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether a warm-up is due, given when the last one was sent
+fn warmup_due(last: Option<Instant>, now: Instant) -> bool {
+    last.is_none_or(|last| now.duration_since(last) >= DAEMON_WARMUP_INTERVAL)
+}
+
+/// Modifiers released at startup in case a crash left them held down
+const STUCK_KEY_CANDIDATES: [&str; 8] = [
+    "leftctrl", "rightctrl", "leftshift", "rightshift",
+    "leftalt", "rightalt", "leftmeta", "rightmeta",
+];
+
+/// ydotool arguments that send a key-up for every stuck-key candidate
+fn key_reset_args() -> Vec<String> {
+    std::iter::once("key".to_string())
+        .chain(STUCK_KEY_CANDIDATES.iter().filter_map(|name| keycode(name)).map(|code| format!("{}:0", code)))
+        .collect()
+}
+
+const SECRET_SERVICE_BUS: &str = "org.freedesktop.secrets";
+
+/// Why a Secret Service lookup failed
+#[derive(Debug)]
+enum SecretLookupError {
+    Bus(zbus::Error),
+    CollectionNotFound,
+    ItemNotFound,
+    Locked,
+    NotUtf8,
+}
+
+impl From<zbus::Error> for SecretLookupError {
+    fn from(e: zbus::Error) -> Self {
+        SecretLookupError::Bus(e)
+    }
+}
+
+/// Fetch a secret from the Secret Service over D-Bus
+/// The returned text is wiped from memory when dropped
+async fn fetch_secret(collection: &str, item: &str) -> Result<Zeroizing<String>, TypeError> {
+    match lookup_secret(collection, item).await {
+        Ok(secret) => Ok(secret),
+        Err(SecretLookupError::Bus(e)) => {
+            error!("Secret Service request failed: {}", e);
+            error!("Make sure a keyring (gnome-keyring, KWallet) is running");
+            error!("Flatpak users also need: {}", flatpak_override_hint("org.freedesktop.secrets", &host_app_id()));
+            Err(TypeError::SourceFailed)
+        }
+        Err(SecretLookupError::CollectionNotFound) => {
+            error!("Secret collection not found: {}", collection);
+            Err(TypeError::InvalidArguments(format!("no secret collection '{}'", collection)))
+        }
+        Err(SecretLookupError::ItemNotFound) => {
+            error!("Secret '{}' not found in collection '{}'", item, collection);
+            Err(TypeError::InvalidArguments(format!("no secret '{}' in collection '{}'", item, collection)))
+        }
+        Err(SecretLookupError::Locked) => {
+            error!("Secret collection '{}' is locked - unlock your keyring and try again", collection);
+            Err(TypeError::SourceFailed)
+        }
+        Err(SecretLookupError::NotUtf8) => {
+            error!("Secret '{}' is not valid UTF-8 text", item);
+            Err(TypeError::SourceFailed)
+        }
+    }
+}
+
+async fn lookup_secret(collection: &str, item: &str) -> Result<Zeroizing<String>, SecretLookupError> {
+    let conn = zbus::Connection::session().await?;
+    let service = zbus::Proxy::new(
+        &conn,
+        SECRET_SERVICE_BUS,
+        "/org/freedesktop/secrets",
+        "org.freedesktop.Secret.Service",
+    ).await?;
     
-    info!("Echo Macro plugin starting...");
+    // Resolve the collection - aliases first ("/" means no such alias), then labels
+    let alias: OwnedObjectPath = service.call("ReadAlias", &(collection,)).await?;
+    let collection_path = if alias.as_str() != "/" {
+        alias
+    } else {
+        let mut found = None;
+        let paths: Vec<OwnedObjectPath> = service.get_property("Collections").await?;
+        for path in paths {
+            let proxy = zbus::Proxy::new(&conn, SECRET_SERVICE_BUS, path.clone(), "org.freedesktop.Secret.Collection").await?;
+            let label: String = proxy.get_property("Label").await?;
+            if label == collection {
+                found = Some(path);
+                break;
+            }
+        }
+        found.ok_or(SecretLookupError::CollectionNotFound)?
+    };
+    
+    let collection_proxy = zbus::Proxy::new(&conn, SECRET_SERVICE_BUS, collection_path, "org.freedesktop.Secret.Collection").await?;
+    if collection_proxy.get_property::<bool>("Locked").await? {
+        return Err(SecretLookupError::Locked);
+    }
+    
+    let items: Vec<OwnedObjectPath> = collection_proxy.get_property("Items").await?;
+    for path in items {
+        let item_proxy = zbus::Proxy::new(&conn, SECRET_SERVICE_BUS, path, "org.freedesktop.Secret.Item").await?;
+        let label: String = item_proxy.get_property("Label").await?;
+        if label != item {
+            continue;
+        }
+        if item_proxy.get_property::<bool>("Locked").await? {
+            return Err(SecretLookupError::Locked);
+        }
+        
+        // "plain" sessions don't encrypt - fine since the bus never leaves this machine
+        let (_, session): (OwnedValue, OwnedObjectPath) = service
+            .call("OpenSession", &("plain", Value::from("")))
+            .await?;
+        let reply: Result<(OwnedObjectPath, Vec<u8>, Vec<u8>, String), _> =
+            item_proxy.call("GetSecret", &(&session,)).await;
+        
+        // Best effort - the service also closes sessions when we disconnect
+        if let Ok(session_proxy) = zbus::Proxy::new(&conn, SECRET_SERVICE_BUS, session, "org.freedesktop.Secret.Session").await {
+            let _: Result<(), _> = session_proxy.call("Close", &()).await;
+        }
+        
+        let (_, _, value, _) = reply?;
+        let value = Zeroizing::new(value);
+        let text = std::str::from_utf8(&value).map_err(|_| SecretLookupError::NotUtf8)?;
+        return Ok(Zeroizing::new(text.to_owned()));
+    }
+    
+    Err(SecretLookupError::ItemNotFound)
+}
+
+/// Largest response body a URL source may return, in bytes
+const URL_SOURCE_MAX_BYTES: usize = 64 * 1024;
+/// Room for the status line and headers on top of the body limit
+const URL_SOURCE_MAX_HEADER_BYTES: usize = 8 * 1024;
+/// How long fetching a URL source may take, connecting included
+const URL_SOURCE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Hosts URL sources may be fetched from
+/// ECHO_MACRO_URL_ALLOWLIST (comma separated) replaces the default, which
+/// only allows this machine
+fn url_allowlist() -> Vec<String> {
+    match env::var("ECHO_MACRO_URL_ALLOWLIST") {
+        Ok(list) => list
+            .split(',')
+            .map(|host| host.trim().to_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect(),
+        Err(_) => ["localhost", "127.0.0.1", "::1"].iter().map(|h| h.to_string()).collect(),
+    }
+}
+
+/// A URL source split into what's needed to request it
+#[derive(Debug, PartialEq, Eq)]
+struct HttpUrl {
+    /// Host without brackets, e.g. "::1" - used for the allowlist and connecting
+    host: String,
+    port: u16,
+    /// Host and port as written in the URL, for the Host header
+    authority: String,
+    /// Path and query, at least "/"
+    path: String,
+}
+
+/// Parse an http:// URL - https is rejected, as URL sources are meant for
+/// local services and TLS would need a much larger client
+fn parse_http_url(url: &str) -> Result<HttpUrl, String> {
+    let rest = url.trim().strip_prefix("http://").ok_or("only http:// URLs are supported")?;
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    // user:pass@host would make the host easy to misread - don't allow it
+    if authority.contains('@') {
+        return Err("credentials in the URL are not supported".to_string());
+    }
+    let (host, port) = if let Some(v6) = authority.strip_prefix('[') {
+        let (host, after) = v6.split_once(']').ok_or("unterminated IPv6 address")?;
+        (host, after.strip_prefix(':'))
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+    if host.is_empty() {
+        return Err("missing host".to_string());
+    }
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| format!("invalid port '{}'", port))?,
+        None => 80,
+    };
+    let path = if path.starts_with('?') { format!("/{}", path) } else { path.to_string() };
+    Ok(HttpUrl {
+        host: host.to_lowercase(),
+        port,
+        authority: authority.to_string(),
+        path,
+    })
+}
+
+/// Check a URL source before anything is fetched: it must be a valid
+/// http:// URL whose host is on `allowlist` exactly, so e.g.
+/// 127.0.0.1.example.com doesn't pass for 127.0.0.1
+fn check_url_source(url: &str, allowlist: &[String]) -> Result<HttpUrl, String> {
+    let parsed = parse_http_url(url)?;
+    if !allowlist.contains(&parsed.host) {
+        return Err(format!("host '{}' is not allowed - add it to ECHO_MACRO_URL_ALLOWLIST", parsed.host));
+    }
+    Ok(parsed)
+}
+
+/// Pull the body out of a raw HTTP response, which must be a 200 within the size limit
+fn http_response_body(raw: &[u8]) -> Result<&[u8], String> {
+    let split = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("malformed response")?;
+    let head = String::from_utf8_lossy(&raw[..split]);
+    let body = &raw[split + 4..];
+    
+    let status = head.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some("200") => {}
+        _ => return Err(format!("server answered '{}'", status)),
+    }
+    // Requests are HTTP/1.0, so a conforming server never chunks the body
+    if head.lines().any(|l| l.to_lowercase().starts_with("transfer-encoding:") && l.to_lowercase().contains("chunked")) {
+        return Err("chunked responses are not supported".to_string());
+    }
+    if body.len() > URL_SOURCE_MAX_BYTES {
+        return Err(format!("response is larger than {} bytes", URL_SOURCE_MAX_BYTES));
+    }
+    Ok(body)
+}
+
+/// Send a raw HTTP/1.0 request and read the whole response, which is
+/// capped at the URL source limits plus one byte (so "too big" is detectable)
+async fn http_exchange(url: &HttpUrl, request: &[u8]) -> std::io::Result<Vec<u8>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     
-    let global_handler = EchoMacroGlobalHandler;
-    let action_handler = EchoMacroHandler::new();
+    let mut stream = tokio::net::TcpStream::connect((url.host.as_str(), url.port)).await?;
+    stream.write_all(request).await?;
+    let mut raw = Vec::new();
+    let limit = (URL_SOURCE_MAX_BYTES + URL_SOURCE_MAX_HEADER_BYTES + 1) as u64;
+    stream.take(limit).read_to_end(&mut raw).await?;
+    Ok(raw)
+}
+
+/// Fetch the text for a URL source, enforcing the allowlist, size and time limits
+async fn fetch_url(url: &str) -> Result<String, TypeError> {
+    let parsed = check_url_source(url, &url_allowlist()).map_err(|e| {
+        error!("Invalid URL source '{}': {}", url, e);
+        TypeError::InvalidArguments(e)
+    })?;
     
-    init_plugin(global_handler, action_handler).await?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: echo-macro\r\nConnection: close\r\n\r\n",
+        parsed.path, parsed.authority
+    );
+    let raw = match tokio::time::timeout(URL_SOURCE_TIMEOUT, http_exchange(&parsed, request.as_bytes())).await {
+        Ok(Ok(raw)) => raw,
+        Ok(Err(e)) => {
+            error!("Failed to fetch {}: {}", url, e);
+            return Err(TypeError::SourceFailed);
+        }
+        Err(_) => {
+            error!("Fetching {} took longer than {}s", url, URL_SOURCE_TIMEOUT.as_secs());
+            return Err(TypeError::SourceFailed);
+        }
+    };
+    if raw.len() > URL_SOURCE_MAX_BYTES + URL_SOURCE_MAX_HEADER_BYTES {
+        error!("Response from {} is larger than {} bytes", url, URL_SOURCE_MAX_BYTES);
+        return Err(TypeError::SourceFailed);
+    }
     
-    info!("Plugin shutting down");
+    let body = http_response_body(&raw).map_err(|e| {
+        error!("Failed to fetch {}: {}", url, e);
+        TypeError::SourceFailed
+    })?;
+    String::from_utf8(body.to_vec()).map_err(|_| {
+        error!("Response from {} is not valid UTF-8 text", url);
+        TypeError::SourceFailed
+    })
+}
+
+/// How long a webhook may take before it is abandoned
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What a webhook is told about a press - only the masked text and its
+/// length, never the text itself
+#[derive(Debug, Default, Clone)]
+struct PressReport {
+    /// Masked the same way as in the log (secrets show no characters at all)
+    masked: Option<String>,
+    chars: usize,
+}
+
+/// The JSON body posted to a button's webhook after a press
+fn webhook_payload(context: &str, action: &str, report: &PressReport, success: bool, timestamp: u64) -> serde_json::Value {
+    serde_json::json!({
+        "context": context,
+        "action": action,
+        "masked_text": report.masked,
+        "length": report.chars,
+        "success": success,
+        "timestamp": timestamp,
+    })
+}
+
+/// POST a press report to a webhook - failures are logged and otherwise ignored
+async fn post_webhook(url: String, payload: serde_json::Value) {
+    let parsed = match parse_http_url(&url) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            warn!("Invalid webhook URL '{}': {}", url, e);
+            return;
+        }
+    };
+    if !url_allowlist().contains(&parsed.host) {
+        warn!("Webhook host '{}' is not allowed - add it to ECHO_MACRO_URL_ALLOWLIST", parsed.host);
+        return;
+    }
+    
+    let body = payload.to_string();
+    let request = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: echo-macro\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        parsed.path, parsed.authority, body.len(), body
+    );
+    match tokio::time::timeout(WEBHOOK_TIMEOUT, http_exchange(&parsed, request.as_bytes())).await {
+        Ok(Ok(raw)) => {
+            let status = String::from_utf8_lossy(raw.split(|b| *b == b'\n').next().unwrap_or_default()).trim().to_string();
+            if status.split_whitespace().nth(1).is_some_and(|code| code.starts_with('2')) {
+                debug!("Webhook {} accepted the press", url);
+            } else {
+                warn!("Webhook {} answered '{}'", url, status);
+            }
+        }
+        Ok(Err(e)) => warn!("Webhook {} failed: {}", url, e),
+        Err(_) => warn!("Webhook {} took longer than {}s - abandoned", url, WEBHOOK_TIMEOUT.as_secs()),
+    }
+}
+
+/// How long a press waits for a message on a FIFO source
+const FIFO_SOURCE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longest message a FIFO source may deliver, in bytes
+const FIFO_SOURCE_MAX_BYTES: usize = 64 * 1024;
+
+/// Largest file a file source may type, in bytes
+const FILE_SOURCE_MAX_BYTES: u64 = 1024 * 1024;
+
+/// Why a file source couldn't be typed - each carries the expanded path
+#[derive(Debug, PartialEq, Eq)]
+enum FileReadError {
+    NotFound(std::path::PathBuf),
+    PermissionDenied(std::path::PathBuf),
+    /// Anything else, with the reason: too large, not UTF-8, an I/O error
+    Other(std::path::PathBuf, String),
+}
+
+impl std::fmt::Display for FileReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileReadError::NotFound(path) => write!(f, "{} doesn't exist", path.display()),
+            FileReadError::PermissionDenied(path) => write!(f, "{} can't be read: permission denied", path.display()),
+            FileReadError::Other(path, reason) => write!(f, "{} can't be typed: {}", path.display(), reason),
+        }
+    }
+}
+
+impl FileReadError {
+    fn from_io(path: &std::path::Path, e: &std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => FileReadError::NotFound(path.to_path_buf()),
+            std::io::ErrorKind::PermissionDenied => FileReadError::PermissionDenied(path.to_path_buf()),
+            _ => FileReadError::Other(path.to_path_buf(), e.to_string()),
+        }
+    }
+    
+    /// Tell what went wrong from the stderr of `cat` run on the host
+    fn from_host_stderr(path: &std::path::Path, stderr: &str) -> Self {
+        if stderr.contains("No such file or directory") {
+            FileReadError::NotFound(path.to_path_buf())
+        } else if stderr.contains("Permission denied") {
+            FileReadError::PermissionDenied(path.to_path_buf())
+        } else {
+            FileReadError::Other(path.to_path_buf(), stderr.trim().to_string())
+        }
+    }
+    
+    /// Log the failure, followed by the fix for this kind of failure
+    fn log(&self, is_flatpak: bool) {
+        error!("File source: {}", self);
+        match self {
+            FileReadError::NotFound(_) if is_flatpak => {
+                error!("Check the path - it was looked for inside the Flatpak sandbox and on the host");
+            }
+            FileReadError::PermissionDenied(_) => {
+                error!("Check the file's permissions - the plugin runs as your user");
+            }
+            FileReadError::NotFound(_) | FileReadError::Other(..) => {}
+        }
+    }
+}
+
+/// Text of a file source, without the file's final line ending
+/// Err when the file is too large or isn't UTF-8 text
+fn file_source_text(path: &std::path::Path, bytes: Vec<u8>) -> Result<String, FileReadError> {
+    if bytes.len() as u64 > FILE_SOURCE_MAX_BYTES {
+        return Err(FileReadError::Other(path.to_path_buf(), format!("larger than {} bytes", FILE_SOURCE_MAX_BYTES)));
+    }
+    let mut text = String::from_utf8(bytes)
+        .map_err(|_| FileReadError::Other(path.to_path_buf(), "not valid UTF-8 text".to_string()))?;
+    // Editors end files with a newline - typing it would press Enter
+    if text.ends_with('\n') {
+        text.pop();
+        if text.ends_with('\r') {
+            text.pop();
+        }
+    }
+    Ok(text)
+}
+
+/// Open a FIFO for reading without waiting for a producer
+/// Read-write, so the pipe never reports EOF while no producer has it open,
+/// and data written between presses isn't discarded
+#[cfg(target_os = "linux")]
+fn open_fifo(path: &str) -> Result<tokio::net::unix::pipe::Receiver, TypeError> {
+    tokio::net::unix::pipe::OpenOptions::new()
+        .read_write(true)
+        .open_receiver(path)
+        .map_err(|e| {
+            error!("Failed to open FIFO {}: {}", path, e);
+            TypeError::SourceFailed
+        })
+}
+
+/// An open FIFO source, shared by the buttons reading from it
+#[cfg(target_os = "linux")]
+struct FifoSource {
+    receiver: tokio::net::unix::pipe::Receiver,
+    /// The start of a message that didn't finish arriving in time - the
+    /// next press picks up from here
+    partial: Vec<u8>,
+    /// Buttons that read from it; it is closed once they're all gone
+    contexts: HashSet<String>,
+}
+
+/// Read one newline-terminated message from a FIFO
+/// Ok(None) when nothing (or an empty line) arrives within `timeout`
+/// Bytes of a message cut off by the timeout stay in `partial`
+#[cfg(target_os = "linux")]
+async fn read_fifo_message(
+    receiver: &mut tokio::net::unix::pipe::Receiver,
+    partial: &mut Vec<u8>,
+    path: &str,
+    timeout: Duration,
+) -> Result<Option<String>, TypeError> {
+    use tokio::io::AsyncReadExt;
+    
+    // Byte by byte, so nothing past the first message is consumed - it is
+    // left in the pipe for the next press. A byte is kept as soon as it is
+    // read, so the timeout can't lose it
+    let read = async {
+        loop {
+            match receiver.read_u8().await? {
+                b'\n' => return Ok(()),
+                _ if partial.len() >= FIFO_SOURCE_MAX_BYTES => {
+                    return Err(std::io::Error::other(format!("message is longer than {} bytes", FIFO_SOURCE_MAX_BYTES)));
+                }
+                byte => partial.push(byte),
+            }
+        }
+    };
+    
+    match tokio::time::timeout(timeout, read).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            error!("Failed to read from FIFO {}: {}", path, e);
+            return Err(TypeError::SourceFailed);
+        }
+        Err(_) if partial.is_empty() => {
+            info!("No message on FIFO {} within {}ms", path, timeout.as_millis());
+            return Ok(None);
+        }
+        Err(_) => {
+            info!("Only part of a message arrived on FIFO {} within {}ms - the next press waits for the rest", path, timeout.as_millis());
+            return Ok(None);
+        }
+    }
+    let mut message = std::mem::take(partial);
+    if message.last() == Some(&b'\r') {
+        message.pop();
+    }
+    let text = String::from_utf8(message).map_err(|_| {
+        error!("Message on FIFO {} is not valid UTF-8 text", path);
+        TypeError::SourceFailed
+    })?;
+    Ok(Some(text).filter(|t| !t.is_empty()))
+}
+
+/// Parse `xprop -root _NET_ACTIVE_WINDOW` output
+/// e.g. "_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007"
+/// Returns Some(false) for window 0x0 (nothing focused), None if unparseable
+fn parse_active_window(output: &str) -> Option<bool> {
+    let id = output.rsplit('#').next()?.trim();
+    let id = id.split(|c: char| c == ',' || c.is_whitespace()).next()?;
+    let id = u64::from_str_radix(id.strip_prefix("0x")?, 16).ok()?;
+    Some(id != 0)
+}
+
+/// Location of the shared snippet library
+/// ECHO_MACRO_SNIPPETS overrides $XDG_CONFIG_HOME/echo-macro/snippets.json
+fn snippets_path() -> std::path::PathBuf {
+    if let Some(path) = env::var_os("ECHO_MACRO_SNIPPETS") {
+        return std::path::PathBuf::from(path);
+    }
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|| expand_home("~/.config"));
+    config_dir.join("echo-macro").join("snippets.json")
+}
+
+/// Name of the snippet set used when none is selected
+const DEFAULT_SNIPPET_SET: &str = "default";
+
+/// Contents of the snippets file - either one flat set of snippets, or
+/// several named sets of which one is active
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum SnippetLibrary {
+    Sets {
+        /// Set used when ECHO_MACRO_SNIPPET_SET isn't set
+        #[serde(default)]
+        active: Option<String>,
+        sets: HashMap<String, HashMap<String, String>>,
+    },
+    Flat(HashMap<String, String>),
+}
+
+impl SnippetLibrary {
+    /// The snippets visible under the active set: the active set's own
+    /// snippets on top of the default set's. `env_set` (from the environment)
+    /// wins over the file's `active`; a blank name counts as none. Err holds
+    /// the name of a missing active set.
+    fn active_snippets(self, env_set: Option<&str>) -> Result<(String, HashMap<String, String>), String> {
+        let env_set = env_set.map(str::trim).filter(|s| !s.is_empty());
+        match self {
+            SnippetLibrary::Flat(snippets) => {
+                let name = env_set.unwrap_or(DEFAULT_SNIPPET_SET).to_string();
+                if name == DEFAULT_SNIPPET_SET {
+                    Ok((name, snippets))
+                } else {
+                    Err(name)
+                }
+            }
+            SnippetLibrary::Sets { active, mut sets } => {
+                let name = env_set
+                    .map(str::to_string)
+                    .or(active.filter(|a| !a.trim().is_empty()))
+                    .unwrap_or_else(|| DEFAULT_SNIPPET_SET.to_string());
+                let Some(selected) = sets.remove(&name) else {
+                    return Err(name);
+                };
+                let mut snippets = sets.remove(DEFAULT_SNIPPET_SET).unwrap_or_default();
+                snippets.extend(selected);
+                Ok((name, snippets))
+            }
+        }
+    }
+}
+
+/// Why a snippet reference couldn't be resolved
+#[derive(Debug)]
+enum SnippetError {
+    /// The named snippet isn't in the library
+    Missing(String),
+    /// Snippets reference each other in a loop - holds the chain, e.g. a -> b -> a
+    Cycle(Vec<String>),
+}
+
+/// Resolve a snippet by name, expanding `{snippet:other}` references inside it
+/// `stack` holds the names currently being expanded, for cycle detection
+fn resolve_snippet(
+    name: &str,
+    snippets: &HashMap<String, String>,
+    stack: &mut Vec<String>,
+) -> Result<String, SnippetError> {
+    if stack.iter().any(|n| n == name) {
+        let mut chain = stack.clone();
+        chain.push(name.to_string());
+        return Err(SnippetError::Cycle(chain));
+    }
+    let body = snippets
+        .get(name)
+        .ok_or_else(|| SnippetError::Missing(name.to_string()))?;
+    
+    stack.push(name.to_string());
+    let mut result = String::with_capacity(body.len());
+    let mut rest = body.as_str();
+    while let Some(start) = rest.find("{snippet:") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + "{snippet:".len()..];
+        match after.find('}') {
+            Some(end) => {
+                result.push_str(&resolve_snippet(after[..end].trim(), snippets, stack)?);
+                rest = &after[end + 1..];
+            }
+            None => {
+                // Unterminated reference - keep it as literal text
+                result.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    result.push_str(rest);
+    stack.pop();
+    Ok(result)
+}
+
+/// Load the snippet library and resolve `name` from it
+/// The file is read on every press so edits apply to all buttons immediately
+fn lookup_snippet(name: &str) -> Result<String, TypeError> {
+    let path = snippets_path();
+    let contents = std::fs::read_to_string(&path).map_err(|e| {
+        error!("Failed to read snippets file {}: {}", path.display(), e);
+        TypeError::SourceFailed
+    })?;
+    let library: SnippetLibrary = serde_json::from_str(&contents).map_err(|e| {
+        error!("Invalid snippets file {}: {}", path.display(), e);
+        TypeError::InvalidArguments(format!("invalid snippets file: {}", e))
+    })?;
+    
+    let env_set = env::var("ECHO_MACRO_SNIPPET_SET").ok();
+    let (set, snippets) = library.active_snippets(env_set.as_deref()).map_err(|missing| {
+        error!("Snippet set '{}' not found in {}", missing, path.display());
+        TypeError::InvalidArguments(format!("no snippet set '{}'", missing))
+    })?;
+    
+    resolve_snippet(name, &snippets, &mut Vec::new()).map_err(|e| match e {
+        SnippetError::Missing(missing) => {
+            error!("Snippet '{}' not found in set '{}' of {}", missing, set, path.display());
+            TypeError::InvalidArguments(format!("no snippet '{}'", missing))
+        }
+        SnippetError::Cycle(chain) => {
+            error!("Snippet reference cycle: {}", chain.join(" -> "));
+            TypeError::InvalidArguments(format!("snippet reference cycle: {}", chain.join(" -> ")))
+        }
+    })
+}
+
+/// Replace every `{name}` variable in `template` that `lookup` knows
+/// Unknown names are kept verbatim, and `{{` / `}}` stand for literal braces
+fn replace_variables(template: &str, mut lookup: impl FnMut(&str) -> Option<String>) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        result.push_str(&rest[..i]);
+        let brace = rest.as_bytes()[i];
+        let after = &rest[i + 1..];
+        if after.as_bytes().first() == Some(&brace) {
+            result.push(char::from(brace));
+            rest = &after[1..];
+            continue;
+        }
+        let value = (brace == b'{')
+            .then(|| after.find('}'))
+            .flatten()
+            .and_then(|end| Some((lookup(&after[..end])?, end)));
+        match value {
+            Some((value, end)) => {
+                result.push_str(&value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                result.push(char::from(brace));
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Default formats for the date and time variables - ISO 8601
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+const DEFAULT_TIME_FORMAT: &str = "%H:%M:%S";
+
+/// Format the local time with a strftime-style format
+/// Err for a format chrono can't render, instead of panicking
+fn format_local_time(now: &chrono::DateTime<chrono::Local>, format: &str) -> Result<String, ()> {
+    use std::fmt::Write as _;
+    let mut formatted = String::new();
+    write!(formatted, "{}", now.format(format)).map_err(|_| ())?;
+    Ok(formatted)
+}
+
+/// Rewrite every `{name}` / `{name:0W}` counter placeholder in `template`
+/// with `f(name, width)` - width 0 means no padding. Anything else in braces
+/// is kept as literal text.
+fn replace_counters(template: &str, mut f: impl FnMut(&str, usize) -> String) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let placeholder = after.find('}').and_then(|end| {
+            let (name, width) = match after[..end].split_once(':') {
+                Some((name, pad)) => {
+                    let digits = pad.strip_prefix('0').filter(|d| !d.is_empty() && d.chars().all(|c| c.is_ascii_digit()))?;
+                    (name, digits.parse().ok()?)
+                }
+                None => (&after[..end], 0),
+            };
+            let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            valid.then_some((name, width, end))
+        });
+        match placeholder {
+            Some((name, width, end)) => {
+                result.push_str(&f(name, width));
+                rest = &after[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Where a button is in its `snippets` list
+/// The list is remembered by fingerprint only, to notice when it changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SnippetRotation {
+    fingerprint: u64,
+    /// Index of the entry typed last
+    last: usize,
+}
+
+/// Fingerprint of a snippets list - any edit, reorder or resize changes it
+fn snippets_fingerprint(snippets: &[String]) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    snippets.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Index of the snippet to type now, and the rotation for the press after
+/// A rotation made for a different list starts over at the first entry
+fn advance_rotation(rotation: Option<SnippetRotation>, snippets: &[String]) -> (usize, SnippetRotation) {
+    let fingerprint = snippets_fingerprint(snippets);
+    let current = match rotation {
+        Some(r) if r.fingerprint == fingerprint => (r.last + 1) % snippets.len(),
+        _ => 0,
+    };
+    (current, SnippetRotation { fingerprint, last: current })
+}
+
+/// Index of a random snippet to type now, and the rotation for the press after
+/// Never the entry typed last when there is another one to choose
+fn random_rotation(rotation: Option<SnippetRotation>, snippets: &[String], rng: &mut impl Rng) -> (usize, SnippetRotation) {
+    let fingerprint = snippets_fingerprint(snippets);
+    let len = snippets.len();
+    let current = match rotation {
+        Some(r) if r.fingerprint == fingerprint && len > 1 => {
+            // Draw from the others: skip over the last entry
+            let pick = rng.random_range(0..len - 1);
+            if pick >= r.last { pick + 1 } else { pick }
+        }
+        _ => rng.random_range(0..len),
+    };
+    (current, SnippetRotation { fingerprint, last: current })
+}
+
+/// Advance every counter used in `template` once and fill in the new values
+/// Counters start at 1. A padded counter wraps back to 1 once it would no
+/// longer fit its width (999 -> 1 for `{n:03}`), an unpadded one at u64::MAX.
+fn expand_counters(template: &str, state: &mut HashMap<String, u64>) -> String {
+    // First pass: the next value per counter - a name used twice in one
+    // template shows the same value in both places
+    let mut next: HashMap<String, u64> = HashMap::new();
+    replace_counters(template, |name, width| {
+        next.entry(name.to_string()).or_insert_with(|| {
+            let limit = 10u64.checked_pow(width as u32).filter(|_| width > 0);
+            match state.get(name).copied().unwrap_or(0).checked_add(1) {
+                Some(value) if limit.is_none_or(|limit| value < limit) => value,
+                _ => 1,
+            }
+        });
+        String::new()
+    });
+    
+    let expanded = replace_counters(template, |name, width| {
+        format!("{:0width$}", next[name], width = width)
+    });
+    state.extend(next);
+    expanded
+}
+
+/// Perturb `ms` by a random amount of up to `pct` percent either way
+/// The RNG is passed in so a fixed seed gives a reproducible sequence
+fn jitter(ms: u32, pct: u8, rng: &mut impl Rng) -> u32 {
+    let spread = (u64::from(ms) * u64::from(pct.min(100)) / 100) as u32;
+    if spread == 0 {
+        return ms;
+    }
+    rng.random_range(ms - spread..=ms.saturating_add(spread))
+}
+
+/// Pick the freedesktop sound theme event for a press outcome, if enabled
+fn bell_sound(settings: &TypeTextSettings, success: bool) -> Option<&'static str> {
+    match (success, settings.bell_on_success, settings.bell_on_failure) {
+        (true, true, _) => Some("complete"),
+        (false, _, true) => Some("dialog-error"),
+        _ => None,
+    }
+}
+
+/// How many debug screenshots are kept per directory - older ones are deleted
+const MAX_DEBUG_SCREENSHOTS: usize = 20;
+
+/// Expand a leading `~/`, `$HOME/` or `${HOME}/` to the user's home directory
+fn expand_home(path: &str) -> std::path::PathBuf {
+    expand_home_in(path, env::var_os("HOME").as_deref())
+}
+
+/// `expand_home` with the home directory supplied by the caller
+/// A path without the prefix, or no home, leaves the path as it is
+fn expand_home_in(path: &str, home: Option<&std::ffi::OsStr>) -> std::path::PathBuf {
+    let rest = ["~/", "$HOME/", "${HOME}/"].iter().find_map(|prefix| path.strip_prefix(prefix));
+    match (rest, home) {
+        (Some(rest), Some(home)) => std::path::Path::new(home).join(rest),
+        _ => std::path::PathBuf::from(path),
+    }
+}
+
+/// Delete the oldest echo-macro screenshots so at most `keep` remain
+/// Names embed a millisecond timestamp, so sorting by name sorts by age
+fn prune_screenshots(dir: &std::path::Path, keep: usize) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut shots: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.starts_with("echo-macro-") && n.ends_with(".png"))
+                .unwrap_or(false)
+        })
+        .collect();
+    if shots.len() <= keep {
+        return;
+    }
+    shots.sort();
+    for old in &shots[..shots.len() - keep] {
+        if let Err(e) = std::fs::remove_file(old) {
+            debug!("Failed to remove old screenshot {}: {}", old.display(), e);
+        }
+    }
+}
+
+/// Recognize "command not found" from a host command run through flatpak-spawn
+/// Shells report exit code 127; flatpak-spawn itself reports the failed exec on stderr
+fn is_host_tool_missing(code: Option<i32>, stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    code == Some(127)
+        || stderr.contains("command not found")
+        || (stderr.contains("execv") && stderr.contains("no such file or directory"))
+}
+
+/// Recognize ydotool failing because it couldn't reach ydotoold, e.g. right
+/// after resume - as opposed to a bad argument, which retrying won't fix
+fn is_daemon_unreachable(stderr: &str) -> bool {
+    stderr.contains("ydotoold") || stderr.contains("socket") || stderr.contains("connection")
+}
+
+/// Pause before retry number `attempt` (1-based): 100ms, 200ms, 400ms, ...
+/// capped at one second
+fn daemon_retry_delay(attempt: u32) -> Duration {
+    let ms = 100u64.saturating_mul(1 << attempt.saturating_sub(1).min(4));
+    Duration::from_millis(ms.min(1000))
+}
+
+/// Push text into a virtual terminal's input queue, one byte at a time,
+/// with the TIOCSTI ioctl - as if it was typed on that console
+/// Modern kernels restrict TIOCSTI heavily, hence the experimental feature flag
+#[cfg(feature = "vt-backend")]
+fn type_into_vt(device: &str, text: &str) -> Result<(), TypeError> {
+    use std::os::unix::io::AsRawFd;
+    
+    let file = match std::fs::OpenOptions::new().write(true).open(device) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            error!("Permission denied opening {} - typing into a VT needs root (or CAP_SYS_ADMIN)", device);
+            return Err(TypeError::VtFailed(e));
+        }
+        Err(e) => {
+            error!("Failed to open {}: {}", device, e);
+            return Err(TypeError::VtFailed(e));
+        }
+    };
+    
+    for byte in text.bytes() {
+        // SAFETY: fd is valid for the lifetime of `file` and TIOCSTI reads a single byte
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), libc::TIOCSTI, &byte as *const u8) };
+        if ret != 0 {
+            let e = std::io::Error::last_os_error();
+            error!("TIOCSTI on {} failed: {}", device, e);
+            if e.raw_os_error() == Some(libc::EPERM) || e.raw_os_error() == Some(libc::EIO) {
+                error!("The kernel refused the input - this needs CAP_SYS_ADMIN and, on Linux 6.2+, sysctl dev.tty.legacy_tiocsti=1");
+            }
+            return Err(TypeError::VtFailed(e));
+        }
+    }
     Ok(())
 }
+
+#[cfg(not(feature = "vt-backend"))]
+fn type_into_vt(_device: &str, _text: &str) -> Result<(), TypeError> {
+    error!("virtual_terminal is not available - this build doesn't include the vt-backend feature");
+    error!("Rebuild with: cargo build --release --features vt-backend");
+    Err(TypeError::VtFailed(std::io::Error::new(std::io::ErrorKind::Unsupported, "this build doesn't include the vt-backend feature")))
+}
+
+/// Detect a Wayland session (WAYLAND_DISPLAY or XDG_SESSION_TYPE=wayland)
+fn is_wayland_session() -> bool {
+    env::var_os("WAYLAND_DISPLAY").is_some()
+        || env::var("XDG_SESSION_TYPE").map(|t| t == "wayland").unwrap_or(false)
+}
+
+/// Detect an X11 session: a DISPLAY and no sign of Wayland
+fn is_x11_session() -> bool {
+    !is_wayland_session() && env::var_os("DISPLAY").is_some()
+}
+
+/// Command-line clipboard tools the plugin knows how to drive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClipboardTool {
+    /// wl-copy/wl-paste from wl-clipboard (Wayland)
+    WlClipboard,
+    Xclip,
+    Xsel,
+}
+
+impl ClipboardTool {
+    /// Parse the ECHO_MACRO_CLIPBOARD_TOOL override
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "wl-copy" | "wl-clipboard" => Some(ClipboardTool::WlClipboard),
+            "xclip" => Some(ClipboardTool::Xclip),
+            "xsel" => Some(ClipboardTool::Xsel),
+            _ => None,
+        }
+    }
+    
+    fn name(self) -> &'static str {
+        match self {
+            ClipboardTool::WlClipboard => "wl-copy",
+            ClipboardTool::Xclip => "xclip",
+            ClipboardTool::Xsel => "xsel",
+        }
+    }
+    
+    /// Tools to try, in order of preference, for the session type
+    /// Wayland falls back to the X11 tools, which still work through XWayland
+    fn candidates(wayland: bool) -> &'static [ClipboardTool] {
+        if wayland {
+            &[ClipboardTool::WlClipboard, ClipboardTool::Xclip, ClipboardTool::Xsel]
+        } else {
+            &[ClipboardTool::Xclip, ClipboardTool::Xsel]
+        }
+    }
+    
+    /// Cheap invocation that succeeds when the tool is installed
+    fn probe_command(self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            ClipboardTool::WlClipboard => ("wl-copy", &["--version"]),
+            ClipboardTool::Xclip => ("xclip", &["-version"]),
+            ClipboardTool::Xsel => ("xsel", &["--version"]),
+        }
+    }
+    
+    /// Command that sets the clipboard from stdin - the text is never passed
+    /// as an argument so it doesn't show up in the process list
+    fn copy_command(self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            ClipboardTool::WlClipboard => ("wl-copy", &[]),
+            ClipboardTool::Xclip => ("xclip", &["-selection", "clipboard"]),
+            ClipboardTool::Xsel => ("xsel", &["--clipboard", "--input"]),
+        }
+    }
+    
+    /// Command that empties the clipboard - None for xclip, which can only
+    /// set it to no text
+    fn clear_command(self) -> Option<(&'static str, &'static [&'static str])> {
+        match self {
+            ClipboardTool::WlClipboard => Some(("wl-copy", &["--clear"])),
+            ClipboardTool::Xclip => None,
+            ClipboardTool::Xsel => Some(("xsel", &["--clipboard", "--clear"])),
+        }
+    }
+    
+    /// Command that prints the primary selection (highlighted text)
+    fn read_primary_command(self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            ClipboardTool::WlClipboard => ("wl-paste", &["--primary", "--no-newline"]),
+            ClipboardTool::Xclip => ("xclip", &["-o", "-selection", "primary"]),
+            ClipboardTool::Xsel => ("xsel", &["--primary", "--output"]),
+        }
+    }
+    
+    /// Command that prints the clipboard's text
+    fn read_clipboard_command(self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            ClipboardTool::WlClipboard => ("wl-paste", &["--no-newline", "--type", "text"]),
+            ClipboardTool::Xclip => ("xclip", &["-o", "-selection", "clipboard"]),
+            ClipboardTool::Xsel => ("xsel", &["--clipboard", "--output"]),
+        }
+    }
+    
+    /// Command that lists the types the clipboard's content is offered as,
+    /// one per line - None for xsel, which only knows text
+    fn list_types_command(self) -> Option<(&'static str, &'static [&'static str])> {
+        match self {
+            ClipboardTool::WlClipboard => Some(("wl-paste", &["--list-types"])),
+            ClipboardTool::Xclip => Some(("xclip", &["-o", "-selection", "clipboard", "-t", "TARGETS"])),
+            ClipboardTool::Xsel => None,
+        }
+    }
+    
+    /// Arguments that print the clipboard's content as `mime`, byte for byte
+    fn read_typed_args(self, mime: &str) -> Option<Vec<&str>> {
+        match self {
+            ClipboardTool::WlClipboard => Some(vec!["--type", mime]),
+            ClipboardTool::Xclip => Some(vec!["-o", "-selection", "clipboard", "-t", mime]),
+            ClipboardTool::Xsel => None,
+        }
+    }
+    
+    /// Arguments that set the clipboard from stdin as `mime`
+    fn copy_typed_args(self, mime: &str) -> Option<Vec<&str>> {
+        match self {
+            ClipboardTool::WlClipboard => Some(vec!["--type", mime]),
+            ClipboardTool::Xclip => Some(vec!["-selection", "clipboard", "-t", mime]),
+            ClipboardTool::Xsel => None,
+        }
+    }
+}
+
+/// Whether a clipboard type from list_types_command is text: a text/* MIME
+/// type, or one of the X11 text targets
+fn is_text_clipboard_type(name: &str) -> bool {
+    name.starts_with("text/") || matches!(name, "UTF8_STRING" | "STRING" | "TEXT" | "COMPOUND_TEXT")
+}
+
+/// What was on the clipboard before a paste, to put back afterwards
+enum ClipboardSnapshot {
+    /// Nothing - there is nothing to put back
+    Empty,
+    /// Text, put back as text
+    Text(Zeroizing<String>),
+    /// Anything else, e.g. an image, put back as its first MIME type
+    Data { mime: String, bytes: Zeroizing<Vec<u8>> },
+    /// Something that couldn't be read - the paste replaces it for good
+    Unreadable(String),
+}
+
+/// How long the target gets to read the clipboard after Ctrl+V before the
+/// previous contents are put back
+const PASTE_RESTORE_DELAY: Duration = Duration::from_millis(500);
+
+/// Program that injects keystrokes
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    /// ydotool via uinput - works on Wayland and X11, needs ydotoold
+    Ydotool,
+    /// xdotool via XTEST - X11 (and XWayland windows) only, needs no daemon
+    Xdotool,
+}
+
+impl Backend {
+    /// Parse the ECHO_MACRO_BACKEND override
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "ydotool" => Some(Backend::Ydotool),
+            "xdotool" => Some(Backend::Xdotool),
+            _ => None,
+        }
+    }
+    
+    /// The backend's binary, also its name in logs and argument templates
+    fn name(self) -> &'static str {
+        match self {
+            Backend::Ydotool => "ydotool",
+            Backend::Xdotool => "xdotool",
+        }
+    }
+    
+    /// Command line typing uses when a button has no template of its own
+    /// The `--` keeps text starting with "-" from being read as an option
+    fn default_template(self) -> &'static str {
+        match self {
+            Backend::Ydotool => "ydotool type -- {text}",
+            Backend::Xdotool => "xdotool type -- {text}",
+        }
+    }
+    
+    /// Option of `type` for the delay between characters
+    fn delay_flag(self) -> &'static str {
+        match self {
+            Backend::Ydotool => "--key-delay",
+            Backend::Xdotool => "--delay",
+        }
+    }
+}
+
+/// How long ydotool is taken to be missing before it is probed again
+const YDOTOOL_RECHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Whether a backend's program is installed, and since when that is known
+#[derive(Debug, Clone, Copy)]
+struct ToolStatus {
+    /// None until probed
+    available: Option<bool>,
+    checked: Instant,
+}
+
+/// How often a button has been used since it appeared
+/// A press is a key down; a repeat or batch delivers a different number of times
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct UsageStats {
+    presses: u64,
+    successes: u64,
+    failures: u64,
+}
+
+impl UsageStats {
+    /// One line for the log
+    fn describe(&self) -> String {
+        format!("{} press(es), {} succeeded, {} failed", self.presses, self.successes, self.failures)
+    }
+}
+
+/// Why typing or pressing keys failed
+#[derive(Debug)]
+enum TypeError {
+    /// ydotool couldn't reach ydotoold, even after retrying
+    DaemonNotRunning,
+    /// The backend's program couldn't be started
+    SpawnFailed(Backend, std::io::Error),
+    /// The backend ran but reported an error
+    CommandFailed { backend: Backend, code: Option<i32>, stderr: String },
+    /// The host doesn't have the program - a backend or a clipboard tool
+    HostToolMissing(&'static str),
+    /// Inside Flatpak: flatpak-spawn isn't allowed to run host commands
+    FlatpakPermission,
+    /// xdotool has no X display to type into
+    NoDisplay,
+    /// The arguments couldn't be built - a bad template, or a key the backend can't press
+    InvalidArguments(String),
+    /// The clipboard tool ran but couldn't set, clear or read the clipboard
+    ClipboardFailed,
+    /// A backend or clipboard tool didn't finish in time and was killed
+    Timeout(&'static str, Duration),
+    /// A secret, URL, FIFO or snippet source couldn't provide the text
+    SourceFailed,
+    /// Writing to the virtual terminal failed
+    VtFailed(std::io::Error),
+    /// The backend was missing when last checked, so it wasn't run
+    KnownMissing(Backend),
+}
+
+impl std::fmt::Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TypeError::DaemonNotRunning => write!(f, "ydotoold is not running"),
+            TypeError::SpawnFailed(backend, e) => write!(f, "failed to start {}: {}", backend.name(), e),
+            TypeError::CommandFailed { backend, code: Some(code), stderr } => {
+                write!(f, "{} exited with code {}: {}", backend.name(), code, stderr.trim())
+            }
+            TypeError::CommandFailed { backend, code: None, stderr } => {
+                write!(f, "{} was killed: {}", backend.name(), stderr.trim())
+            }
+            TypeError::HostToolMissing(program) => write!(f, "{} is not installed on the host", program),
+            TypeError::FlatpakPermission => write!(f, "flatpak-spawn can't run host commands"),
+            TypeError::NoDisplay => write!(f, "xdotool can't open an X11 display"),
+            TypeError::InvalidArguments(e) => write!(f, "{}", e),
+            TypeError::ClipboardFailed => write!(f, "the clipboard tool failed"),
+            TypeError::Timeout(program, limit) => {
+                write!(f, "{} didn't finish within {}ms and was killed", program, limit.as_millis())
+            }
+            TypeError::SourceFailed => write!(f, "the text source couldn't provide the text"),
+            TypeError::VtFailed(e) => write!(f, "couldn't write to the virtual terminal: {}", e),
+            TypeError::KnownMissing(backend) => write!(f, "{} was not found", backend.name()),
+        }
+    }
+}
+
+impl TypeError {
+    /// Tell what went wrong from a failed run's exit code and stderr
+    fn from_output(backend: Backend, is_flatpak: bool, code: Option<i32>, stderr: &str) -> Self {
+        if is_flatpak && is_host_tool_missing(code, stderr) {
+            TypeError::HostToolMissing(backend.name())
+        } else if is_flatpak && stderr.contains("flatpak-spawn") {
+            TypeError::FlatpakPermission
+        } else if backend == Backend::Ydotool && is_daemon_unreachable(stderr) {
+            TypeError::DaemonNotRunning
+        } else if backend == Backend::Xdotool && (stderr.contains("DISPLAY") || stderr.contains("open display")) {
+            TypeError::NoDisplay
+        } else {
+            TypeError::CommandFailed { backend, code, stderr: stderr.to_string() }
+        }
+    }
+    
+    /// Log `what` failed and why, followed by the fix for this kind of failure
+    fn log(&self, what: &str, is_flatpak: bool) {
+        error!("{}: {}", what, self);
+        match self {
+            TypeError::DaemonNotRunning => {
+                error!("Try: systemctl start ydotoold (or run ydotoold in a terminal)");
+            }
+            TypeError::SpawnFailed(backend, _) if is_flatpak => {
+                error!("Make sure {} is installed on the HOST system", backend.name());
+                error!("Also check: {}", flatpak_override_hint("org.freedesktop.Flatpak", &host_app_id()));
+            }
+            TypeError::SpawnFailed(backend, _) => {
+                error!("Make sure {} is installed: sudo apt install {}", backend.name(), backend.name());
+            }
+            TypeError::HostToolMissing(program) if ClipboardTool::from_name(program).is_some() => {
+                error!("Install wl-clipboard (Wayland) or xclip/xsel (X11) to use the clipboard");
+            }
+            TypeError::HostToolMissing(program) => {
+                error!("Install it on the host, not inside the Flatpak: sudo apt install {} (or dnf/pacman)", program);
+            }
+            TypeError::FlatpakPermission => {
+                error!("The Flatpak needs permission: {}", flatpak_override_hint("org.freedesktop.Flatpak", &host_app_id()));
+            }
+            TypeError::NoDisplay => {
+                error!("xdotool needs an X11 display - it can't type into native Wayland windows");
+            }
+            TypeError::Timeout("ydotool", _) => {
+                error!("ydotoold may be stuck - try: systemctl --user restart ydotoold");
+                error!("If long texts are cut off, raise --command-timeout-ms");
+            }
+            TypeError::KnownMissing(backend) => {
+                error!("Install {} - it is looked for again within {}s, no restart needed", backend.name(), YDOTOOL_RECHECK_INTERVAL.as_secs());
+            }
+            TypeError::Timeout(_, _) => {
+                error!("If long texts are cut off, raise --command-timeout-ms");
+            }
+            TypeError::CommandFailed { .. }
+            | TypeError::InvalidArguments(_)
+            | TypeError::ClipboardFailed
+            | TypeError::SourceFailed
+            | TypeError::VtFailed(_) => {}
+        }
+    }
+}
+
+/// Pick the backend: ydotool, unless the probe found it missing and
+/// xdotool works (checked lazily, only then)
+async fn choose_backend<F: Future<Output = bool>>(ydotool_available: Option<bool>, xdotool_works: impl FnOnce() -> F) -> Backend {
+    if ydotool_available == Some(false) && xdotool_works().await {
+        Backend::Xdotool
+    } else {
+        Backend::Ydotool
+    }
+}
+
+/// X keysym name for a Linux keycode from `keycode`, for xdotool
+fn x_keysym(code: u16) -> Option<&'static str> {
+    const LETTERS: [(u16, &str); 26] = [
+        (16, "q"), (17, "w"), (18, "e"), (19, "r"), (20, "t"), (21, "y"), (22, "u"),
+        (23, "i"), (24, "o"), (25, "p"), (30, "a"), (31, "s"), (32, "d"), (33, "f"),
+        (34, "g"), (35, "h"), (36, "j"), (37, "k"), (38, "l"), (44, "z"), (45, "x"),
+        (46, "c"), (47, "v"), (48, "b"), (49, "n"), (50, "m"),
+    ];
+    const DIGITS: [&str; 10] = ["1", "2", "3", "4", "5", "6", "7", "8", "9", "0"];
+    const FUNCTION_KEYS: [&str; 10] = ["F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10"];
+    
+    let sym = match code {
+        1 => "Escape",
+        2..=11 => DIGITS[usize::from(code - 2)],
+        12 => "minus",
+        13 => "equal",
+        14 => "BackSpace",
+        15 => "Tab",
+        26 => "bracketleft",
+        27 => "bracketright",
+        28 => "Return",
+        29 => "Control_L",
+        39 => "semicolon",
+        40 => "apostrophe",
+        41 => "grave",
+        42 => "Shift_L",
+        43 => "backslash",
+        51 => "comma",
+        52 => "period",
+        53 => "slash",
+        54 => "Shift_R",
+        56 => "Alt_L",
+        57 => "space",
+        58 => "Caps_Lock",
+        59..=68 => FUNCTION_KEYS[usize::from(code - 59)],
+        87 => "F11",
+        88 => "F12",
+        97 => "Control_R",
+        99 => "Print",
+        100 => "Alt_R",
+        102 => "Home",
+        103 => "Up",
+        104 => "Prior",
+        105 => "Left",
+        106 => "Right",
+        107 => "End",
+        108 => "Down",
+        109 => "Next",
+        110 => "Insert",
+        111 => "Delete",
+        119 => "Pause",
+        125 => "Super_L",
+        126 => "Super_R",
+        127 => "Menu",
+        _ => return LETTERS.iter().find(|(c, _)| *c == code).map(|(_, sym)| *sym),
+    };
+    Some(sym)
+}
+
+/// Key events as (Linux keycode, pressed) pairs, sent in order
+type KeyEvents = Vec<(u16, bool)>;
+
+/// Arguments that send key events - (keycode, pressed) pairs - with a backend
+/// None when xdotool has no keysym for one of the keys
+fn key_event_args(backend: Backend, events: &[(u16, bool)]) -> Option<Vec<String>> {
+    match backend {
+        Backend::Ydotool => Some(
+            std::iter::once("key".to_string())
+                .chain(events.iter().map(|(code, down)| format!("{}:{}", code, u8::from(*down))))
+                .collect(),
+        ),
+        Backend::Xdotool => events
+            .iter()
+            .flat_map(|(code, down)| {
+                let action = if *down { "keydown" } else { "keyup" };
+                [Some(action.to_string()), x_keysym(*code).map(str::to_string)]
+            })
+            .collect(),
+    }
+}
+
+/// Arguments that move the pointer to an absolute position with a backend
+/// ydotool has no absolute coordinates of its own: --absolute moves to the
+/// top-left corner first, then by (x, y), so pointer acceleration can skew it
+fn mouse_move_args(backend: Backend, x: i32, y: i32) -> Vec<String> {
+    match backend {
+        Backend::Ydotool => vec![
+            "mousemove".to_string(),
+            "--absolute".to_string(),
+            "-x".to_string(),
+            x.to_string(),
+            "-y".to_string(),
+            y.to_string(),
+        ],
+        Backend::Xdotool => vec!["mousemove".to_string(), x.to_string(), y.to_string()],
+    }
+}
+
+/// Parse the pointer position from `xdotool getmouselocation --shell`
+/// (X=..., Y=..., SCREEN=..., WINDOW=... lines)
+fn parse_mouse_location(output: &str) -> Option<(i32, i32)> {
+    let value = |key: &str| {
+        output
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(key)?.strip_prefix('=')?.parse().ok())
+    };
+    Some((value("X")?, value("Y")?))
+}
+
+/// Pick the first available clipboard tool for the session
+async fn choose_clipboard_tool<F: Future<Output = bool>>(wayland: bool, is_available: impl Fn(ClipboardTool) -> F) -> Option<ClipboardTool> {
+    for tool in ClipboardTool::candidates(wayland) {
+        if is_available(*tool).await {
+            return Some(*tool);
+        }
+    }
+    None
+}
+
+/// Mask text for privacy in logs
+/// - If <= 10 chars: show first char only (e.g., "H...")
+/// - If > 10 chars: show first + 15 asterisks + last (e.g., "H***************d")
+/// 
+/// NOTE: This is best-effort only. Debug mode may expose raw text via SDK logging.
+fn mask_text(text: &str) -> String {
+    let len = text.chars().count();
+    if len == 0 {
+        return "(empty)".to_string();
+    }
+    if len <= 10 {
+        let first = text.chars().next().unwrap();
+        return format!("{}... ({} chars)", first, len);
+    }
+    // > 10 chars: first + 15 asterisks + last
+    let first = text.chars().next().unwrap();
+    let last = text.chars().last().unwrap();
+    format!("{}***************{} ({} chars)", first, last, len)
+}
+
+/// Characters of the text previewed in the button title
+const TITLE_PREVIEW_CHARS: usize = 10;
+
+/// Title of a type-text button: the label when set, else the start of what it types
+/// None means no title - secrets, sensitive buttons and press-time sources aren't
+/// previewed, and neither is anything the log policy hides
+fn title_preview(settings: &TypeTextSettings, policy: LogTextPolicy) -> Option<String> {
+    if let Some(label) = settings.label.as_deref().filter(|l| !l.trim().is_empty()) {
+        return Some(label.to_string());
+    }
+    if settings.sensitive || matches!(policy, LogTextPolicy::Full | LogTextPolicy::LengthOnly) {
+        return None;
+    }
+    // Same precedence as delivery: steps, source, file_path, snippet_ref, snippets, text, then keys alone
+    let text = if !settings.steps.is_empty() {
+        settings.steps.iter().find_map(|step| match step {
+            MacroStep::Type(text) => Some(text.as_str()),
+            _ => None,
+        })?
+    } else if settings.source.is_some() || settings.file_path().is_some() {
+        return None;
+    } else if let Some(name) = settings.snippet_ref.as_deref().filter(|r| !r.trim().is_empty()) {
+        name
+    } else if let Some(first) = settings.snippets.first() {
+        first
+    } else if let Some(text) = settings.text.as_deref().filter(|t| !t.is_empty()) {
+        text
+    } else {
+        settings.keys.as_deref()?
+    };
+    // One line on the key, whatever the snippet's layout
+    let flat = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if flat.is_empty() {
+        return None;
+    }
+    let mut preview: String = flat.chars().take(TITLE_PREVIEW_CHARS).collect();
+    if flat.chars().count() > TITLE_PREVIEW_CHARS {
+        preview.push('…');
+    }
+    Some(preview)
+}
+
+/// How much of the typed text the log may show
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum LogTextPolicy {
+    /// First (and last) character plus the length - see mask_text
+    #[default]
+    Masked,
+    /// "(redacted, N chars)" - no characters at all
+    Full,
+    /// Just "N chars"
+    LengthOnly,
+    /// The real text, for debugging
+    Plain,
+}
+
+impl LogTextPolicy {
+    /// Parse --log-text-policy / ECHO_MACRO_LOG_TEXT_POLICY
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "masked" => Some(LogTextPolicy::Masked),
+            "full" => Some(LogTextPolicy::Full),
+            "length_only" => Some(LogTextPolicy::LengthOnly),
+            "plain" => Some(LogTextPolicy::Plain),
+            _ => None,
+        }
+    }
+    
+    /// Describe `text` for the log under this policy
+    fn describe(self, text: &str) -> String {
+        let len = text.chars().count();
+        match self {
+            LogTextPolicy::Masked => mask_text(text),
+            LogTextPolicy::Full => format!("(redacted, {} chars)", len),
+            LogTextPolicy::LengthOnly => format!("{} chars", len),
+            LogTextPolicy::Plain => format!("{:?} ({} chars)", text, len),
+        }
+    }
+}
+
+/// What happens to a press before anything is typed
+#[derive(Debug, PartialEq)]
+enum PressAdmission {
+    /// The button is locked after its last press
+    Locked,
+    /// Debounced, for the given reason
+    Ignored(String),
+    /// Counted towards the button's batch
+    Batched,
+    /// Typed now
+    Accepted,
+}
+
+/// Cheap to clone - all per-context state lives behind an Arc so background
+/// tasks can hold their own handle
+#[derive(Clone)]
+struct EchoMacroHandler {
+    is_flatpak: bool,
+    /// is_flatpak came from --use-flatpak-spawn / ECHO_MACRO_USE_FLATPAK_SPAWN,
+    /// so the global setting doesn't change it
+    flatpak_forced: bool,
+    /// Whether ydotool is installed - probed at startup, then kept up to
+    /// date by every ydotool run. Shared with the global handler
+    ydotool_status: Arc<Mutex<ToolStatus>>,
+    /// Program used to type and press keys, chosen at startup
+    backend: Backend,
+    /// backend came from --backend / ECHO_MACRO_BACKEND, so the global
+    /// setting doesn't change it
+    backend_forced: bool,
+    /// Typing delay for buttons without a delay_ms of their own
+    /// None falls back to the global settings
+    default_delay_ms: Option<u32>,
+    /// Typed by buttons with nothing configured - None falls back to the
+    /// global settings, then DEFAULT_TEXT
+    default_text: Option<String>,
+    /// Retries for ydotool calls that fail to reach ydotoold
+    daemon_retries: u32,
+    /// How long a host tool may run before it is killed - None waits forever
+    command_timeout: Option<Duration>,
+    /// ydotool binary to run - "ydotool" (found on PATH) unless YDOTOOL_BIN says otherwise
+    ydotool_bin: String,
+    /// ydotool_bin came from --ydotool-bin / YDOTOOL_BIN, so the global
+    /// setting doesn't change it
+    ydotool_bin_forced: bool,
+    /// How much of the text the log shows - None falls back to the global
+    /// settings. Read it through log_text_policy()
+    log_text_policy: Option<LogTextPolicy>,
+    /// Log commands instead of running them - for every button, or for one
+    /// press of a dry_run button (see type_text)
+    dry_run: bool,
+    /// Plugin-wide settings saved by the host, below the command line and
+    /// the environment. Written by the global handler
+    global_settings: Arc<RwLock<GlobalSettings>>,
+    /// Presses waiting for their batch window to close, keyed by context
+    pending_batches: Arc<Mutex<HashMap<String, u32>>>,
+    /// Clipboard tool detected at startup - None disables clipboard features
+    clipboard_tool: Option<ClipboardTool>,
+    /// Buttons locked after a successful press, with the lock's expiry
+    locked_until: Arc<Mutex<HashMap<String, Instant>>>,
+    /// Source of timing jitter - ECHO_MACRO_JITTER_SEED makes it reproducible
+    rng: Arc<Mutex<StdRng>>,
+    /// Counter values per button for counter templates
+    counters: Arc<Mutex<HashMap<String, HashMap<String, u64>>>>,
+    /// Position in the `snippets` list per button
+    snippet_rotations: Arc<Mutex<HashMap<String, SnippetRotation>>>,
+    /// Hold-to-repeat tasks of buttons that are held down
+    repeats: Arc<Mutex<HashMap<String, tokio::task::JoinHandle<()>>>>,
+    /// When buttons with a long press text went down
+    press_started: Arc<Mutex<HashMap<String, Instant>>>,
+    /// When each button's last accepted press came in, for the debounce
+    last_press: Arc<Mutex<HashMap<String, Instant>>>,
+    /// How many type_text calls are running per button
+    in_flight: Arc<Mutex<HashMap<String, usize>>>,
+    /// Presses and delivery outcomes per button, summarized at shutdown
+    usage: Arc<Mutex<HashMap<String, UsageStats>>>,
+    /// Plugin-wide presentation mode, toggled by the presentation mode action
+    presentation_mode: Arc<AtomicBool>,
+    /// Contexts of visible presentation mode buttons, to keep their state in sync
+    presentation_buttons: Arc<Mutex<HashSet<String>>>,
+    /// When ydotoold was last warmed up, shared by all buttons
+    last_warmup: Arc<Mutex<Option<Instant>>>,
+    /// FIFO sources stay open once read, so messages queue up between presses
+    #[cfg(target_os = "linux")]
+    fifos: Arc<tokio::sync::Mutex<HashMap<String, FifoSource>>>,
+    /// Held by the press that is typing, across all buttons - others wait their turn
+    typing_turn: Arc<tokio::sync::Mutex<()>>,
+    /// How long the press holding the typing turn may need it
+    turn_limit: Arc<Mutex<Duration>>,
+    /// Runs every host tool - the backend, clipboard tools and probes
+    runner: Arc<dyn CommandRunner>,
+}
+
+impl EchoMacroHandler {
+    async fn new(defaults: &Defaults, runner: Arc<dyn CommandRunner>) -> Self {
+        let is_flatpak = detect_flatpak(defaults.use_flatpak_spawn);
+        let forced = if defaults.use_flatpak_spawn.is_some() { ", forced by --use-flatpak-spawn / ECHO_MACRO_USE_FLATPAK_SPAWN" } else { "" };
+        if is_flatpak {
+            info!("Echo Macro handler created (Flatpak mode - using flatpak-spawn{})", forced);
+        } else {
+            info!("Echo Macro handler created (native mode - using ydotool directly{})", forced);
+        }
+        
+        let ydotool_bin = defaults.ydotool_bin.as_deref().unwrap_or(DEFAULT_YDOTOOL_BIN);
+        if ydotool_bin != DEFAULT_YDOTOOL_BIN {
+            info!("Using ydotool binary: {}", ydotool_bin);
+        }
+        if defaults.dry_run {
+            info!("Dry run: commands are logged, not run (--dry-run / ECHO_MACRO_DRY_RUN)");
+        }
+        
+        // The probe can be slow or start the daemon as a side effect on some systems.
+        // Skipping it leaves availability unknown - failures surface on the first press.
+        let ydotool_available = if env_flag("ECHO_MACRO_SKIP_STARTUP_PROBE") {
+            info!("Startup ydotool probe disabled (ECHO_MACRO_SKIP_STARTUP_PROBE)");
+            None
+        } else {
+            Some(probe_ydotool(&*runner, is_flatpak, ydotool_bin, defaults.command_timeout).await)
+        };
+        
+        let mut handler = EchoMacroHandler {
+            is_flatpak,
+            flatpak_forced: defaults.use_flatpak_spawn.is_some(),
+            ydotool_status: Arc::new(Mutex::new(ToolStatus { available: ydotool_available, checked: Instant::now() })),
+            backend: Backend::Ydotool,
+            backend_forced: defaults.backend.is_some(),
+            default_delay_ms: defaults.delay_ms,
+            default_text: defaults.default_text.clone(),
+            daemon_retries: defaults.daemon_retries,
+            command_timeout: defaults.command_timeout,
+            ydotool_bin: ydotool_bin.to_string(),
+            ydotool_bin_forced: defaults.ydotool_bin.is_some(),
+            log_text_policy: defaults.log_text_policy,
+            dry_run: defaults.dry_run,
+            global_settings: Arc::new(RwLock::new(GlobalSettings::default())),
+            pending_batches: Arc::new(Mutex::new(HashMap::new())),
+            clipboard_tool: None,
+            locked_until: Arc::new(Mutex::new(HashMap::new())),
+            rng: Arc::new(Mutex::new(Self::jitter_rng())),
+            counters: Arc::new(Mutex::new(HashMap::new())),
+            snippet_rotations: Arc::new(Mutex::new(HashMap::new())),
+            repeats: Arc::new(Mutex::new(HashMap::new())),
+            press_started: Arc::new(Mutex::new(HashMap::new())),
+            last_press: Arc::new(Mutex::new(HashMap::new())),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+            presentation_mode: Arc::new(AtomicBool::new(false)),
+            presentation_buttons: Arc::new(Mutex::new(HashSet::new())),
+            last_warmup: Arc::new(Mutex::new(None)),
+            #[cfg(target_os = "linux")]
+            fifos: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            typing_turn: Arc::new(tokio::sync::Mutex::new(())),
+            turn_limit: Arc::new(Mutex::new(Duration::ZERO)),
+            runner,
+        };
+        handler.clipboard_tool = handler.detect_clipboard_tool().await;
+        handler.backend = handler.detect_backend(defaults.backend).await;
+        handler
+    }
+    
+    /// Use ydotool, falling back to xdotool when ydotool is missing
+    /// `forced` - the --backend / ECHO_MACRO_BACKEND choice - skips detection
+    async fn detect_backend(&self, forced: Option<Backend>) -> Backend {
+        if let Some(backend) = forced {
+            info!("Backend: {} (--backend / ECHO_MACRO_BACKEND)", backend.name());
+            return backend;
+        }
+        
+        let backend = choose_backend(self.ydotool_available(), || async {
+            self.run_host("xdotool", &["--version"]).await.is_ok_and(|output| output.status.success())
+        }).await;
+        if backend == Backend::Xdotool {
+            warn!("ydotool is unavailable - falling back to xdotool");
+            if is_wayland_session() {
+                warn!("xdotool only reaches X11/XWayland windows in a Wayland session");
+            }
+        }
+        info!("Backend: {}", backend.name());
+        backend
+    }
+    
+    /// RNG for timing jitter, seeded from ECHO_MACRO_JITTER_SEED when set
+    fn jitter_rng() -> StdRng {
+        match env::var("ECHO_MACRO_JITTER_SEED").ok().and_then(|s| s.trim().parse().ok()) {
+            Some(seed) => {
+                info!("Timing jitter seeded with {}", seed);
+                StdRng::seed_from_u64(seed)
+            }
+            None => StdRng::from_os_rng(),
+        }
+    }
+    
+    /// A configured delay with the button's jitter applied
+    fn jittered(&self, ms: u32, settings: &TypeTextSettings) -> u32 {
+        if settings.jitter_pct == 0 {
+            return ms;
+        }
+        jitter(ms, settings.jitter_pct, &mut *self.rng.lock().unwrap())
+    }
+    
+    /// Find the clipboard tool to use, once, so presses don't have to probe
+    /// ECHO_MACRO_CLIPBOARD_TOOL (wl-copy, xclip, xsel) skips detection
+    async fn detect_clipboard_tool(&self) -> Option<ClipboardTool> {
+        if let Ok(name) = env::var("ECHO_MACRO_CLIPBOARD_TOOL") {
+            match ClipboardTool::from_name(&name) {
+                Some(tool) => {
+                    info!("Clipboard tool: {} (ECHO_MACRO_CLIPBOARD_TOOL)", tool.name());
+                    return Some(tool);
+                }
+                None => warn!("Unknown ECHO_MACRO_CLIPBOARD_TOOL '{}' - auto-detecting", name),
+            }
+        }
+        
+        self.probe_clipboard_tool(is_wayland_session()).await
+    }
+    
+    /// Probe the tools that suit the session type, in order of preference,
+    /// and log the one found
+    async fn probe_clipboard_tool(&self, wayland: bool) -> Option<ClipboardTool> {
+        let tool = choose_clipboard_tool(wayland, |tool| async move {
+            let (program, args) = tool.probe_command();
+            self.run_host(program, args).await.is_ok_and(|output| output.status.success())
+        }).await;
+        
+        match tool {
+            Some(tool) => info!("Clipboard tool: {} ({} session)", tool.name(), if wayland { "Wayland" } else { "X11" }),
+            None => warn!("No clipboard tool found (wl-copy, xclip or xsel) - clipboard features are disabled"),
+        }
+        tool
+    }
+    
+    /// Run a host binary to completion through the command runner, killing
+    /// it after the command timeout
+    async fn run_host(&self, program: &str, args: &[&str]) -> std::io::Result<Output> {
+        self.run_host_within(program, args, self.command_timeout).await
+    }
+    
+    /// run_host with a timeout of its own
+    /// Goes through flatpak-spawn --host when running inside Flatpak
+    async fn run_host_within(&self, program: &str, args: &[&str], timeout: Option<Duration>) -> std::io::Result<Output> {
+        run_on_host(&*self.runner, self.is_flatpak, program, args, timeout).await
+    }
+    
+    /// Deliver the text using the configured method
+    /// `presses` > 1 types the text that many times in one go (batched presses)
+    /// Returns true on success, false on failure
+    async fn type_text(&self, context: &str, settings: &TypeTextSettings, presses: u32) -> bool {
+        let _in_flight = InFlightGuard::enter(&self.in_flight, context);
+        let mut report = PressReport::default();
+        // A dry_run button, or a global setting that differs from what the
+        // command line, environment or detection chose, gets a handler of
+        // its own - it shares all per-context state with this one
+        let global = self.global_settings();
+        let dry_run = self.dry_run || settings.dry_run || global.dry_run;
+        let is_flatpak = match global.use_flatpak_spawn {
+            Some(use_spawn) if !self.flatpak_forced => use_spawn,
+            _ => self.is_flatpak,
+        };
+        let backend = match global.backend {
+            Some(backend) if !self.backend_forced => backend,
+            _ => self.backend,
+        };
+        let ydotool_bin = match global.ydotool_bin.as_deref().map(str::trim).filter(|bin| !bin.is_empty()) {
+            Some(bin) if !self.ydotool_bin_forced => bin,
+            _ => self.ydotool_bin.as_str(),
+        };
+        let adjusted;
+        let handler = if dry_run != self.dry_run
+            || is_flatpak != self.is_flatpak
+            || backend != self.backend
+            || ydotool_bin != self.ydotool_bin
+        {
+            adjusted = EchoMacroHandler { dry_run, is_flatpak, backend, ydotool_bin: ydotool_bin.to_string(), ..self.clone() };
+            &adjusted
+        } else {
+            self
+        };
+        let success = handler.deliver(context, settings, presses, &mut report).await;
+        {
+            let mut usage = self.usage.lock().unwrap();
+            let stats = usage.entry(context.to_string()).or_default();
+            if success {
+                stats.successes += 1;
+            } else {
+                stats.failures += 1;
+            }
+        }
+        
+        if handler.dry_run {
+            debug!("Dry run - not sending the webhook");
+            return success;
+        }
+        // Fire and forget - a slow or broken webhook never holds up a press
+        if let Some(url) = settings.webhook_url.as_deref().map(str::trim).filter(|u| !u.is_empty()) {
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            let payload = webhook_payload(context, &action_uuid(TYPE_TEXT_ACTION), &report, success, timestamp);
+            tokio::spawn(post_webhook(url.to_string(), payload));
+        }
+        success
+    }
+    
+    /// Wait until no other press is typing - the turn lasts until the guard
+    /// is dropped, and `needs` is how long this press may hold it. Taken
+    /// right before keys are sent, so pre-delays and text sources don't
+    /// hold up other buttons. None when the wait took longer than the
+    /// holder's limit, so a press that hangs (e.g. with the command timeout
+    /// off) can't stall every button for good
+    async fn wait_for_turn(&self, needs: Duration) -> Option<tokio::sync::MutexGuard<'_, ()>> {
+        let turn = match self.typing_turn.try_lock() {
+            Ok(turn) => turn,
+            Err(_) => {
+                debug!("Another press is typing - waiting for it");
+                let started = Instant::now();
+                let mut lock = std::pin::pin!(self.typing_turn.lock());
+                loop {
+                    // Read again on every timeout - the turn may have passed to a press with a longer text
+                    let limit = TYPING_TURN_TIMEOUT.max(*self.turn_limit.lock().unwrap());
+                    let Some(left) = limit.checked_sub(started.elapsed()) else {
+                        error!("Another press has been typing for over {}s - giving up on this one", limit.as_secs());
+                        return None;
+                    };
+                    if let Ok(turn) = tokio::time::timeout(left, lock.as_mut()).await {
+                        break turn;
+                    }
+                }
+            }
+        };
+        *self.turn_limit.lock().unwrap() = needs;
+        Some(turn)
+    }
+    
+    /// How long typing `text` may take: the backend's time limit for it
+    /// plus the line delays. Zero with the command timeout off
+    fn typing_time_limit(&self, text: &str, settings: &TypeTextSettings) -> Duration {
+        let Some(base) = self.command_timeout else {
+            return Duration::ZERO;
+        };
+        let delay_ms = settings.delay_ms.or(self.default_delay_ms).or_else(|| self.global_settings().delay_ms).unwrap_or(0).to_string();
+        let typing = command_time_limit(base, self.backend, &[self.backend.delay_flag(), &delay_ms, "--", text]);
+        let lines = text.matches('\n').count() as u64;
+        typing + Duration::from_millis(lines * u64::from(settings.line_delay_ms))
+    }
+    
+    /// The work behind type_text - fills in `report` once the text is known
+    async fn deliver(&self, context: &str, settings: &TypeTextSettings, presses: u32, report: &mut PressReport) -> bool {
+        // Checked here rather than on press, so batches queued before it was
+        // switched on don't slip through either
+        if self.presentation_mode_active() {
+            info!("Presentation mode is on - not typing");
+            return false;
+        }
+        
+        // clipboard_only sends no keys, so there's nothing to refocus for
+        if settings.pre_delay_ms > 0 && settings.method != TypeMethod::ClipboardOnly {
+            debug!("Waiting {}ms before typing", settings.pre_delay_ms);
+            tokio::time::sleep(Duration::from_millis(settings.pre_delay_ms.into())).await;
+        }
+        
+        // One press types at a time across all buttons, so two presses can't
+        // interleave their keys in the focused window
+        if !settings.steps.is_empty() {
+            let Some(_turn) = self.wait_for_turn(Duration::ZERO).await else {
+                return false;
+            };
+            return self.run_steps(settings, presses, report).await;
+        }
+        
+        if settings.method == TypeMethod::TimedHold {
+            // Jitter can at most double the hold
+            let hold = Duration::from_millis(u64::from(settings.hold_duration_ms) * 2);
+            let Some(_turn) = self.wait_for_turn(hold).await else {
+                return false;
+            };
+            return self.timed_hold(settings).await;
+        }
+        
+        if let Some(spec) = settings.keys.as_deref().filter(|k| !k.trim().is_empty()) {
+            let codes = match parse_chord(spec) {
+                Ok(codes) => codes,
+                Err(e) => {
+                    error!("Invalid key combination: {}", e);
+                    return false;
+                }
+            };
+            if !self.daemon_preflight(settings) {
+                return false;
+            }
+            let Some(_turn) = self.wait_for_turn(Duration::ZERO).await else {
+                return false;
+            };
+            info!("Pressing {}", spec.trim());
+            for _ in 0..presses.max(1) {
+                let hold_ms = self.jittered(settings.key_hold_ms, settings);
+                if let Err(e) = self.press_chord(&codes, settings.chord_release, hold_ms).await {
+                    e.log(&format!("Failed to press {}", spec.trim()), self.is_flatpak);
+                    return false;
+                }
+            }
+            if !settings.has_text() {
+                return true;
+            }
+        }
+        
+        // Precedence: source, then file_path, then snippet_ref, then inline text
+        // Secrets are fetched fresh on every press and wiped when this function returns
+        let secret;
+        let fetched;
+        let snippet;
+        let default_text;
+        let snippet_ref = settings.snippet_ref.as_deref().filter(|r| !r.trim().is_empty());
+        let mut text = match (&settings.source, settings.file_path(), snippet_ref) {
+            (Some(TextSource::SecretService { collection, item }), _, _) => {
+                secret = match fetch_secret(collection, item).await {
+                    Ok(secret) => secret,
+                    Err(_) => return false,
+                };
+                secret.as_str()
+            }
+            (Some(TextSource::Url(url)), _, _) => {
+                fetched = match fetch_url(url).await {
+                    Ok(text) => text,
+                    Err(_) => return false,
+                };
+                fetched.as_str()
+            }
+            (Some(TextSource::Fifo(path)), _, _) => {
+                fetched = match self.read_fifo(context, path).await {
+                    Ok(Some(text)) => text,
+                    Ok(None) if settings.on_empty_source == EmptySourcePolicy::Skip => {
+                        info!("Nothing to type from {} - skipping", path);
+                        return true;
+                    }
+                    Ok(None) => {
+                        error!("Nothing to type from {}", path);
+                        return false;
+                    }
+                    Err(_) => return false,
+                };
+                fetched.as_str()
+            }
+            (None, Some(path), _) => {
+                fetched = match self.read_text_file(path).await {
+                    Ok(text) => text,
+                    Err(e) => {
+                        e.log(self.is_flatpak);
+                        return false;
+                    }
+                };
+                fetched.as_str()
+            }
+            (None, None, Some(name)) => {
+                snippet = match lookup_snippet(name.trim()) {
+                    Ok(snippet) => snippet,
+                    Err(_) => return false,
+                };
+                snippet.as_str()
+            }
+            (None, None, None) if !settings.snippets.is_empty() => {
+                let mut rotations = self.snippet_rotations.lock().unwrap();
+                let previous = rotations.get(context).copied();
+                let (index, rotation) = match settings.selection {
+                    SnippetSelection::Sequential => advance_rotation(previous, &settings.snippets),
+                    SnippetSelection::Random => random_rotation(previous, &settings.snippets, &mut *self.rng.lock().unwrap()),
+                };
+                rotations.insert(context.to_string(), rotation);
+                debug!("Typing snippet {} of {}", index + 1, settings.snippets.len());
+                &settings.snippets[index]
+            }
+            (None, None, None) => {
+                default_text = self.default_text();
+                match settings.effective_text(&default_text) {
+                    Some(text) => text,
+                    None => {
+                        error!("Nothing to type - the text is empty");
+                        return false;
+                    }
+                }
+            }
+        };
+        
+        // Wrapped first, so the wrapper goes through everything the text does
+        let wrapped;
+        if !settings.prefix.is_empty() || !settings.suffix.is_empty() {
+            wrapped = Zeroizing::new([settings.prefix.as_str(), text, settings.suffix.as_str()].concat());
+            text = wrapped.as_str();
+        }
+        
+        // Variables first, so counters never see `{date}` and friends
+        let variables;
+        if settings.expand_variables {
+            variables = match self.expand_variables(text, settings).await {
+                Ok(expanded) => Zeroizing::new(expanded),
+                Err(_) => return false,
+            };
+            text = variables.as_str();
+        }
+        
+        // Each batched press gets its own counter values, so the template is
+        // expanded once per press here instead of repeating the text below
+        let expanded;
+        if settings.counter_template {
+            let times = if matches!(settings.method, TypeMethod::Type | TypeMethod::Paste) { presses.max(1) } else { 1 };
+            let mut counters = self.counters.lock().unwrap();
+            // A persisted button picks up where it left off the first time it's pressed
+            let state = counters
+                .entry(context.to_string())
+                .or_insert_with(|| if settings.persist_counters { settings.counter_state.clone() } else { HashMap::new() });
+            expanded = Zeroizing::new((0..times).map(|_| expand_counters(text, state)).collect::<String>());
+            text = expanded.as_str();
+        }
+        
+        // The substituted copy may contain a secret too, so it gets wiped as well
+        let substituted;
+        if !settings.substitutions.is_empty() {
+            substituted = Zeroizing::new(apply_substitutions(text, &settings.substitutions));
+            text = substituted.as_str();
+        }
+        
+        let collapsed;
+        if settings.collapse_whitespace {
+            collapsed = Zeroizing::new(collapse_whitespace(text, settings.collapse_newlines));
+            text = collapsed.as_str();
+        }
+        
+        let prefixed;
+        if let Some(prefix) = settings.line_prefix.as_deref().filter(|p| !p.is_empty()) {
+            prefixed = Zeroizing::new(prefix_lines(text, prefix, settings.empty_lines));
+            text = prefixed.as_str();
+        }
+        
+        let broken;
+        if settings.max_word_length > 0 {
+            let separator = settings.word_break.as_deref().filter(|b| !b.is_empty()).unwrap_or(DEFAULT_WORD_BREAK);
+            broken = Zeroizing::new(break_long_words(text, settings.max_word_length as usize, separator));
+            text = broken.as_str();
+        }
+        
+        // Batched presses are typed back to back in a single invocation
+        let repeated;
+        if presses > 1 && matches!(settings.method, TypeMethod::Type | TypeMethod::Paste) && !settings.counter_template {
+            repeated = Zeroizing::new(text.repeat(presses as usize));
+            text = repeated.as_str();
+        }
+        
+        // Mask text for privacy in logs - secrets and sensitive buttons never
+        // show any characters, only the length, whatever the policy
+        let describe = |policy: LogTextPolicy| match &settings.source {
+            Some(TextSource::SecretService { item, .. }) => {
+                format!("secret '{}' ({} chars)", item, text.chars().count())
+            }
+            _ if settings.sensitive && matches!(policy, LogTextPolicy::Masked | LogTextPolicy::Plain) => {
+                LogTextPolicy::Full.describe(text)
+            }
+            Some(TextSource::Url(_)) | Some(TextSource::Fifo(_)) | None => policy.describe(text),
+        };
+        let masked = describe(self.log_text_policy());
+        // Plain is for reading your own logs - the webhook never gets raw text
+        report.masked = Some(match self.log_text_policy() {
+            LogTextPolicy::Plain => describe(LogTextPolicy::Masked),
+            policy => describe(policy),
+        });
+        report.chars = text.chars().count();
+        
+        let Some(_turn) = self.wait_for_turn(self.typing_time_limit(text, settings)).await else {
+            return false;
+        };
+        match settings.method {
+            TypeMethod::Type => self.deliver_typed(text, settings, &masked).await,
+            TypeMethod::ClipboardOnly => {
+                info!("Copying to clipboard: {}", masked);
+                match self.copy_to_clipboard(text).await {
+                    Ok(()) => {
+                        info!("Clipboard loaded successfully");
+                        true
+                    }
+                    Err(e) if settings.on_clipboard_failure == ClipboardFailurePolicy::FallbackToType => {
+                        match e {
+                            TypeError::HostToolMissing(program) => warn!("{} is missing - typing the text instead", program),
+                            TypeError::Timeout(program, _) => warn!("{} timed out - typing the text instead", program),
+                            _ => warn!("Couldn't set the clipboard - typing the text instead"),
+                        }
+                        self.deliver_typed(text, settings, &masked).await
+                    }
+                    Err(e) => {
+                        e.log("Failed to copy text to clipboard", self.is_flatpak);
+                        false
+                    }
+                }
+            }
+            TypeMethod::Paste => {
+                if !self.daemon_preflight(settings) {
+                    return false;
+                }
+                let restore_mouse = match self.move_mouse_for_typing(settings).await {
+                    Ok(guard) => guard,
+                    Err(e) => {
+                        e.log("Failed to move the pointer - not pasting", self.is_flatpak);
+                        return false;
+                    }
+                };
+                info!("Pasting: {}", masked);
+                let pasted = match self.paste_text(text, settings).await {
+                    Ok(()) => self.append_enter(settings).await,
+                    Err(e) => Err(e),
+                };
+                let success = match pasted {
+                    Ok(()) => {
+                        info!("Finished pasting successfully");
+                        true
+                    }
+                    Err(e) => {
+                        e.log("Failed to paste text", self.is_flatpak);
+                        false
+                    }
+                };
+                if let Some(guard) = restore_mouse {
+                    guard.restore().await;
+                }
+                success
+            }
+            TypeMethod::VirtualTerminal => {
+                let Some(device) = settings.vt_device.as_deref().filter(|d| !d.trim().is_empty()) else {
+                    error!("virtual_terminal needs a vt_device such as /dev/tty3");
+                    return false;
+                };
+                info!("Typing into {}: {}", device, masked);
+                if self.dry_run {
+                    info!("Dry run - would write {} chars to {}", text.chars().count(), device);
+                    return true;
+                }
+                match type_into_vt(device, text) {
+                    Ok(()) => {
+                        info!("Finished typing successfully");
+                        true
+                    }
+                    Err(_) => {
+                        error!("Failed to type into {}", device);
+                        false
+                    }
+                }
+            }
+            TypeMethod::TimedHold => unreachable!("timed_hold is handled before resolving text"),
+        }
+    }
+    
+    /// Type resolved text the way the "type" method does: move the pointer,
+    /// check focus and the daemon, press the undo boundary, then type
+    /// Also what a clipboard_only button falls back to
+    async fn deliver_typed(&self, text: &str, settings: &TypeTextSettings, masked: &str) -> bool {
+        if settings.skip_if_selection_matches && self.selection_matches(text, is_x11_session()).await {
+            info!("Selection already matches - skipping");
+            return true;
+        }
+        
+        // Moving the pointer can move focus, so it happens before the focus check
+        let restore_mouse = match self.move_mouse_for_typing(settings).await {
+            Ok(guard) => guard,
+            Err(e) => {
+                e.log("Failed to move the pointer - not typing", self.is_flatpak);
+                return false;
+            }
+        };
+        
+        if settings.check_input_target && self.has_input_target().await == Some(false) {
+            error!("No window has input focus - not typing");
+            return false;
+        }
+        
+        if !self.daemon_preflight(settings) {
+            return false;
+        }
+        
+        if let Some(key) = settings.pre_undo_boundary_key.as_deref().filter(|k| !k.trim().is_empty()) {
+            let Some(code) = keycode(key) else {
+                error!("Unknown undo boundary key: {}", key);
+                return false;
+            };
+            debug!("Pressing undo boundary key: {}", key);
+            let hold_ms = self.jittered(settings.key_hold_ms, settings);
+            if let Err(e) = self.press_key(code, hold_ms).await {
+                e.log("Failed to press undo boundary key - not typing", self.is_flatpak);
+                return false;
+            }
+        }
+        
+        let paste = should_paste_unicode(
+            settings.unicode_strategy,
+            text,
+            self.backend,
+            settings.gtk_unicode_input,
+            self.clipboard_tool.is_some(),
+        );
+        let delivered = if paste {
+            info!("Pasting (the text has characters ydotool can't type): {}", masked);
+            self.paste_text(text, settings).await
+        } else {
+            if self.backend == Backend::Ydotool && !settings.gtk_unicode_input {
+                let untypable = text.chars().filter(|c| !is_ydotool_typable(*c)).count();
+                if untypable > 0 {
+                    warn!("{} characters may be dropped or mangled by ydotool - set unicode_strategy to \"auto\" to paste them", untypable);
+                }
+            }
+            info!("Typing: {}", masked);
+            self.type_with_backend(text, settings).await
+        };
+        let delivered = match delivered {
+            Ok(()) => self.append_enter(settings).await,
+            Err(e) => Err(e),
+        };
+        let success = match delivered {
+            Ok(()) => {
+                info!("Finished typing successfully");
+                if let Some(dir) = settings.debug_screenshot_dir.as_deref().filter(|d| !d.trim().is_empty() && !self.dry_run) {
+                    self.capture_debug_screenshot(dir).await;
+                }
+                true
+            }
+            Err(e) => {
+                e.log("Failed to type text", self.is_flatpak);
+                false
+            }
+        };
+        if let Some(guard) = restore_mouse {
+            guard.restore().await;
+        }
+        success
+    }
+    
+    /// Handle a press that should type - the same whether it fires on key
+    /// down or key up
+    async fn do_type(&self, outbound: &mut OutboundEventManager, context: String, settings: TypeTextSettings) {
+        // The settings hold the text, so they're only logged with the plain policy
+        if self.log_text_policy() == LogTextPolicy::Plain && !settings.sensitive {
+            debug!("Settings: {:?}", settings);
+        }
+        
+        match self.admit_press(&context, &settings) {
+            PressAdmission::Locked => {
+                debug!("Button is locked after the last press - ignoring");
+                return;
+            }
+            PressAdmission::Ignored(reason) => {
+                debug!("Ignoring press: {}", reason);
+                if settings.alert_on_ignored_press {
+                    if let Err(e) = outbound.show_alert(context).await {
+                        error!("Failed to show alert: {}", e);
+                    }
+                }
+                return;
+            }
+            PressAdmission::Batched => return,
+            PressAdmission::Accepted => {}
+        }
+        if settings.method == TypeMethod::TimedHold {
+            self.spawn_press(context, settings, 1);
+            return;
+        }
+        
+        let success = self.type_text(&context, &settings, 1).await;
+        self.finish_press(outbound, context, &settings, success).await;
+    }
+    
+    /// Decide what happens to a press before anything is typed
+    /// Batching comes before the debounce: a batch is there to collect fast
+    /// presses, so they add to it instead of being dropped as bounces
+    fn admit_press(&self, context: &str, settings: &TypeTextSettings) -> PressAdmission {
+        if self.is_locked(context) {
+            return PressAdmission::Locked;
+        }
+        if settings.batch_window_ms > 0 {
+            self.queue_batched_press(context.to_string(), settings.clone());
+            return PressAdmission::Batched;
+        }
+        match self.debounce(context, settings) {
+            Some(reason) => PressAdmission::Ignored(reason),
+            None => PressAdmission::Accepted,
+        }
+    }
+    
+    /// Why a press should be ignored: the button is still typing its last
+    /// press, or that press came in too recently. None accepts the press
+    fn debounce(&self, context: &str, settings: &TypeTextSettings) -> Option<String> {
+        if self.in_flight.lock().unwrap().get(context).is_some_and(|n| *n > 0) {
+            return Some("the last press is still typing".to_string());
+        }
+        let window = Duration::from_millis(settings.debounce_ms.unwrap_or(DEFAULT_DEBOUNCE_MS).into());
+        let now = Instant::now();
+        let mut last_press = self.last_press.lock().unwrap();
+        if let Some(since) = last_press.get(context).map(|last| now.duration_since(*last)) {
+            if since < window {
+                return Some(format!("{}ms after the last one", since.as_millis()));
+            }
+        }
+        last_press.insert(context.to_string(), now);
+        None
+    }
+    
+    /// Run the button's macro steps in order, once per press
+    /// Every chord is checked before anything runs; the first failing step
+    /// stops the macro
+    async fn run_steps(&self, settings: &TypeTextSettings, presses: u32, report: &mut PressReport) -> bool {
+        let mut chords = Vec::new();
+        for step in &settings.steps {
+            if let MacroStep::Key(spec) = step {
+                match parse_chord(spec) {
+                    Ok(codes) => chords.push(codes),
+                    Err(e) => {
+                        error!("Invalid key combination in macro: {}", e);
+                        return false;
+                    }
+                }
+            }
+        }
+        
+        let restore_mouse = match self.move_mouse_for_typing(settings).await {
+            Ok(guard) => guard,
+            Err(e) => {
+                e.log("Failed to move the pointer - not running the macro", self.is_flatpak);
+                return false;
+            }
+        };
+        if settings.check_input_target && self.has_input_target().await == Some(false) {
+            error!("No window has input focus - not running the macro");
+            return false;
+        }
+        if !self.daemon_preflight(settings) {
+            return false;
+        }
+        
+        let total = settings.steps.len();
+        for _ in 0..presses.max(1) {
+            let mut chords = chords.iter();
+            for (i, step) in settings.steps.iter().enumerate() {
+                let result = match step {
+                    MacroStep::Type(text) => {
+                        let masked = if settings.sensitive {
+                            LogTextPolicy::Full.describe(text)
+                        } else {
+                            self.log_text_policy().describe(text)
+                        };
+                        info!("Step {}/{}: typing {}", i + 1, total, masked);
+                        report.chars += text.chars().count();
+                        self.type_with_backend(text, settings).await
+                    }
+                    MacroStep::Key(spec) => {
+                        info!("Step {}/{}: pressing {}", i + 1, total, spec.trim());
+                        let codes = chords.next().expect("parsed above");
+                        let hold_ms = self.jittered(settings.key_hold_ms, settings);
+                        self.press_chord(codes, settings.chord_release, hold_ms).await
+                    }
+                    MacroStep::Delay(ms) => {
+                        let ms = self.jittered(*ms, settings);
+                        info!("Step {}/{}: waiting {}ms", i + 1, total, ms);
+                        tokio::time::sleep(Duration::from_millis(ms.into())).await;
+                        Ok(())
+                    }
+                };
+                if let Err(e) = result {
+                    e.log(&format!("Macro step {} failed - stopping", i + 1), self.is_flatpak);
+                    return false;
+                }
+            }
+        }
+        info!("Macro finished");
+        if let Some(guard) = restore_mouse {
+            guard.restore().await;
+        }
+        true
+    }
+    
+    /// Hold the configured key for the configured duration, then release it
+    async fn timed_hold(&self, settings: &TypeTextSettings) -> bool {
+        let Some(key) = settings.hold_key.as_deref().filter(|k| !k.trim().is_empty()) else {
+            error!("timed_hold needs a hold_key");
+            return false;
+        };
+        let Some(code) = keycode(key) else {
+            error!("Unknown hold key: {}", key);
+            return false;
+        };
+        if !self.daemon_preflight(settings) {
+            return false;
+        }
+        
+        let duration_ms = self.jittered(settings.hold_duration_ms, settings);
+        info!("Holding {} for {}ms", key, duration_ms);
+        match self.press_key(code, duration_ms).await {
+            Ok(()) => true,
+            Err(e) => {
+                e.log("Timed hold failed", self.is_flatpak);
+                false
+            }
+        }
+    }
+    
+    /// Read a file source
+    /// Inside Flatpak, a file the sandbox can't see is read on the host instead
+    async fn read_text_file(&self, path: &str) -> Result<String, FileReadError> {
+        let path = expand_home(path.trim());
+        // One byte over the limit is enough to tell the file is too large
+        let read = std::fs::File::open(&path).and_then(|file| {
+            use std::io::Read;
+            let mut bytes = Vec::new();
+            file.take(FILE_SOURCE_MAX_BYTES + 1).read_to_end(&mut bytes)?;
+            Ok(bytes)
+        });
+        let bytes = match read {
+            Ok(bytes) => bytes,
+            Err(e) if self.is_flatpak && matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied) => {
+                debug!("{} isn't visible in the sandbox ({}) - reading it on the host", path.display(), e);
+                let path_str = path.to_string_lossy();
+                match self.run_host("cat", &["--", path_str.as_ref()]).await {
+                    Ok(output) if output.status.success() => output.stdout,
+                    Ok(output) => return Err(FileReadError::from_host_stderr(&path, &String::from_utf8_lossy(&output.stderr))),
+                    Err(e) => return Err(FileReadError::Other(path.clone(), format!("couldn't read it on the host: {}", e))),
+                }
+            }
+            Err(e) => return Err(FileReadError::from_io(&path, &e)),
+        };
+        file_source_text(&path, bytes)
+    }
+    
+    /// Read the next message from a FIFO source for `context`, opening it on
+    /// first use
+    #[cfg(target_os = "linux")]
+    async fn read_fifo(&self, context: &str, path: &str) -> Result<Option<String>, TypeError> {
+        let mut fifos = self.fifos.lock().await;
+        if !fifos.contains_key(path) {
+            let receiver = open_fifo(path)?;
+            fifos.insert(path.to_string(), FifoSource { receiver, partial: Vec::new(), contexts: HashSet::new() });
+        }
+        let fifo = fifos.get_mut(path).expect("inserted above");
+        fifo.contexts.insert(context.to_string());
+        let message = read_fifo_message(&mut fifo.receiver, &mut fifo.partial, path, FIFO_SOURCE_TIMEOUT).await;
+        if message.is_err() {
+            // Reopen next time, in case the FIFO was replaced
+            fifos.remove(path);
+        }
+        message
+    }
+    
+    #[cfg(not(target_os = "linux"))]
+    async fn read_fifo(&self, _context: &str, _path: &str) -> Result<Option<String>, TypeError> {
+        error!("FIFO sources are only supported on Linux");
+        Err(TypeError::InvalidArguments("FIFO sources are only supported on Linux".to_string()))
+    }
+    
+    /// Forget a button that went away, closing the FIFOs no other button reads
+    #[cfg(target_os = "linux")]
+    async fn release_fifos(&self, context: &str) {
+        self.fifos.lock().await.retain(|path, fifo| {
+            fifo.contexts.remove(context);
+            if fifo.contexts.is_empty() {
+                debug!("Closing FIFO {}", path);
+            }
+            !fifo.contexts.is_empty()
+        });
+    }
+    
+    #[cfg(not(target_os = "linux"))]
+    async fn release_fifos(&self, _context: &str) {}
+    
+    /// Whether typing is blocked: by the toggle action, or because the file
+    /// named by ECHO_MACRO_PRESENTATION_FILE exists (for scripts and hotkeys)
+    fn presentation_mode_active(&self) -> bool {
+        self.presentation_mode.load(Ordering::SeqCst)
+            || env::var_os("ECHO_MACRO_PRESENTATION_FILE")
+                .is_some_and(|path| std::path::Path::new(&path).exists())
+    }
+    
+    /// Flip presentation mode and show the new state on every toggle button
+    /// State 0 is off (typing allowed), state 1 is on
+    async fn toggle_presentation_mode(&self, outbound: &mut OutboundEventManager) {
+        let on = !self.presentation_mode.fetch_xor(true, Ordering::SeqCst);
+        info!("Presentation mode {}", if on { "on - typing is blocked" } else { "off" });
+        let buttons: Vec<String> = self.presentation_buttons.lock().unwrap().iter().cloned().collect();
+        for context in buttons {
+            if let Err(e) = outbound.set_state(context, u16::from(on)).await {
+                error!("Failed to set state: {}", e);
+            }
+        }
+    }
+    
+    /// Check what a press depends on, without typing anything: that
+    /// flatpak-spawn can reach the host, that the backend runs and, for
+    /// ydotool, that ydotoold is up. Stops at the first problem, as
+    /// everything after it depends on it
+    async fn health_check(&self) -> Result<(), TypeError> {
+        if self.is_flatpak {
+            match self.run_host("true", &[]).await {
+                Ok(output) if output.status.success() => debug!("flatpak-spawn can reach the host"),
+                Ok(output) => {
+                    debug!("flatpak-spawn --host true failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+                    return Err(TypeError::FlatpakPermission);
+                }
+                Err(e) => {
+                    debug!("Couldn't run flatpak-spawn: {}", e);
+                    return Err(TypeError::FlatpakPermission);
+                }
+            }
+        }
+        
+        let (program, args) = match self.backend {
+            Backend::Ydotool => (self.ydotool_bin.as_str(), ["help"]),
+            Backend::Xdotool => ("xdotool", ["--version"]),
+        };
+        let result = match self.run_host(program, &args).await {
+            Ok(output) if output.status.success() => Ok(()),
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                Err(TypeError::from_output(self.backend, self.is_flatpak, output.status.code(), &stderr))
+            }
+            Err(e) => Err(TypeError::SpawnFailed(self.backend, e)),
+        };
+        if self.backend == Backend::Ydotool {
+            self.update_ydotool_status(&result);
+        }
+        result?;
+        debug!("{} runs", self.backend.name());
+        
+        if self.backend == Backend::Ydotool && !ydotoold_socket_present(&*self.runner, self.is_flatpak, self.command_timeout).await {
+            return Err(TypeError::DaemonNotRunning);
+        }
+        Ok(())
+    }
+    
+    /// Run the health check and show the outcome on the button: the
+    /// checkmark when presses can work, the alert when not - the log says why
+    async fn report_health(&self, outbound: &mut OutboundEventManager, context: String) {
+        info!("Running the health check");
+        let healthy = match self.health_check().await {
+            Ok(()) => {
+                info!("Health check passed - {} is ready to type", self.backend.name());
+                true
+            }
+            Err(e) => {
+                e.log("Health check failed", self.is_flatpak);
+                false
+            }
+        };
+        info!("{}", capability_summary(&Capabilities {
+            wayland: is_wayland_session(),
+            is_flatpak: self.is_flatpak,
+            ydotool_available: self.ydotool_available(),
+            backend: self.backend,
+            daemon_running: (!self.is_flatpak).then(ydotool_daemon_running),
+            clipboard_tool: self.clipboard_tool,
+        }));
+        
+        let shown = if healthy {
+            outbound.show_ok(context).await
+        } else {
+            outbound.show_alert(context).await
+        };
+        if let Err(e) = shown {
+            error!("Failed to show the health check result: {}", e);
+        }
+    }
+    
+    /// Send ydotoold a key-up for Shift - the kernel drops key-ups for keys
+    /// that aren't down, so nothing reaches any app. Throttled across buttons.
+    fn warm_daemon(&self) {
+        if self.backend != Backend::Ydotool {
+            return;
+        }
+        {
+            let mut last = self.last_warmup.lock().unwrap();
+            let now = Instant::now();
+            if !warmup_due(*last, now) {
+                debug!("Daemon was warmed up recently - skipping");
+                return;
+            }
+            *last = Some(now);
+        }
+        
+        let handler = self.clone();
+        tokio::spawn(async move {
+            debug!("Warming up ydotoold");
+            let shift = keycode("shift").unwrap_or(42);
+            if let Err(e) = handler.run_ydotool(&["key", &format!("{}:0", shift)]).await {
+                warn!("Daemon warm-up failed ({}) - the next press may fail too", e);
+            }
+        });
+    }
+    
+    /// Per-button work when a button appears
+    fn appeared(&self, context: &str, settings: &TypeTextSettings) {
+        // OpenDeck may send will_appear for a context that is already
+        // showing, so appearing only resets state when asked to
+        if settings.warm_daemon_on_appear {
+            self.warm_daemon();
+        }
+        if settings.reset_state_on_appear {
+            debug!("Resetting state for {}", context);
+            // An empty entry rather than none, so persisted values aren't reloaded
+            self.snippet_rotations.lock().unwrap().remove(context);
+            self.counters.lock().unwrap().insert(context.to_string(), HashMap::new());
+        }
+    }
+    
+    /// Drop everything kept for a button that has disappeared
+    async fn forget_context(&self, context: &str) {
+        if let Some(stats) = self.usage.lock().unwrap().remove(context) {
+            info!("Usage for {}: {}", context, stats.describe());
+        }
+        self.pending_batches.lock().unwrap().remove(context);
+        self.locked_until.lock().unwrap().remove(context);
+        self.counters.lock().unwrap().remove(context);
+        self.snippet_rotations.lock().unwrap().remove(context);
+        self.stop_repeat(context);
+        self.press_started.lock().unwrap().remove(context);
+        self.last_press.lock().unwrap().remove(context);
+        self.presentation_buttons.lock().unwrap().remove(context);
+        self.release_fifos(context).await;
+    }
+    
+    /// The optional per-press daemon check - a single stat of ydotoold's socket
+    /// Returns false (after logging the daemon hint) when the socket is missing
+    fn daemon_preflight(&self, settings: &TypeTextSettings) -> bool {
+        if !settings.preflight_daemon_check || self.backend != Backend::Ydotool {
+            return true;
+        }
+        // The host's socket isn't visible from inside the sandbox
+        if self.is_flatpak {
+            debug!("Skipping daemon preflight check under Flatpak");
+            return true;
+        }
+        if ydotool_daemon_running() {
+            return true;
+        }
+        error!("ydotoold daemon is not running (no socket at {}) - not typing", ydotool_socket_path().display());
+        error!("Try: systemctl start ydotoold (or run ydotoold in a terminal)");
+        false
+    }
+    
+    /// Run a press on a background task so long-running work (e.g. a timed
+    /// hold) doesn't stall event handling for every other button
+    fn spawn_press(&self, context: String, settings: TypeTextSettings, presses: u32) {
+        let handler = self.clone();
+        tokio::spawn(async move {
+            let success = handler.type_text(&context, &settings, presses).await;
+            
+            // Outside of an event handler, so borrow the SDK's shared manager
+            if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+                handler.finish_press(outbound, context, &settings, success).await;
+            }
+        });
+    }
+    
+    /// Start typing the text over and over until the button is released
+    /// A failed repeat shows the alert and ends the repeat early
+    fn start_repeat(&self, context: String, mut settings: TypeTextSettings) {
+        if self.is_locked(&context) {
+            debug!("Button is locked after the last press - ignoring");
+            return;
+        }
+        let interval = match settings.repeat_interval_ms {
+            0 => DEFAULT_REPEAT_INTERVAL_MS,
+            ms => ms.max(MIN_REPEAT_INTERVAL_MS),
+        };
+        info!("Repeating every {}ms while held", interval);
+        
+        let handler = self.clone();
+        let task_context = context.clone();
+        let task = tokio::spawn(async move {
+            // The pre-type delay is only waited for once per hold
+            if settings.pre_delay_ms > 0 {
+                tokio::time::sleep(Duration::from_millis(settings.pre_delay_ms.into())).await;
+                settings.pre_delay_ms = 0;
+            }
+            loop {
+                if !handler.type_text(&task_context, &settings, 1).await {
+                    error!("Repeat failed - stopping");
+                    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+                        handler.finish_press(outbound, task_context, &settings, false).await;
+                    }
+                    return;
+                }
+                let interval = handler.jittered(interval, &settings);
+                tokio::time::sleep(Duration::from_millis(interval.into())).await;
+            }
+        });
+        // A key down without a key up (e.g. a dropped event) must not leave the old task running
+        if let Some(previous) = self.repeats.lock().unwrap().insert(context, task) {
+            previous.abort();
+        }
+    }
+    
+    /// Stop the button's repeat, if it has one
+    /// Returns whether it was still running - a failed repeat has already reported itself
+    fn stop_repeat(&self, context: &str) -> bool {
+        let Some(task) = self.repeats.lock().unwrap().remove(context) else {
+            return false;
+        };
+        let running = !task.is_finished();
+        task.abort();
+        if running {
+            debug!("Stopped repeating {}", context);
+        }
+        running
+    }
+    
+    /// Count a press towards this context's batch
+    /// The first press of a batch starts a task that waits for the window to
+    /// close and then types the text once per collected press
+    fn queue_batched_press(&self, context: String, settings: TypeTextSettings) {
+        {
+            let mut pending = self.pending_batches.lock().unwrap();
+            let count = pending.entry(context.clone()).or_insert(0);
+            *count += 1;
+            if *count > 1 {
+                debug!("Press added to batch ({} pending)", count);
+                return;
+            }
+        }
+        
+        let handler = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(settings.batch_window_ms.into())).await;
+            // Gone if the button disappeared while the window was open
+            let Some(presses) = handler.pending_batches.lock().unwrap().remove(&context) else {
+                debug!("Batch for {} was cancelled", context);
+                return;
+            };
+            
+            info!("Typing batch of {} press(es)", presses);
+            handler.spawn_press(context, settings, presses);
+        });
+    }
+    
+    /// Everything that happens once a press has been delivered: sound,
+    /// on-button indicator and the optional post-type lock
+    async fn finish_press(
+        &self,
+        outbound: &mut OutboundEventManager,
+        context: String,
+        settings: &TypeTextSettings,
+        success: bool,
+    ) {
+        self.ring_bell(settings, success);
+        show_result(outbound, context.clone(), success, settings).await;
+        if settings.counter_template && settings.persist_counters {
+            self.persist_counters(outbound, context.clone(), settings).await;
+        }
+        if success && settings.post_type_lock_ms > 0 {
+            let title = self.button_title(&context, settings);
+            self.lock_button(outbound, context, settings.post_type_lock_ms, title).await;
+        } else if settings.show_count {
+            self.refresh_title(outbound, context, settings).await;
+        }
+    }
+    
+    /// Write the button's counter values back into its settings
+    async fn persist_counters(&self, outbound: &mut OutboundEventManager, context: String, settings: &TypeTextSettings) {
+        let Some(state) = self.counters.lock().unwrap().get(&context).cloned() else {
+            return;
+        };
+        let mut settings = settings.clone();
+        settings.counter_state = state;
+        match serde_json::to_value(&settings) {
+            Ok(value) => {
+                if let Err(e) = outbound.set_settings(context, value).await {
+                    error!("Failed to save counters: {}", e);
+                }
+            }
+            Err(e) => error!("Failed to serialize counters: {}", e),
+        }
+    }
+    
+    /// Whether a press should be ignored because the button is still locked
+    fn is_locked(&self, context: &str) -> bool {
+        self.locked_until.lock().unwrap()
+            .get(context)
+            .map(|until| Instant::now() < *until)
+            .unwrap_or(false)
+    }
+    
+    /// Lock the button for `ms`, showing a lock as its title until it expires
+    /// and `title` after that
+    async fn lock_button(&self, outbound: &mut OutboundEventManager, context: String, ms: u32, title: Option<String>) {
+        let until = Instant::now() + Duration::from_millis(ms.into());
+        self.locked_until.lock().unwrap().insert(context.clone(), until);
+        debug!("Locked {} for {}ms", context, ms);
+        if let Err(e) = outbound.set_title(context.clone(), Some("🔒".to_string()), None).await {
+            error!("Failed to set title: {}", e);
+        }
+        
+        let handler = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(until.into()).await;
+            {
+                // Only restore our own lock - the button may have disappeared,
+                // or been pressed and re-locked in the meantime
+                let mut locks = handler.locked_until.lock().unwrap();
+                if locks.get(&context) != Some(&until) {
+                    return;
+                }
+                locks.remove(&context);
+            }
+            debug!("Unlocked {}", context);
+            if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+                if let Err(e) = outbound.set_title(context, title, None).await {
+                    error!("Failed to restore title: {}", e);
+                }
+            }
+        });
+    }
+    
+    /// Log and flash the alert when the settings can't work, so a
+    /// misconfigured button shows it before it is pressed
+    async fn check_settings(&self, outbound: &mut OutboundEventManager, context: &str, settings: &TypeTextSettings) {
+        let Some(problem) = settings.problem() else {
+            return;
+        };
+        warn!("Button {} is misconfigured: {}", context, problem);
+        if let Err(e) = outbound.show_alert(context.to_string()).await {
+            error!("Failed to show alert: {}", e);
+        }
+    }
+    
+    /// The press count with show_count, otherwise the label or text preview
+    fn button_title(&self, context: &str, settings: &TypeTextSettings) -> Option<String> {
+        if settings.show_count {
+            let presses = self.usage.lock().unwrap().get(context).map_or(0, |stats| stats.presses);
+            return Some(presses.to_string());
+        }
+        title_preview(settings, self.log_text_policy())
+    }
+    
+    /// Show the press count, label or text preview as the button's title
+    async fn refresh_title(&self, outbound: &mut OutboundEventManager, context: String, settings: &TypeTextSettings) {
+        // While locked the title is the lock - it is refreshed when the lock expires
+        if self.is_locked(&context) {
+            return;
+        }
+        let title = self.button_title(&context, settings);
+        if let Err(e) = outbound.set_title(context, title, None).await {
+            error!("Failed to set title: {}", e);
+        }
+    }
+    
+    /// Play the success/failure sound if enabled, using canberra-gtk-play
+    /// Fire and forget - missing sound support is only logged at debug level
+    fn ring_bell(&self, settings: &TypeTextSettings, success: bool) {
+        let Some(sound) = bell_sound(settings, success) else {
+            return;
+        };
+        
+        let handler = self.clone();
+        tokio::spawn(async move {
+            match handler.run_host("canberra-gtk-play", &[&format!("--id={}", sound)]).await {
+                Ok(output) if output.status.success() => {}
+                Ok(output) => debug!("canberra-gtk-play exited with {} - no bell", output.status),
+                Err(e) => debug!("Can't play bell ({}) - install libcanberra for sound feedback", e),
+            }
+        });
+    }
+    
+    /// Save a screenshot of the whole screen for diagnosing where text landed
+    /// Uses grim on Wayland and scrot on X11. Failures only log a warning -
+    /// the press itself already succeeded.
+    async fn capture_debug_screenshot(&self, dir: &str) {
+        let dir = expand_home(dir);
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            warn!("Can't create screenshot directory {}: {}", dir.display(), e);
+            return;
+        }
+        
+        let millis = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let path = dir.join(format!("echo-macro-{}.png", millis));
+        let path_str = path.to_string_lossy();
+        let program = if is_wayland_session() { "grim" } else { "scrot" };
+        
+        match self.run_host(program, &[path_str.as_ref()]).await {
+            Ok(output) if output.status.success() => {
+                info!("Debug screenshot saved: {}", path.display());
+                prune_screenshots(&dir, MAX_DEBUG_SCREENSHOTS);
+            }
+            Ok(output) => {
+                warn!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim());
+            }
+            Err(e) => {
+                warn!("Can't take debug screenshot - failed to run {}: {}", program, e);
+                warn!("Install grim (Wayland) or scrot (X11) to use debug_screenshot_dir");
+            }
+        }
+    }
+    
+    /// Best-effort check for a focused X11 window that can receive the keystrokes
+    /// Returns None when this can't be determined (Wayland, xprop missing, odd
+    /// output) - callers should type anyway in that case
+    async fn has_input_target(&self) -> Option<bool> {
+        if !is_x11_session() {
+            debug!("Input target check only works on X11 - skipping");
+            return None;
+        }
+        
+        let output = match self.run_host("xprop", &["-root", "_NET_ACTIVE_WINDOW"]).await {
+            Ok(output) if output.status.success() => output,
+            Ok(output) => {
+                debug!("xprop failed ({}) - skipping input target check", output.status);
+                return None;
+            }
+            Err(e) => {
+                debug!("Failed to run xprop ({}) - skipping input target check", e);
+                return None;
+            }
+        };
+        
+        let result = parse_active_window(&String::from_utf8_lossy(&output.stdout));
+        debug!("Input target check: {:?}", result);
+        result
+    }
+    
+    /// Whether the primary selection already holds `text`, so typing it
+    /// would change nothing. Only checked on X11 (`x11`); a selection that
+    /// can't be read never matches, so the text is typed
+    async fn selection_matches(&self, text: &str, x11: bool) -> bool {
+        if !x11 {
+            debug!("Primary selection check only works on X11 - skipping");
+            return false;
+        }
+        self.read_primary_selection().await.as_deref() == Some(text)
+    }
+    
+    /// Read the X11 primary selection (the currently highlighted text)
+    /// Returns None if the selection can't be read
+    async fn read_primary_selection(&self) -> Option<String> {
+        let Some(tool) = self.clipboard_tool else {
+            debug!("No clipboard tool - skipping selection check");
+            return None;
+        };
+        
+        let (program, args) = tool.read_primary_command();
+        match self.run_host(program, args).await {
+            Ok(output) if output.status.success() => {
+                String::from_utf8(output.stdout).ok()
+            }
+            Ok(output) => {
+                // xclip also exits non-zero when nothing is selected
+                debug!("{} could not read the selection ({})", program, output.status);
+                None
+            }
+            Err(e) => {
+                debug!("Failed to run {} ({}) - skipping selection check", program, e);
+                None
+            }
+        }
+    }
+    
+    /// Move the pointer as pre_type_mouse_move asks, before typing
+    /// The returned guard puts the pointer back when dropped, if
+    /// restore_mouse_position is set and the old position could be read
+    async fn move_mouse_for_typing(&self, settings: &TypeTextSettings) -> Result<Option<MouseRestoreGuard>, TypeError> {
+        let Some((x, y)) = settings.pre_type_mouse_move else {
+            return Ok(None);
+        };
+        if x < 0 || y < 0 {
+            return Err(TypeError::InvalidArguments(format!("pointer position {},{} is off screen", x, y)));
+        }
+        
+        let previous = if settings.restore_mouse_position {
+            let previous = self.mouse_location().await;
+            if previous.is_none() {
+                warn!("Couldn't read the pointer position (needs xdotool and X11) - it won't be restored");
+            }
+            previous
+        } else {
+            None
+        };
+        debug!("Moving the pointer to {},{}", x, y);
+        self.move_mouse(x, y).await?;
+        Ok(previous.map(|position| MouseRestoreGuard { handler: self.clone(), position: Some(position) }))
+    }
+    
+    /// Move the pointer to an absolute position
+    async fn move_mouse(&self, x: i32, y: i32) -> Result<(), TypeError> {
+        let args = mouse_move_args(self.backend, x, y);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run_backend(&args).await
+    }
+    
+    /// The pointer's position, read with xdotool whatever the backend -
+    /// ydotool can only move the pointer, not tell where it is
+    async fn mouse_location(&self) -> Option<(i32, i32)> {
+        match self.run_host("xdotool", &["getmouselocation", "--shell"]).await {
+            Ok(output) if output.status.success() => parse_mouse_location(&String::from_utf8_lossy(&output.stdout)),
+            Ok(output) => {
+                debug!("xdotool could not read the pointer position ({})", output.status);
+                None
+            }
+            Err(e) => {
+                debug!("Failed to run xdotool ({}) - can't read the pointer position", e);
+                None
+            }
+        }
+    }
+    
+    /// Press Enter after the text, if the button asks for it
+    async fn append_enter(&self, settings: &TypeTextSettings) -> Result<(), TypeError> {
+        if !settings.append_enter {
+            return Ok(());
+        }
+        debug!("Pressing Enter");
+        let enter = keycode("enter").unwrap_or(28);
+        let hold_ms = self.jittered(settings.key_hold_ms, settings);
+        self.press_key(enter, hold_ms).await.inspect_err(|_| {
+            error!("Text was delivered, but pressing Enter failed");
+        })
+    }
+    
+    /// Paste text: put it on the clipboard, press Ctrl+V, then restore the
+    /// clipboard's previous content - or clear it, when there's nothing to
+    /// put back, so the pasted text doesn't linger
+    /// The restore is awaited, so the press keeps its typing turn until the
+    /// clipboard is back and the next press can't snapshot the pasted text
+    /// Without a clipboard tool the text is typed instead
+    async fn paste_text(&self, text: &str, settings: &TypeTextSettings) -> Result<(), TypeError> {
+        if self.clipboard_tool.is_none() {
+            warn!("No clipboard tool was found at startup - typing instead of pasting");
+            return self.type_with_backend(text, settings).await;
+        }
+        
+        let previous = self.snapshot_clipboard().await;
+        if let Err(e) = self.copy_to_clipboard(text).await {
+            return match settings.on_clipboard_failure {
+                ClipboardFailurePolicy::FallbackToType => {
+                    warn!("Couldn't set the clipboard ({}) - typing the text instead", e);
+                    self.type_with_backend(text, settings).await
+                }
+                ClipboardFailurePolicy::Alert => Err(e),
+            };
+        }
+        
+        let (ctrl, v) = (keycode("ctrl").unwrap_or(29), keycode("v").unwrap_or(47));
+        let pasted = self.send_keys(&[(ctrl, true), (v, true), (v, false), (ctrl, false)]).await;
+        
+        // Restore even if Ctrl+V failed, so the clipboard doesn't keep the text
+        // After a paste, the target gets time to read the clipboard first
+        if pasted.is_ok() && !self.dry_run {
+            tokio::time::sleep(PASTE_RESTORE_DELAY).await;
+        }
+        let restored = match &previous {
+            ClipboardSnapshot::Text(text) => self.copy_to_clipboard(text).await,
+            ClipboardSnapshot::Data { mime, bytes } => self.set_clipboard(bytes, Some(mime)).await,
+            ClipboardSnapshot::Empty => self.clear_clipboard().await,
+            ClipboardSnapshot::Unreadable(what) => {
+                warn!("Couldn't read {} on the clipboard - it can't be put back, so the clipboard is cleared", what);
+                self.clear_clipboard().await
+            }
+        };
+        match restored {
+            Ok(()) => debug!("Restored the previous clipboard"),
+            Err(e) => warn!("Couldn't restore the previous clipboard ({}) - the pasted text is still on it", e),
+        }
+        pasted
+    }
+    
+    /// What the clipboard holds now, read so it can be put back after a paste
+    /// Text is read as text; other content as the first MIME type it is
+    /// offered as, where the tool can list types (not xsel)
+    async fn snapshot_clipboard(&self) -> ClipboardSnapshot {
+        let Some(tool) = self.clipboard_tool else {
+            return ClipboardSnapshot::Unreadable("what was".to_string());
+        };
+        let types = match tool.list_types_command() {
+            Some((program, args)) => match self.run_host(program, args).await {
+                Ok(output) if output.status.success() => Some(
+                    String::from_utf8_lossy(&output.stdout)
+                        .lines()
+                        .map(str::trim)
+                        .filter(|t| !t.is_empty())
+                        .map(str::to_string)
+                        .collect::<Vec<_>>(),
+                ),
+                Ok(output) => {
+                    // Both tools also exit non-zero for an empty clipboard
+                    debug!("{} could not list the clipboard's types ({})", program, output.status);
+                    None
+                }
+                Err(e) => {
+                    debug!("Failed to run {}: {}", program, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        
+        let text = || async {
+            match self.read_clipboard().await {
+                Some(text) => ClipboardSnapshot::Text(Zeroizing::new(text)),
+                None => ClipboardSnapshot::Empty,
+            }
+        };
+        let Some(types) = types else {
+            return text().await;
+        };
+        if types.iter().any(|t| is_text_clipboard_type(t)) {
+            return match text().await {
+                ClipboardSnapshot::Empty => ClipboardSnapshot::Unreadable("the text".to_string()),
+                snapshot => snapshot,
+            };
+        }
+        // X11 also lists targets such as TIMESTAMP that aren't content
+        let Some(mime) = types.into_iter().find(|t| t.contains('/')) else {
+            return ClipboardSnapshot::Empty;
+        };
+        let Some(args) = tool.read_typed_args(&mime) else {
+            return ClipboardSnapshot::Unreadable(mime);
+        };
+        let program = tool.read_clipboard_command().0;
+        match self.run_host(program, &args).await {
+            Ok(output) if output.status.success() && !output.stdout.is_empty() => {
+                debug!("Saved {} bytes of {} from the clipboard", output.stdout.len(), mime);
+                ClipboardSnapshot::Data { mime, bytes: Zeroizing::new(output.stdout) }
+            }
+            Ok(output) => {
+                debug!("{} could not read {} from the clipboard ({})", program, mime, output.status);
+                ClipboardSnapshot::Unreadable(mime)
+            }
+            Err(e) => {
+                debug!("Failed to run {}: {}", program, e);
+                ClipboardSnapshot::Unreadable(mime)
+            }
+        }
+    }
+    
+    /// Fill in the date, time and clipboard variables in `text`
+    /// The clipboard is only read if the text uses it
+    /// Fails when a variable can't be filled in
+    async fn expand_variables(&self, text: &str, settings: &TypeTextSettings) -> Result<String, TypeError> {
+        // Read up front, as the lookup below can't wait for it
+        let mut uses_clipboard = false;
+        replace_variables(text, |name| {
+            uses_clipboard |= name == "clipboard";
+            None
+        });
+        let clipboard = if uses_clipboard { self.read_clipboard().await } else { None };
+        let now = chrono::Local::now();
+        let date_format = settings.date_format.as_deref().filter(|f| !f.is_empty()).unwrap_or(DEFAULT_DATE_FORMAT);
+        let time_format = settings.time_format.as_deref().filter(|f| !f.is_empty()).unwrap_or(DEFAULT_TIME_FORMAT);
+        let mut failed = None;
+        let expanded = replace_variables(text, |name| {
+            let value = match name {
+                "date" => format_local_time(&now, date_format),
+                "time" => format_local_time(&now, time_format),
+                "datetime" => format_local_time(&now, &format!("{}T{}", date_format, time_format)),
+                "clipboard" => clipboard.clone().ok_or(()),
+                _ => return None,
+            };
+            if value.is_err() {
+                failed = Some(match name {
+                    "clipboard" => {
+                        error!("{{clipboard}} is used, but the clipboard is empty or can't be read");
+                        TypeError::ClipboardFailed
+                    }
+                    _ => {
+                        error!("Invalid date_format or time_format for {{{}}}", name);
+                        TypeError::InvalidArguments(format!("invalid date_format or time_format for {{{}}}", name))
+                    }
+                });
+            }
+            value.ok()
+        });
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(expanded),
+        }
+    }
+    
+    /// The clipboard's current text, if it holds any
+    async fn read_clipboard(&self) -> Option<String> {
+        let (program, args) = self.clipboard_tool?.read_clipboard_command();
+        match self.run_host(program, args).await {
+            Ok(output) if output.status.success() => {
+                String::from_utf8(output.stdout).ok().filter(|text| !text.is_empty())
+            }
+            Ok(output) => {
+                // Both tools exit non-zero for an empty clipboard
+                debug!("{} could not read the clipboard ({})", program, output.status);
+                None
+            }
+            Err(e) => {
+                debug!("Failed to run {}: {}", program, e);
+                None
+            }
+        }
+    }
+    
+    /// Empty the clipboard - with xclip, which has no option for it, by
+    /// setting it to no text
+    async fn clear_clipboard(&self) -> Result<(), TypeError> {
+        let Some((program, args)) = self.clipboard_tool.and_then(ClipboardTool::clear_command) else {
+            return self.copy_to_clipboard("").await;
+        };
+        if self.dry_run {
+            info!("Dry run - would run: {}", dry_run_command(program, args));
+            return Ok(());
+        }
+        match self.run_host(program, args).await {
+            Ok(output) if output.status.success() => Ok(()),
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                error!("{} could not clear the clipboard ({}): {}", program, output.status, stderr.trim());
+                Err(self.clipboard_error(program, output.status.code(), &stderr))
+            }
+            Err(e) => {
+                error!("Failed to run {}: {}", program, e);
+                Err(self.clipboard_run_error(program, e))
+            }
+        }
+    }
+    
+    /// Load text onto the clipboard with the detected clipboard tool
+    async fn copy_to_clipboard(&self, text: &str) -> Result<(), TypeError> {
+        self.set_clipboard(text.as_bytes(), None).await
+    }
+    
+    /// Load `bytes` onto the clipboard as text, or as `mime` when given
+    async fn set_clipboard(&self, bytes: &[u8], mime: Option<&str>) -> Result<(), TypeError> {
+        let Some(tool) = self.clipboard_tool else {
+            error!("Clipboard features are disabled - no clipboard tool was found at startup");
+            error!("Install wl-clipboard (Wayland) or xclip/xsel (X11) and reload the plugin");
+            return Err(TypeError::HostToolMissing(ClipboardTool::candidates(is_wayland_session())[0].name()));
+        };
+        let (program, text_args) = tool.copy_command();
+        let args = match mime {
+            None => text_args.to_vec(),
+            Some(mime) => tool.copy_typed_args(mime).ok_or_else(|| {
+                error!("{} can only put text on the clipboard, not {}", program, mime);
+                TypeError::InvalidArguments(format!("{} can't put {} on the clipboard", program, mime))
+            })?,
+        };
+        debug!("Using {} for clipboard", program);
+        if self.dry_run {
+            let size = match mime {
+                None => format!("{} chars", String::from_utf8_lossy(bytes).chars().count()),
+                Some(mime) => format!("{} bytes of {}", bytes.len(), mime),
+            };
+            info!("Dry run - would run: {} <{} on stdin>", dry_run_command(program, &args), size);
+            return Ok(());
+        }
+        
+        let (host_program, host_args) = host_argv(self.is_flatpak, program, &args);
+        match self.runner.run_with_input(host_program, &host_args, bytes, self.command_timeout).await {
+            Ok(status) if status.success() => {
+                debug!("{} completed successfully", program);
+                Ok(())
+            }
+            Ok(status) => {
+                error!("{} failed: {}", program, status);
+                if self.is_flatpak {
+                    error!("Make sure {} is installed on the HOST system", program);
+                } else {
+                    error!("Install wl-clipboard (Wayland) or xclip/xsel (X11) to use clipboard mode");
+                }
+                Err(self.clipboard_error(program, status.code(), ""))
+            }
+            Err(e) => {
+                error!("Failed to run {}: {}", program, e);
+                error!("Install wl-clipboard (Wayland) or xclip/xsel (X11) to use clipboard mode");
+                Err(self.clipboard_run_error(program, e))
+            }
+        }
+    }
+    
+    /// Tell a clipboard tool missing on the host from one that ran and failed
+    fn clipboard_error(&self, program: &'static str, code: Option<i32>, stderr: &str) -> TypeError {
+        if self.is_flatpak && is_host_tool_missing(code, stderr) {
+            TypeError::HostToolMissing(program)
+        } else {
+            TypeError::ClipboardFailed
+        }
+    }
+    
+    /// Tell a clipboard tool that couldn't be started from one that timed out
+    fn clipboard_run_error(&self, program: &'static str, e: std::io::Error) -> TypeError {
+        match e.kind() {
+            std::io::ErrorKind::TimedOut => TypeError::Timeout(program, self.command_timeout.unwrap_or_default()),
+            std::io::ErrorKind::NotFound => TypeError::HostToolMissing(program),
+            _ => TypeError::ClipboardFailed,
+        }
+    }
+    
+    /// Type text with the chosen backend
+    async fn type_with_backend(&self, text: &str, settings: &TypeTextSettings) -> Result<(), TypeError> {
+        if settings.line_delay_ms > 0 && text.contains('\n') {
+            return self.type_lines(text, settings).await;
+        }
+        self.type_line(text, settings).await
+    }
+    
+    /// Type multiline text one line at a time, pressing Enter between lines
+    /// Targets that treat an embedded newline as "submit" get time to catch up
+    async fn type_lines(&self, text: &str, settings: &TypeTextSettings) -> Result<(), TypeError> {
+        let enter = keycode("enter").unwrap_or(28);
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                let hold_ms = self.jittered(settings.key_hold_ms, settings);
+                self.press_key(enter, hold_ms).await?;
+                let delay_ms = self.jittered(settings.line_delay_ms, settings);
+                tokio::time::sleep(Duration::from_millis(delay_ms.into())).await;
+            }
+            // Enter replaces the whole line ending
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if !line.is_empty() {
+                self.type_line(line, settings).await?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Type text without newline handling of its own
+    async fn type_line(&self, text: &str, settings: &TypeTextSettings) -> Result<(), TypeError> {
+        if settings.gtk_unicode_input && !text.chars().all(is_ydotool_typable) {
+            return self.type_with_gtk_unicode(text, settings).await;
+        }
+        self.type_plain(text, settings).await
+    }
+    
+    /// Type plain runs as usual and everything else via Ctrl+Shift+U
+    async fn type_with_gtk_unicode(&self, text: &str, settings: &TypeTextSettings) -> Result<(), TypeError> {
+        for segment in split_for_gtk_unicode(text) {
+            match segment {
+                TypeSegment::Plain(plain) => self.type_plain(plain, settings).await?,
+                TypeSegment::Unicode(c) => {
+                    // The code point isn't logged - the text may be a secret
+                    debug!("Entering a character with Ctrl+Shift+U");
+                    let (open, hex, commit) = gtk_unicode_sequence(c);
+                    self.send_keys(&open).await?;
+                    self.run_backend(&["type", "--", &hex]).await?;
+                    self.send_keys(&commit).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    /// Type text with a single backend call, through the button's argument template
+    async fn type_plain(&self, text: &str, settings: &TypeTextSettings) -> Result<(), TypeError> {
+        for backend in settings.arg_templates.keys().filter(|b| !TEMPLATE_BACKENDS.contains(&b.as_str())) {
+            warn!("Ignoring argument template for unsupported backend '{}'", backend);
+        }
+        let backend = self.backend;
+        let template = settings.arg_templates
+            .get(backend.name())
+            .map(String::as_str)
+            .unwrap_or(backend.default_template());
+        // The expanded arguments hold the text, which may be a secret
+        let mut args = Zeroizing::new(expand_arg_template(backend.name(), template, text).map_err(|e| {
+            TypeError::InvalidArguments(format!("invalid {} argument template: {}", backend.name(), e))
+        })?);
+        
+        // `type` takes the delay right after the subcommand - a template that
+        // sets the delay itself wins. Only the options count: the text is last
+        if let Some(delay_ms) = settings.delay_ms.or(self.default_delay_ms).or_else(|| self.global_settings().delay_ms) {
+            let options = &args[..args.len() - 1];
+            if !options.iter().any(|arg| arg.starts_with(backend.delay_flag())) {
+                let delay_ms = self.jittered(delay_ms, settings);
+                debug!("Key delay: {}ms", delay_ms);
+                args.splice(1..1, [backend.delay_flag().to_string(), delay_ms.to_string()]);
+            }
+        }
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run_backend(&args).await
+    }
+    
+    /// Press and release a single key by its Linux keycode
+    async fn press_key(&self, code: u16, hold_ms: u32) -> Result<(), TypeError> {
+        self.press_chord(&[code], ChordRelease::Reverse, hold_ms).await
+    }
+    
+    /// Press the keys of a chord in order and release them as `release` says
+    /// With a hold time the key-downs and key-ups are sent separately with a
+    /// sleep in between, else all of them in one backend call
+    async fn press_chord(&self, codes: &[u16], release: ChordRelease, hold_ms: u32) -> Result<(), TypeError> {
+        let events = chord_events(codes, release);
+        if hold_ms == 0 {
+            return self.send_keys(&events).await;
+        }
+        
+        let (downs, ups) = events.split_at(codes.len());
+        self.send_keys(downs).await?;
+        // Releases the keys even if this future is dropped mid-sleep
+        let mut guard = KeyReleaseGuard { handler: self.clone(), codes: ups.iter().map(|(code, _)| *code).collect() };
+        tokio::time::sleep(Duration::from_millis(hold_ms.into())).await;
+        guard.release().await
+    }
+    
+    /// Send key events - (keycode, pressed) pairs - in one backend call
+    async fn send_keys(&self, events: &[(u16, bool)]) -> Result<(), TypeError> {
+        debug!("Key events: {}", describe_key_events(events));
+        let Some(args) = key_event_args(self.backend, events) else {
+            return Err(TypeError::InvalidArguments(format!("{} can't press one of these keys: {:?}", self.backend.name(), events)));
+        };
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.run_backend(&args).await
+    }
+    
+    /// Run the chosen backend with the given arguments
+    async fn run_backend(&self, args: &[&str]) -> Result<(), TypeError> {
+        match self.backend {
+            Backend::Ydotool => self.run_ydotool(args).await,
+            Backend::Xdotool => self.run_xdotool(args).await,
+        }
+    }
+    
+    /// Run xdotool with the given arguments
+    async fn run_xdotool(&self, args: &[&str]) -> Result<(), TypeError> {
+        self.run_host_tool(Backend::Xdotool, args).await
+    }
+    
+    /// Run ydotool with the given arguments
+    /// Failures to reach ydotoold are retried up to daemon_retries times
+    async fn run_ydotool(&self, args: &[&str]) -> Result<(), TypeError> {
+        let mut attempt = 0;
+        loop {
+            match self.run_host_tool(Backend::Ydotool, args).await {
+                Err(TypeError::DaemonNotRunning) if attempt < self.daemon_retries => {
+                    attempt += 1;
+                    let delay = daemon_retry_delay(attempt);
+                    debug!("ydotoold not reachable - retry {}/{} in {}ms", attempt, self.daemon_retries, delay.as_millis());
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+    
+    /// Whether ydotool is installed, as far as is known - None when never probed
+    fn ydotool_available(&self) -> Option<bool> {
+        self.ydotool_status.lock().unwrap().available
+    }
+    
+    /// A copy of the latest global settings, so no lock is held across a press
+    fn global_settings(&self) -> GlobalSettings {
+        self.global_settings.read().unwrap().clone()
+    }
+    
+    /// The command line or environment choice, else the global settings'
+    fn log_text_policy(&self) -> LogTextPolicy {
+        self.log_text_policy
+            .or_else(|| self.global_settings().log_text_policy)
+            .unwrap_or_default()
+    }
+    
+    /// What buttons with nothing configured type
+    fn default_text(&self) -> String {
+        self.default_text.clone()
+            .or_else(|| self.global_settings().default_text)
+            .unwrap_or_else(|| DEFAULT_TEXT.to_string())
+    }
+    
+    /// Fail fast while ydotool is known to be missing, instead of spawning it
+    /// on every press. After YDOTOOL_RECHECK_INTERVAL it is probed again, so
+    /// installing it is picked up without a restart
+    async fn check_ydotool(&self) -> Result<(), TypeError> {
+        {
+            let status = self.ydotool_status.lock().unwrap();
+            if status.available != Some(false) {
+                return Ok(());
+            }
+            if status.checked.elapsed() < YDOTOOL_RECHECK_INTERVAL {
+                return Err(TypeError::KnownMissing(Backend::Ydotool));
+            }
+        }
+        debug!("ydotool was missing - checking again");
+        let available = probe_ydotool(&*self.runner, self.is_flatpak, &self.ydotool_bin, self.command_timeout).await;
+        *self.ydotool_status.lock().unwrap() = ToolStatus { available: Some(available), checked: Instant::now() };
+        if available {
+            info!("ydotool is available again");
+            Ok(())
+        } else {
+            Err(TypeError::KnownMissing(Backend::Ydotool))
+        }
+    }
+    
+    /// Record what a ydotool run showed about it being installed
+    fn update_ydotool_status(&self, result: &Result<(), TypeError>) {
+        let available = match result {
+            Err(TypeError::SpawnFailed(..)) | Err(TypeError::HostToolMissing(_)) => false,
+            // Any other outcome means ydotool itself ran
+            _ => true,
+        };
+        let mut status = self.ydotool_status.lock().unwrap();
+        if status.available != Some(available) {
+            if available {
+                info!("ydotool is available");
+            } else {
+                warn!("ydotool is missing - presses fail straight away until it is found again");
+            }
+        }
+        *status = ToolStatus { available: Some(available), checked: Instant::now() };
+    }
+    
+    /// Run a backend's program once
+    /// Uses flatpak-spawn --host when running inside Flatpak
+    async fn run_host_tool(&self, backend: Backend, args: &[&str]) -> Result<(), TypeError> {
+        let program = match backend {
+            Backend::Ydotool => self.ydotool_bin.as_str(),
+            Backend::Xdotool => "xdotool",
+        };
+        if self.dry_run {
+            let command = dry_run_command(program, args);
+            if self.is_flatpak {
+                info!("Dry run - would run: flatpak-spawn --host {}", command);
+            } else {
+                info!("Dry run - would run: {}", command);
+            }
+            return Ok(());
+        }
+        if backend == Backend::Ydotool {
+            self.check_ydotool().await?;
+        }
+        let timeout = self.command_timeout.map(|base| command_time_limit(base, backend, args));
+        let result = match self.run_host_within(program, args, timeout).await {
+            Ok(result) if result.status.success() => {
+                debug!("{} completed successfully", backend.name());
+                Ok(())
+            }
+            Ok(result) => {
+                let stderr = String::from_utf8_lossy(&result.stderr);
+                debug!("{} failed: {}", backend.name(), stderr.trim());
+                Err(TypeError::from_output(backend, self.is_flatpak, result.status.code(), &stderr))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                Err(TypeError::Timeout(backend.name(), timeout.unwrap_or_default()))
+            }
+            Err(e) => Err(TypeError::SpawnFailed(backend, e)),
+        };
+        if backend == Backend::Ydotool {
+            self.update_ydotool_status(&result);
+        }
+        result
+    }
+}
+
+/// Counts a button as typing for as long as it lives - dropping it, even
+/// when the press is aborted, counts the button down again
+struct InFlightGuard<'a> {
+    in_flight: &'a Mutex<HashMap<String, usize>>,
+    context: String,
+}
+
+impl<'a> InFlightGuard<'a> {
+    fn enter(in_flight: &'a Mutex<HashMap<String, usize>>, context: &str) -> Self {
+        *in_flight.lock().unwrap().entry(context.to_string()).or_insert(0) += 1;
+        InFlightGuard { in_flight, context: context.to_string() }
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.context) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.context);
+            }
+        }
+    }
+}
+
+/// Sends the key-ups for held keys when dropped, unless already released
+/// Guarantees a held key is never left stuck down
+struct KeyReleaseGuard {
+    handler: EchoMacroHandler,
+    /// In the order they are released - empty once they have been
+    codes: Vec<u16>,
+}
+
+impl KeyReleaseGuard {
+    async fn release(&mut self) -> Result<(), TypeError> {
+        let ups: KeyEvents = self.codes.drain(..).map(|code| (code, false)).collect();
+        if ups.is_empty() {
+            return Ok(());
+        }
+        self.handler.send_keys(&ups).await
+    }
+}
+
+impl Drop for KeyReleaseGuard {
+    fn drop(&mut self) {
+        if self.codes.is_empty() {
+            return;
+        }
+        // Dropped mid-press, e.g. an aborted repeat - drop can't wait, so
+        // the key-ups go out on a task of its own
+        debug!("Releasing held keys from guard");
+        let ups: KeyEvents = self.codes.drain(..).map(|code| (code, false)).collect();
+        let handler = self.handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handler.send_keys(&ups).await {
+                warn!("Couldn't release the held keys: {}", e);
+            }
+        });
+    }
+}
+
+/// Moves the pointer back to where it was before typing - with restore(),
+/// or from a task of its own when dropped without it
+struct MouseRestoreGuard {
+    handler: EchoMacroHandler,
+    position: Option<(i32, i32)>,
+}
+
+impl MouseRestoreGuard {
+    async fn restore(mut self) {
+        if let Some((x, y)) = self.position.take() {
+            restore_mouse(&self.handler, x, y).await;
+        }
+    }
+}
+
+impl Drop for MouseRestoreGuard {
+    fn drop(&mut self) {
+        if let Some((x, y)) = self.position.take() {
+            let handler = self.handler.clone();
+            tokio::spawn(async move { restore_mouse(&handler, x, y).await });
+        }
+    }
+}
+
+/// Put the pointer back where MouseRestoreGuard found it
+async fn restore_mouse(handler: &EchoMacroHandler, x: i32, y: i32) {
+    debug!("Restoring the pointer to {},{}", x, y);
+    if let Err(e) = handler.move_mouse(x, y).await {
+        warn!("Couldn't restore the pointer position: {}", e);
+    }
+}
+
+/// Reflect the outcome of a press on the button
+/// Failures show the alert; clipboard-only successes show a checkmark since
+/// nothing else visibly happens, and so does any success with confirm_success
+async fn show_result(outbound: &mut OutboundEventManager, context: String, success: bool, settings: &TypeTextSettings) {
+    if !success {
+        if let Err(e) = outbound.show_alert(context).await {
+            error!("Failed to show alert: {}", e);
+        }
+    } else if settings.confirm_success || settings.method == TypeMethod::ClipboardOnly {
+        if let Err(e) = outbound.show_ok(context).await {
+            error!("Failed to show ok: {}", e);
+        }
+    }
+}
+
+impl ActionEventHandler for EchoMacroHandler {
+    fn key_down(
+        &self,
+        event: KeyEvent,
+        outbound: &mut OutboundEventManager,
+    ) -> impl std::future::Future<Output = EventHandlerResult> + Send {
+        let raw_settings = event.payload.settings;
+        let context = event.context;
+        let action = event.action;
+        
+        async move {
+            let Some(name) = known_action(&action) else {
+                // Don't guess and type
+                error!("Key pressed for unknown action UUID: {} - ignoring", action);
+                if let Err(e) = outbound.show_alert(context).await {
+                    error!("Failed to show alert: {}", e);
+                }
+                return Ok(());
+            };
+            if name == PRESENTATION_MODE_ACTION {
+                self.toggle_presentation_mode(outbound).await;
+                return Ok(());
+            }
+            if name == HEALTH_CHECK_ACTION {
+                self.report_health(outbound, context).await;
+                return Ok(());
+            }
+            let Ok(settings) = parse_settings(&context, raw_settings) else {
+                if let Err(e) = outbound.show_alert(context).await {
+                    error!("Failed to show alert: {}", e);
+                }
+                return Ok(());
+            };
+            
+            info!("Key pressed!");
+            self.usage.lock().unwrap().entry(context.clone()).or_default().presses += 1;
+            if settings.repeat {
+                self.start_repeat(context, settings);
+                return Ok(());
+            }
+            if settings.has_long_press() {
+                self.press_started.lock().unwrap().insert(context, Instant::now());
+                debug!("Button has a long press - waiting for key up");
+                return Ok(());
+            }
+            if settings.trigger_on == TriggerOn::Up {
+                debug!("Button types on release - waiting for key up");
+                return Ok(());
+            }
+            self.do_type(outbound, context, settings).await;
+            Ok(())
+        }
+    }
+
+    fn key_up(
+        &self,
+        event: KeyEvent,
+        outbound: &mut OutboundEventManager,
+    ) -> impl std::future::Future<Output = EventHandlerResult> + Send {
+        let raw_settings = event.payload.settings;
+        let context = event.context;
+        let action = event.action;
+        
+        async move {
+            if !is_action(&action, TYPE_TEXT_ACTION) {
+                return Ok(());
+            }
+            // The key down already showed the alert
+            let Ok(settings) = parse_settings(&context, raw_settings) else {
+                self.stop_repeat(&context);
+                self.press_started.lock().unwrap().remove(&context);
+                return Ok(());
+            };
+            // Whatever the settings say now - a repeat may have been started before they changed
+            if self.stop_repeat(&context) {
+                self.finish_press(outbound, context, &settings, true).await;
+                return Ok(());
+            }
+            if settings.repeat {
+                return Ok(());
+            }
+            let started = self.press_started.lock().unwrap().remove(&context);
+            if settings.has_long_press() {
+                // No key down on record (e.g. the settings just changed) counts as a tap
+                let held = started.map(|s| s.elapsed()).unwrap_or_default();
+                let threshold = match settings.long_press_ms {
+                    0 => DEFAULT_LONG_PRESS_MS,
+                    ms => ms,
+                };
+                info!("Key released after {}ms", held.as_millis());
+                let settings = if held >= Duration::from_millis(threshold.into()) {
+                    info!("Long press - typing the long press text");
+                    settings.for_long_press()
+                } else {
+                    settings
+                };
+                self.do_type(outbound, context, settings).await;
+                return Ok(());
+            }
+            if settings.trigger_on != TriggerOn::Up {
+                return Ok(());
+            }
+            info!("Key released!");
+            self.do_type(outbound, context, settings).await;
+            Ok(())
+        }
+    }
+
+    fn will_appear(
+        &self,
+        event: AppearEvent,
+        outbound: &mut OutboundEventManager,
+    ) -> impl std::future::Future<Output = EventHandlerResult> + Send {
+        let raw_settings = event.payload.settings;
+        let context = event.context;
+        let action = event.action;
+        async move {
+            info!("Action appeared: {}", context);
+            if is_action(&action, PRESENTATION_MODE_ACTION) {
+                self.presentation_buttons.lock().unwrap().insert(context.clone());
+                let on = self.presentation_mode.load(Ordering::SeqCst);
+                if let Err(e) = outbound.set_state(context, u16::from(on)).await {
+                    error!("Failed to set state: {}", e);
+                }
+                return Ok(());
+            }
+            let Ok(settings) = parse_settings(&context, raw_settings) else {
+                if let Err(e) = outbound.show_alert(context).await {
+                    error!("Failed to show alert: {}", e);
+                }
+                return Ok(());
+            };
+            self.appeared(&context, &settings);
+            if is_action(&action, TYPE_TEXT_ACTION) {
+                self.check_settings(outbound, &context, &settings).await;
+                self.refresh_title(outbound, context, &settings).await;
+            }
+            Ok(())
+        }
+    }
+
+    fn will_disappear(
+        &self,
+        event: AppearEvent,
+        _outbound: &mut OutboundEventManager,
+    ) -> impl std::future::Future<Output = EventHandlerResult> + Send {
+        let context = event.context;
+        async move {
+            info!("Action disappeared: {}", context);
+            self.forget_context(&context).await;
+            Ok(())
+        }
+    }
+
+    fn did_receive_settings(
+        &self,
+        event: DidReceiveSettingsEvent,
+        outbound: &mut OutboundEventManager,
+    ) -> impl std::future::Future<Output = EventHandlerResult> + Send {
+        let raw_settings = event.payload.settings;
+        let context = event.context;
+        let action = event.action;
+        async move {
+            debug!("Received new settings for: {}", context);
+            let Ok(settings) = parse_settings(&context, raw_settings) else {
+                if let Err(e) = outbound.show_alert(context).await {
+                    error!("Failed to show alert: {}", e);
+                }
+                return Ok(());
+            };
+            {
+                // A changed snippets list starts again from its first entry
+                let mut rotations = self.snippet_rotations.lock().unwrap();
+                if rotations.get(&context).is_some_and(|r| r.fingerprint != snippets_fingerprint(&settings.snippets)) {
+                    debug!("Snippets list changed - starting over");
+                    rotations.remove(&context);
+                }
+            }
+            if is_action(&action, TYPE_TEXT_ACTION) {
+                self.check_settings(outbound, &context, &settings).await;
+                self.refresh_title(outbound, context, &settings).await;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// What the startup probes found, for the one-line capability summary
+/// Only yes/no facts and tool names - no paths, text or settings
+struct Capabilities {
+    wayland: bool,
+    is_flatpak: bool,
+    /// None when the probe was skipped
+    ydotool_available: Option<bool>,
+    backend: Backend,
+    /// None when the socket can't be checked (e.g. from inside Flatpak)
+    daemon_running: Option<bool>,
+    clipboard_tool: Option<ClipboardTool>,
+}
+
+/// Compose the capability summary logged once at startup
+fn capability_summary(caps: &Capabilities) -> String {
+    fn probed(value: Option<bool>, yes: &str, no: &str) -> String {
+        match value {
+            Some(true) => yes.to_string(),
+            Some(false) => no.to_string(),
+            None => "unknown".to_string(),
+        }
+    }
+    
+    let mut backends = Vec::new();
+    if caps.ydotool_available != Some(false) {
+        backends.push("ydotool");
+    }
+    if caps.backend == Backend::Xdotool {
+        backends.push("xdotool");
+    }
+    if caps.clipboard_tool.is_some() {
+        backends.push("clipboard");
+    }
+    if cfg!(feature = "vt-backend") {
+        backends.push("vt");
+    }
+    let features: Vec<&str> = [("vt-backend", cfg!(feature = "vt-backend"))]
+        .into_iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| name)
+        .collect();
+    
+    format!(
+        "Capabilities: session={} flatpak={} backends=[{}] backend={} ydotool={} daemon={} clipboard={} features=[{}]",
+        if caps.wayland { "wayland" } else { "x11" },
+        if caps.is_flatpak { "yes" } else { "no" },
+        backends.join(","),
+        match (caps.backend, caps.ydotool_available) {
+            (Backend::Ydotool, Some(false)) => "none",
+            (backend, _) => backend.name(),
+        },
+        probed(caps.ydotool_available, "available", "missing"),
+        probed(caps.daemon_running, "running", "not-running"),
+        caps.clipboard_tool.map(|t| t.name()).unwrap_or("none"),
+        features.join(","),
+    )
+}
+
+/// Where ydotool looks for ydotoold's socket: $YDOTOOL_SOCKET,
+/// $XDG_RUNTIME_DIR/.ydotool_socket, then /tmp/.ydotool_socket
+fn ydotool_socket_path() -> std::path::PathBuf {
+    ydotool_socket_path_with(|name| env::var_os(name))
+}
+
+/// `ydotool_socket_path` with the environment lookup supplied by the caller
+fn ydotool_socket_path_with(var: impl Fn(&str) -> Option<std::ffi::OsString>) -> std::path::PathBuf {
+    var("YDOTOOL_SOCKET")
+        .map(std::path::PathBuf::from)
+        .or_else(|| var("XDG_RUNTIME_DIR").map(|dir| std::path::Path::new(&dir).join(".ydotool_socket")))
+        .unwrap_or_else(|| std::path::PathBuf::from("/tmp/.ydotool_socket"))
+}
+
+/// Whether ydotoold is up, judged by its socket - `var` and `exists` do the
+/// environment and filesystem lookups so callers can substitute their own
+fn ydotool_daemon_running_with(
+    var: impl Fn(&str) -> Option<std::ffi::OsString>,
+    exists: impl Fn(&std::path::Path) -> bool,
+) -> bool {
+    exists(&ydotool_socket_path_with(var))
+}
+
+/// Whether ydotoold's socket exists - the daemon must run for ydotool to work
+fn ydotool_daemon_running() -> bool {
+    ydotool_daemon_running_with(|name| env::var_os(name), |path| path.exists())
+}
+
+/// Plugin-wide settings, saved by the host (setGlobalSettings) and shared by
+/// every button. A button's own settings, the command line and the
+/// environment all win over these where they overlap
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+struct GlobalSettings {
+    /// Typing delay for buttons without a delay_ms of their own
+    #[serde(default)]
+    delay_ms: Option<u32>,
+    /// Typed by buttons with nothing configured
+    #[serde(default)]
+    default_text: Option<String>,
+    /// How much of the typed text the log shows
+    #[serde(default)]
+    log_text_policy: Option<LogTextPolicy>,
+    /// Log commands instead of running them, for every button
+    #[serde(default)]
+    dry_run: bool,
+    /// Run host tools through flatpak-spawn (true) or directly (false),
+    /// whatever detection found - None keeps the detected mode
+    #[serde(default)]
+    use_flatpak_spawn: Option<bool>,
+    /// Typing backend, "ydotool" or "xdotool" - None keeps the detected one
+    #[serde(default)]
+    backend: Option<Backend>,
+    /// Path or name of the ydotool binary - None runs "ydotool" from PATH
+    #[serde(default)]
+    ydotool_bin: Option<String>,
+    /// Start ydotoold, if it isn't running, once these settings arrive
+    #[serde(default)]
+    start_daemon: bool,
+}
+
+struct EchoMacroGlobalHandler {
+    /// Whether host tools go through flatpak-spawn, as the action handler decided
+    is_flatpak: bool,
+    /// What the action handler detected at startup, for the summary
+    clipboard_tool: Option<ClipboardTool>,
+    /// The action handler's live ydotool status
+    ydotool_status: Arc<Mutex<ToolStatus>>,
+    backend: Backend,
+    /// The backend came from the command line or environment, so the
+    /// global setting doesn't change it
+    backend_forced: bool,
+    /// Start ydotoold at startup if it isn't running - opt-in. None leaves
+    /// it to the global settings
+    start_daemon: Option<bool>,
+    ydotool_bin: String,
+    /// Send no keys at startup either
+    dry_run: bool,
+    /// The action handler's copy, replaced whenever the host sends new ones
+    global_settings: Arc<RwLock<GlobalSettings>>,
+    /// The action handler's command runner
+    runner: Arc<dyn CommandRunner>,
+    /// How long each startup command may take, as for presses
+    command_timeout: Option<Duration>,
+}
+
+impl GlobalEventHandler for EchoMacroGlobalHandler {
+    async fn plugin_ready(
+        &self,
+        outbound: &mut OutboundEventManager,
+    ) -> EventHandlerResult {
+        let is_flatpak = self.is_flatpak;
+        if is_flatpak {
+            info!("Echo Macro plugin connected! Running in Flatpak mode.");
+            info!("Will use flatpak-spawn --host to access ydotool");
+        } else {
+            info!("Echo Macro plugin connected! Running in native mode.");
+        }
+        info!("Using {} to type.", self.backend.name());
+        let ydotool_available = self.ydotool_status.lock().unwrap().available;
+        
+        // Before anything below needs the daemon. Never fails plugin_ready
+        if self.start_daemon == Some(true) && self.backend == Backend::Ydotool && ydotool_available != Some(false) {
+            start_ydotoold(&*self.runner, is_flatpak, self.command_timeout).await;
+        }
+        
+        if self.dry_run {
+            info!("Dry run - not releasing stuck keys");
+        } else if should_release_stuck_keys(env_flag_or("ECHO_MACRO_RESET_KEYS_ON_START", true), ydotool_available) {
+            release_stuck_keys(&*self.runner, is_flatpak, &self.ydotool_bin, self.command_timeout).await;
+        }
+        
+        // The host's socket isn't visible from inside the sandbox
+        let daemon_running = (!is_flatpak).then(ydotool_daemon_running);
+        info!("{}", capability_summary(&Capabilities {
+            wayland: is_wayland_session(),
+            is_flatpak,
+            ydotool_available,
+            backend: self.backend,
+            daemon_running,
+            clipboard_tool: self.clipboard_tool,
+        }));
+        
+        // Answered with did_receive_global_settings
+        if let Err(e) = outbound.get_global_settings().await {
+            warn!("Failed to request the global settings: {}", e);
+        }
+        
+        Ok(())
+    }
+    
+    async fn did_receive_global_settings(
+        &self,
+        event: DidReceiveGlobalSettingsEvent,
+        _outbound: &mut OutboundEventManager,
+    ) -> EventHandlerResult {
+        if store_global_settings(&self.global_settings, event.payload.settings).is_ok() {
+            self.start_daemon_if_asked().await;
+        }
+        Ok(())
+    }
+}
+
+impl EchoMacroGlobalHandler {
+    /// Start ydotoold for a global start_daemon - here rather than in
+    /// plugin_ready, which runs before the host sends the global settings.
+    /// The command line or environment wins, as for the other fields
+    async fn start_daemon_if_asked(&self) {
+        let (start_daemon, backend) = {
+            let global = self.global_settings.read().unwrap();
+            let backend = match global.backend {
+                Some(backend) if !self.backend_forced => backend,
+                _ => self.backend,
+            };
+            (self.start_daemon.unwrap_or(global.start_daemon), backend)
+        };
+        let ydotool_available = self.ydotool_status.lock().unwrap().available;
+        if start_daemon && backend == Backend::Ydotool && ydotool_available != Some(false) {
+            start_ydotoold(&*self.runner, self.is_flatpak, self.command_timeout).await;
+        }
+    }
+}
+
+/// Replace the stored global settings with the ones the host sent
+/// A payload that can't be read keeps the previous settings, so one bad
+/// field doesn't reset every plugin-wide setting
+fn store_global_settings(store: &RwLock<GlobalSettings>, raw: serde_json::Value) -> Result<(), ()> {
+    let settings: GlobalSettings = serde_json::from_value(raw).map_err(|e| {
+        error!("Invalid global settings, keeping the previous ones: {}", e);
+    })?;
+    debug!(
+        "Received global settings: delay {:?}, default text {}, log text policy {:?}, dry run {}, flatpak-spawn {:?}, backend {:?}, ydotool binary {:?}, start daemon {}",
+        settings.delay_ms,
+        if settings.default_text.is_some() { "set" } else { "unset" },
+        settings.log_text_policy,
+        settings.dry_run,
+        settings.use_flatpak_spawn,
+        settings.backend,
+        settings.ydotool_bin,
+        settings.start_daemon,
+    );
+    *store.write().unwrap() = settings;
+    Ok(())
+}
+
+/// A command run in flight - boxed so CommandRunner stays object safe
+type CommandFuture<'a, T> = Pin<Box<dyn Future<Output = std::io::Result<T>> + Send + 'a>>;
+
+/// Runs external programs to completion and collects their output
+/// The handler goes through this instead of Command, so the typing logic
+/// can run against something other than real processes
+trait CommandRunner: Send + Sync {
+    /// Run `program` and wait for it - for at most `timeout`, after which it
+    /// is killed and the error is io::ErrorKind::TimedOut
+    fn run<'a>(&'a self, program: &'a str, args: &'a [&'a str], timeout: Option<Duration>) -> CommandFuture<'a, Output>;
+    
+    /// Run `program` with `input` on its stdin and wait for it to exit
+    /// Its output isn't captured - clipboard tools fork to keep serving the
+    /// selection, and the forked child would hold the pipes open forever
+    /// Killed after `timeout` like `run`
+    fn run_with_input<'a>(&'a self, program: &'a str, args: &'a [&'a str], input: &'a [u8], timeout: Option<Duration>) -> CommandFuture<'a, ExitStatus>;
+    
+    /// Start `program` in the background without waiting for it
+    /// Returns its process id
+    fn spawn(&self, program: &str, args: &[&str]) -> std::io::Result<u32>;
+}
+
+/// Runs commands for real, with tokio's Command
+struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+    fn run<'a>(&'a self, program: &'a str, args: &'a [&'a str], timeout: Option<Duration>) -> CommandFuture<'a, Output> {
+        Box::pin(async move {
+            let mut child = tokio::process::Command::new(program)
+                .args(args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                // An aborted press doesn't leave the child running
+                .kill_on_drop(true)
+                .spawn()?;
+            let Some(timeout) = timeout else {
+                return child.wait_with_output().await;
+            };
+            
+            // Both pipes are read while waiting, so a chatty child can't fill one and stall
+            let mut stdout = child.stdout.take();
+            let mut stderr = child.stderr.take();
+            let finished = tokio::time::timeout(timeout, async {
+                tokio::try_join!(child.wait(), read_pipe(&mut stdout), read_pipe(&mut stderr))
+            }).await;
+            match finished {
+                Ok(result) => {
+                    let (status, stdout, stderr) = result?;
+                    Ok(Output { status, stdout, stderr })
+                }
+                Err(_) => {
+                    // Waits for it too, so it doesn't linger as a zombie
+                    let _ = child.kill().await;
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("{} didn't finish within {}ms and was killed", program, timeout.as_millis()),
+                    ))
+                }
+            }
+        })
+    }
+    
+    fn run_with_input<'a>(&'a self, program: &'a str, args: &'a [&'a str], input: &'a [u8], timeout: Option<Duration>) -> CommandFuture<'a, ExitStatus> {
+        Box::pin(async move {
+            let mut child = tokio::process::Command::new(program)
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .kill_on_drop(true)
+                .spawn()?;
+            let mut stdin = child.stdin.take();
+            let finished = async {
+                // Dropping stdin closes the pipe so the tool sees EOF
+                if let Some(mut stdin) = stdin.take() {
+                    if let Err(e) = tokio::io::AsyncWriteExt::write_all(&mut stdin, input).await {
+                        warn!("Failed to write to {}: {}", program, e);
+                    }
+                }
+                child.wait().await
+            };
+            let Some(timeout) = timeout else {
+                return finished.await;
+            };
+            match tokio::time::timeout(timeout, finished).await {
+                Ok(status) => status,
+                Err(_) => {
+                    let _ = child.kill().await;
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("{} didn't finish within {}ms and was killed", program, timeout.as_millis()),
+                    ))
+                }
+            }
+        })
+    }
+    
+    fn spawn(&self, program: &str, args: &[&str]) -> std::io::Result<u32> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let pid = child.id();
+        // Reap it if it ever exits, so it doesn't linger as a zombie
+        std::thread::spawn(move || child.wait());
+        Ok(pid)
+    }
+}
+
+/// Read a child's pipe to the end - nothing when it wasn't captured
+async fn read_pipe(pipe: &mut Option<impl tokio::io::AsyncRead + Unpin>) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if let Some(pipe) = pipe {
+        tokio::io::AsyncReadExt::read_to_end(pipe, &mut buf).await?;
+    }
+    Ok(buf)
+}
+
+/// Time a typing backend gets per character on top of the command timeout,
+/// so long texts aren't killed for taking as long as they should
+const TIMEOUT_ALLOWANCE_PER_CHAR_MS: u64 = 50;
+
+/// How long a backend run may take: `base`, plus an allowance for each
+/// character of text (the words after `--`) and its key delay
+fn command_time_limit(base: Duration, backend: Backend, args: &[&str]) -> Duration {
+    let flag = backend.delay_flag();
+    let delay_ms = args.iter().enumerate().find_map(|(i, arg)| {
+        let value = match arg.strip_prefix(flag)? {
+            "" => *args.get(i + 1)?,
+            rest => rest.strip_prefix('=')?,
+        };
+        value.parse::<u64>().ok()
+    }).unwrap_or(0);
+    let chars: u64 = args.iter()
+        .skip_while(|arg| **arg != "--")
+        .skip(1)
+        .map(|text| text.chars().count() as u64)
+        .sum();
+    base + Duration::from_millis(chars.saturating_mul(TIMEOUT_ALLOWANCE_PER_CHAR_MS + delay_ms))
+}
+
+/// The command line a dry run logs instead of running
+/// Words after `--` are the text, so only their length is shown - the text
+/// itself is logged separately, under the log text policy
+fn dry_run_command(program: &str, args: &[&str]) -> String {
+    let mut words = vec![program.to_string()];
+    let mut is_text = false;
+    for arg in args {
+        if is_text {
+            words.push(format!("<text, {} chars>", arg.chars().count()));
+        } else {
+            words.push(arg.to_string());
+            is_text = *arg == "--";
+        }
+    }
+    words.join(" ")
+}
+
+/// The program and arguments that run a host binary: through
+/// flatpak-spawn --host inside Flatpak, else the binary itself
+fn host_argv<'a>(is_flatpak: bool, program: &'a str, args: &[&'a str]) -> (&'a str, Vec<&'a str>) {
+    if is_flatpak {
+        let mut host_args = vec!["--host", program];
+        host_args.extend_from_slice(args);
+        ("flatpak-spawn", host_args)
+    } else {
+        (program, args.to_vec())
+    }
+}
+
+/// Run a host binary to completion through `runner`
+async fn run_on_host(runner: &dyn CommandRunner, is_flatpak: bool, program: &str, args: &[&str], timeout: Option<Duration>) -> std::io::Result<Output> {
+    let (program, args) = host_argv(is_flatpak, program, args);
+    runner.run(program, &args, timeout).await
+}
+
+/// systemd user units ydotoold is packaged as, tried in order
+const YDOTOOLD_UNITS: [&str; 2] = ["ydotoold", "ydotool"];
+
+/// How long a freshly started ydotoold gets to create its socket
+const YDOTOOLD_START_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether ydotoold's socket exists - inside Flatpak it is checked on the
+/// host, as the sandbox can't see it
+async fn ydotoold_socket_present(runner: &dyn CommandRunner, is_flatpak: bool, timeout: Option<Duration>) -> bool {
+    if !is_flatpak {
+        return ydotool_daemon_running();
+    }
+    let socket = ydotool_socket_path();
+    let socket = socket.to_string_lossy();
+    run_on_host(runner, true, "test", &["-S", socket.as_ref()], timeout)
+        .await
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Start ydotoold if its socket is missing: through systemd first, then by
+/// running it directly. Best effort - returns whether the daemon is up
+/// afterwards and only warns when it isn't. Each host command gets `timeout`
+async fn start_ydotoold(runner: &dyn CommandRunner, is_flatpak: bool, timeout: Option<Duration>) -> bool {
+    if ydotoold_socket_present(runner, is_flatpak, timeout).await {
+        debug!("ydotoold is already running");
+        return true;
+    }
+    info!("ydotoold isn't running - starting it");
+    
+    let mut via_systemd = None;
+    for unit in YDOTOOLD_UNITS {
+        match run_on_host(runner, is_flatpak, "systemctl", &["--user", "start", unit], timeout).await {
+            Ok(output) if output.status.success() => {
+                via_systemd = Some(unit);
+                break;
+            }
+            Ok(output) => {
+                debug!("systemctl --user start {} failed: {}", unit, String::from_utf8_lossy(&output.stderr).trim());
+            }
+            Err(e) => {
+                debug!("Couldn't run systemctl: {}", e);
+            }
+        }
+    }
+    match via_systemd {
+        Some(unit) => info!("Started ydotoold with systemctl --user start {}", unit),
+        None => {
+            let (program, args) = host_argv(is_flatpak, "ydotoold", &[]);
+            match runner.spawn(program, &args) {
+                Ok(pid) => {
+                    info!("No ydotoold systemd unit - started ydotoold directly (pid {})", pid);
+                }
+                Err(e) => {
+                    warn!("Couldn't start ydotoold: {} - start it yourself: systemctl start ydotoold (or run ydotoold)", e);
+                    return false;
+                }
+            }
+        }
+    }
+    
+    let deadline = Instant::now() + YDOTOOLD_START_TIMEOUT;
+    while Instant::now() < deadline {
+        if ydotoold_socket_present(runner, is_flatpak, timeout).await {
+            info!("ydotoold is up");
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    warn!("ydotoold was started, but its socket hasn't appeared - it may need access to /dev/uinput (see Requirements)");
+    false
+}
+
+/// Whether plugin start should release stuck keys. Releasing keys that aren't
+/// down is harmless, so this runs unless switched off or ydotool is known missing
+fn should_release_stuck_keys(enabled: bool, ydotool_available: Option<bool>) -> bool {
+    enabled && ydotool_available != Some(false)
+}
+
+/// Send key-ups for the modifiers, so keys left held by a crashed run don't
+/// corrupt the first press. Best effort - failures are only logged.
+async fn release_stuck_keys(runner: &dyn CommandRunner, is_flatpak: bool, ydotool_bin: &str, timeout: Option<Duration>) {
+    let args = key_reset_args();
+    debug!("Releasing possibly stuck keys: ydotool {}", args.join(" "));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = run_on_host(runner, is_flatpak, ydotool_bin, &args, timeout).await;
+    match result {
+        Ok(output) if output.status.success() => debug!("Released stuck keys"),
+        Ok(output) => warn!("Couldn't release stuck keys: {}", String::from_utf8_lossy(&output.stderr).trim()),
+        Err(e) => warn!("Couldn't release stuck keys: {}", e),
+    }
+}
+
+/// Check that ydotool can be run, logging install hints when it can't
+async fn probe_ydotool(runner: &dyn CommandRunner, is_flatpak: bool, ydotool_bin: &str, timeout: Option<Duration>) -> bool {
+    // Test if ydotool is available (ydotool doesn't have --version, use 'help')
+    let test_cmd = run_on_host(runner, is_flatpak, ydotool_bin, &["help"], timeout).await;
+    
+    match test_cmd {
+        Ok(result) => {
+            if result.status.success() {
+                info!("ydotool is available");
+                true
+            } else {
+                let stderr = String::from_utf8_lossy(&result.stderr);
+                error!("ydotool returned error: {}", stderr);
+                false
+            }
+        }
+        Err(e) => {
+            error!("Failed to run ydotool: {}", e);
+            if is_flatpak {
+                error!("Make sure ydotool is installed on the HOST system");
+                error!("You may also need to grant Flatpak permission:");
+                error!("  {}", flatpak_override_hint("org.freedesktop.Flatpak", &host_app_id()));
+            } else {
+                error!("Install ydotool: sudo apt install ydotool");
+            }
+            false
+        }
+    }
+}
+
+/// Exit codes for fatal startup errors, so supervisors (e.g. systemd) can
+/// tell failure modes apart. A clean shutdown exits with 0.
+const EXIT_LOGGER_INIT_FAILED: i32 = 2;
+const EXIT_PLUGIN_INIT_FAILED: i32 = 3;
+const EXIT_INVALID_ARGUMENTS: i32 = 4;
+
+/// Printed for --help
+const CLI_USAGE: &str = "\
+Usage: echo-macro [OPTIONS] -port <PORT> -pluginUUID <UUID> -registerEvent <EVENT> -info <JSON>
+
+OpenDeck passes the single-dash arguments when it starts the plugin.
+Options set plugin-wide defaults and win over the matching environment variable:
+
+  --backend <ydotool|xdotool>  Typing backend                 [ECHO_MACRO_BACKEND]
+  --delay-ms <MS>              Delay between typed characters [ECHO_MACRO_DELAY_MS]
+  --log-level <LEVEL>          off, error, warn, info, debug or trace (default info)
+                                                   [ECHO_MACRO_LOG_LEVEL, RUST_LOG]
+  --default-text <TEXT>        Typed by buttons without text (default \"Hello World\")
+                                                              [ECHO_MACRO_DEFAULT_TEXT]
+  --daemon-retries <N>         Retries while ydotoold can't be reached (default 3)
+                                                              [ECHO_MACRO_DAEMON_RETRIES]
+  --command-timeout-ms <MS>    Kill host tools that run longer, 0 to wait forever
+                               (default 5000, more for long texts)
+                                                              [ECHO_MACRO_COMMAND_TIMEOUT_MS]
+  --start-daemon[=<on|off>]    Start ydotoold at startup if it isn't running (default off)
+                                                              [ECHO_MACRO_START_DAEMON]
+  --log-text-policy <POLICY>   How typed text shows in the log: masked (default),
+                               full, length_only or plain [ECHO_MACRO_LOG_TEXT_POLICY]
+  --ydotool-bin <PATH>         ydotool binary, if it isn't on PATH      [YDOTOOL_BIN]
+  --dry-run[=<on|off>]         Log commands instead of running them (default off)
+                                                              [ECHO_MACRO_DRY_RUN]
+  --use-flatpak-spawn[=<on|off>]
+                               Run host tools through flatpak-spawn --host (on) or
+                               directly (off) (default auto-detect)
+                                                              [ECHO_MACRO_USE_FLATPAK_SPAWN]
+  --help                       Print this help
+";
+
+/// The level RUST_LOG sets for this plugin: one given for this crate
+/// ("echo_macro=debug") or else a bare level ("warn")
+/// None for anything else, so other programs' filters don't stop the plugin
+fn rust_log_level(spec: &str) -> Option<String> {
+    let mut level = None;
+    for directive in spec.split(',').map(str::trim) {
+        match directive.split_once('=') {
+            Some((target, value)) if target.trim() == env!("CARGO_CRATE_NAME") && value.trim().parse::<simplelog::LevelFilter>().is_ok() => {
+                return Some(value.trim().to_string());
+            }
+            None if directive.parse::<simplelog::LevelFilter>().is_ok() => level = Some(directive.to_string()),
+            _ => {}
+        }
+    }
+    level
+}
+
+/// Options that are on when given without a value
+const CLI_SWITCHES: [&str; 3] = ["start-daemon", "dry-run", "use-flatpak-spawn"];
+
+/// Parse an on/off option value
+fn parse_switch(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Plugin-wide defaults as given by one source, unparsed
+/// Buttons' own settings (e.g. delay_ms) still win over these
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct DefaultsLayer {
+    backend: Option<String>,
+    delay_ms: Option<String>,
+    log_level: Option<String>,
+    default_text: Option<String>,
+    daemon_retries: Option<String>,
+    command_timeout_ms: Option<String>,
+    start_daemon: Option<String>,
+    ydotool_bin: Option<String>,
+    log_text_policy: Option<String>,
+    dry_run: Option<String>,
+    use_flatpak_spawn: Option<String>,
+}
+
+impl DefaultsLayer {
+    /// The layer set through ECHO_MACRO_* environment variables
+    /// RUST_LOG is the fallback for the log level, as far as it can be read
+    fn from_env(var: impl Fn(&str) -> Option<String>) -> Self {
+        DefaultsLayer {
+            backend: var("ECHO_MACRO_BACKEND"),
+            delay_ms: var("ECHO_MACRO_DELAY_MS"),
+            log_level: var("ECHO_MACRO_LOG_LEVEL").or_else(|| var("RUST_LOG").as_deref().and_then(rust_log_level)),
+            default_text: var("ECHO_MACRO_DEFAULT_TEXT"),
+            daemon_retries: var("ECHO_MACRO_DAEMON_RETRIES"),
+            command_timeout_ms: var("ECHO_MACRO_COMMAND_TIMEOUT_MS"),
+            start_daemon: var("ECHO_MACRO_START_DAEMON"),
+            ydotool_bin: var("YDOTOOL_BIN"),
+            log_text_policy: var("ECHO_MACRO_LOG_TEXT_POLICY"),
+            dry_run: var("ECHO_MACRO_DRY_RUN"),
+            use_flatpak_spawn: var("ECHO_MACRO_USE_FLATPAK_SPAWN"),
+        }
+    }
+}
+
+/// What the command line asked for
+#[derive(Debug, PartialEq, Eq)]
+enum CliCommand {
+    /// Boxed so the enum stays small as options are added
+    Run(Box<DefaultsLayer>),
+    Help,
+}
+
+/// Parse our `--flag value` / `--flag=value` options out of the arguments
+/// (without the program name). Single-dash arguments belong to OpenDeck and
+/// are skipped along with their values, so the SDK can still find them.
+fn parse_cli_args(args: &[String]) -> Result<CliCommand, String> {
+    let mut layer = DefaultsLayer::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            if arg.starts_with('-') {
+                args.next();
+            }
+            continue;
+        };
+        if flag == "help" {
+            return Ok(CliCommand::Help);
+        }
+        let (name, inline_value) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (flag, None),
+        };
+        let slot = match name {
+            "backend" => &mut layer.backend,
+            "delay-ms" => &mut layer.delay_ms,
+            "log-level" => &mut layer.log_level,
+            "default-text" => &mut layer.default_text,
+            "daemon-retries" => &mut layer.daemon_retries,
+            "command-timeout-ms" => &mut layer.command_timeout_ms,
+            "start-daemon" => &mut layer.start_daemon,
+            "dry-run" => &mut layer.dry_run,
+            "use-flatpak-spawn" => &mut layer.use_flatpak_spawn,
+            "ydotool-bin" => &mut layer.ydotool_bin,
+            "log-text-policy" => &mut layer.log_text_policy,
+            _ => return Err(format!("unknown option '--{}'", name)),
+        };
+        let value = match inline_value {
+            Some(value) => value,
+            // Switches need no value
+            None if CLI_SWITCHES.contains(&name) => "true".to_string(),
+            None => args.next().cloned().ok_or_else(|| format!("'--{}' needs a value", name))?,
+        };
+        *slot = Some(value);
+    }
+    Ok(CliCommand::Run(Box::new(layer)))
+}
+
+/// Host tools still running after this are killed, unless --command-timeout-ms says otherwise
+const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// ydotool binary run when neither YDOTOOL_BIN nor the global settings name one
+const DEFAULT_YDOTOOL_BIN: &str = "ydotool";
+
+/// How long a press waits for another button to finish typing before it
+/// fails - or longer, when the text being typed is given more time than this
+const TYPING_TURN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Typed by buttons with nothing configured, unless --default-text or the
+/// global settings say otherwise
+const DEFAULT_TEXT: &str = "Hello World";
+
+/// Plugin-wide defaults after resolving every source
+#[derive(Debug, Clone, PartialEq)]
+struct Defaults {
+    /// None auto-detects
+    backend: Option<Backend>,
+    /// Used by buttons without a delay_ms of their own
+    delay_ms: Option<u32>,
+    log_level: simplelog::LevelFilter,
+    /// Typed by buttons with nothing configured - None leaves it to the
+    /// global settings
+    default_text: Option<String>,
+    /// How often a ydotool call is retried while ydotoold can't be reached
+    daemon_retries: u32,
+    /// Host tools still running after this are killed - None waits forever
+    command_timeout: Option<Duration>,
+    /// Start ydotoold at startup when it isn't running - None leaves it to
+    /// the global settings
+    start_daemon: Option<bool>,
+    /// Path or name of the ydotool binary, for systems where it isn't on PATH
+    /// None leaves it to the global settings, then "ydotool"
+    ydotool_bin: Option<String>,
+    /// None leaves it to the global settings
+    log_text_policy: Option<LogTextPolicy>,
+    /// Log commands instead of running them, for every button
+    dry_run: bool,
+    /// Run host tools through flatpak-spawn or not - None auto-detects
+    use_flatpak_spawn: Option<bool>,
+    /// Values that were ignored for their default, logged once the logger is up
+    warnings: Vec<String>,
+}
+
+impl Default for Defaults {
+    fn default() -> Self {
+        Defaults {
+            backend: None,
+            delay_ms: None,
+            log_level: simplelog::LevelFilter::Info,
+            default_text: None,
+            daemon_retries: 3,
+            command_timeout: Some(DEFAULT_COMMAND_TIMEOUT),
+            start_daemon: None,
+            ydotool_bin: None,
+            log_text_policy: None,
+            dry_run: false,
+            use_flatpak_spawn: None,
+            warnings: Vec::new(),
+        }
+    }
+}
+
+/// Combine the sources of plugin-wide defaults: the command line wins over
+/// the environment, which wins over the built-in values. The host's global
+/// settings arrive later and slot in between the environment and the
+/// built-in values, so the fields they cover stay None here when neither
+/// layer sets them. Err names the first invalid value - except for a log
+/// level from the environment, which falls back to info with a warning
+fn resolve_defaults(cli: &DefaultsLayer, env: &DefaultsLayer) -> Result<Defaults, String> {
+    fn pick<'a>(cli: &'a Option<String>, env: &'a Option<String>) -> Option<&'a str> {
+        cli.as_deref().or(env.as_deref())
+    }
+    
+    let mut defaults = Defaults::default();
+    if let Some(name) = pick(&cli.backend, &env.backend) {
+        defaults.backend = Some(Backend::from_name(name).ok_or_else(|| format!("unknown backend '{}'", name))?);
+    }
+    if let Some(ms) = pick(&cli.delay_ms, &env.delay_ms) {
+        defaults.delay_ms = Some(ms.trim().parse().map_err(|_| format!("invalid delay '{}'", ms))?);
+    }
+    if let Some(level) = &cli.log_level {
+        defaults.log_level = level.trim().parse().map_err(|_| format!("invalid log level '{}'", level))?;
+    } else if let Some(level) = &env.log_level {
+        match level.trim().parse() {
+            Ok(level) => defaults.log_level = level,
+            Err(_) => defaults.warnings.push(format!("Invalid log level '{}' in ECHO_MACRO_LOG_LEVEL - using info", level)),
+        }
+    }
+    if let Some(text) = pick(&cli.default_text, &env.default_text) {
+        defaults.default_text = Some(text.to_string());
+    }
+    if let Some(retries) = pick(&cli.daemon_retries, &env.daemon_retries) {
+        defaults.daemon_retries = retries.trim().parse().map_err(|_| format!("invalid retry count '{}'", retries))?;
+    }
+    if let Some(ms) = pick(&cli.command_timeout_ms, &env.command_timeout_ms) {
+        let ms: u64 = ms.trim().parse().map_err(|_| format!("invalid timeout '{}'", ms))?;
+        defaults.command_timeout = (ms > 0).then(|| Duration::from_millis(ms));
+    }
+    if let Some(start) = pick(&cli.start_daemon, &env.start_daemon) {
+        defaults.start_daemon = Some(parse_switch(start).ok_or_else(|| format!("invalid on/off value '{}'", start))?);
+    }
+    if let Some(dry_run) = pick(&cli.dry_run, &env.dry_run) {
+        defaults.dry_run = parse_switch(dry_run).ok_or_else(|| format!("invalid on/off value '{}'", dry_run))?;
+    }
+    if let Some(spawn) = pick(&cli.use_flatpak_spawn, &env.use_flatpak_spawn) {
+        defaults.use_flatpak_spawn = Some(parse_switch(spawn).ok_or_else(|| format!("invalid on/off value '{}'", spawn))?);
+    }
+    if let Some(policy) = pick(&cli.log_text_policy, &env.log_text_policy) {
+        defaults.log_text_policy = Some(LogTextPolicy::from_name(policy).ok_or_else(|| format!("unknown log text policy '{}'", policy))?);
+    }
+    if let Some(bin) = pick(&cli.ydotool_bin, &env.ydotool_bin).map(str::trim).filter(|b| !b.is_empty()) {
+        defaults.ydotool_bin = Some(bin.to_string());
+    }
+    Ok(defaults)
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let defaults = match parse_cli_args(&args) {
+        Ok(CliCommand::Help) => {
+            print!("{}", CLI_USAGE);
+            return;
+        }
+        Ok(CliCommand::Run(cli)) => resolve_defaults(&cli, &DefaultsLayer::from_env(|name| env::var(name).ok())),
+        Err(e) => Err(e),
+    };
+    let defaults = match defaults {
+        Ok(defaults) => defaults,
+        Err(e) => {
+            // The log level may be what's wrong, so there's no logger yet
+            eprintln!("Echo Macro: fatal: {} (see --help)", e);
+            std::process::exit(EXIT_INVALID_ARGUMENTS);
+        }
+    };
+    
+    if let Err(e) = simplelog::SimpleLogger::init(
+        defaults.log_level,
+        simplelog::Config::default()
+    ) {
+        // No logger yet, so stderr is all we have
+        eprintln!("Echo Macro: fatal: failed to initialize logging: {}", e);
+        std::process::exit(EXIT_LOGGER_INIT_FAILED);
+    }
+    
+    info!("Echo Macro plugin starting...");
+    for warning in &defaults.warnings {
+        warn!("{}", warning);
+    }
+    
+    let action_handler = EchoMacroHandler::new(&defaults, Arc::new(SystemCommandRunner)).await;
+    let usage = action_handler.usage.clone();
+    let global_handler = EchoMacroGlobalHandler {
+        is_flatpak: action_handler.is_flatpak,
+        clipboard_tool: action_handler.clipboard_tool,
+        ydotool_status: action_handler.ydotool_status.clone(),
+        backend: action_handler.backend,
+        backend_forced: action_handler.backend_forced,
+        start_daemon: defaults.start_daemon,
+        ydotool_bin: action_handler.ydotool_bin.clone(),
+        dry_run: defaults.dry_run,
+        global_settings: action_handler.global_settings.clone(),
+        runner: action_handler.runner.clone(),
+        command_timeout: action_handler.command_timeout,
+    };
+    
+    if let Err(e) = init_plugin(global_handler, action_handler).await {
+        error!("Fatal: failed to connect to OpenDeck: {}", e);
+        std::process::exit(EXIT_PLUGIN_INIT_FAILED);
+    }
+    
+    info!("Plugin shutting down");
+    let usage = usage.lock().unwrap();
+    let mut contexts: Vec<&String> = usage.keys().collect();
+    contexts.sort();
+    for context in contexts {
+        info!("Usage for {}: {}", context, usage[context].describe());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::sync::atomic::AtomicUsize;
+    
+    /// Answers a command, given as program then arguments
+    type Respond = Box<dyn Fn(&[String]) -> std::io::Result<Output> + Send + Sync>;
+    
+    /// Records every command it is asked to run and answers with `respond`
+    struct MockRunner {
+        calls: Mutex<Vec<Vec<String>>>,
+        respond: Respond,
+        /// Never finish on their own, like a wedged tool
+        hangs: bool,
+    }
+    
+    impl MockRunner {
+        fn new(respond: impl Fn(&[String]) -> std::io::Result<Output> + Send + Sync + 'static) -> Arc<Self> {
+            Arc::new(MockRunner { calls: Mutex::new(Vec::new()), respond: Box::new(respond), hangs: false })
+        }
+        
+        /// Every command hangs until its timeout, or forever without one
+        fn hanging() -> Arc<Self> {
+            Arc::new(MockRunner { calls: Mutex::new(Vec::new()), respond: Box::new(|_| Ok(exited(0, ""))), hangs: true })
+        }
+        
+        /// What the system runner does with a command that doesn't finish
+        async fn hang(timeout: Option<Duration>) -> std::io::Error {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "killed")
+        }
+        
+        /// Every command succeeds without output
+        fn succeeding() -> Arc<Self> {
+            Self::new(|_| Ok(exited(0, "")))
+        }
+        
+        /// The commands run so far, program first
+        fn calls(&self) -> Vec<Vec<String>> {
+            self.calls.lock().unwrap().clone()
+        }
+        
+        fn clear(&self) {
+            self.calls.lock().unwrap().clear();
+        }
+        
+        fn record(&self, program: &str, args: &[&str]) -> Vec<String> {
+            let argv: Vec<String> = std::iter::once(program).chain(args.iter().copied()).map(str::to_string).collect();
+            self.calls.lock().unwrap().push(argv.clone());
+            argv
+        }
+    }
+    
+    impl CommandRunner for MockRunner {
+        fn run<'a>(&'a self, program: &'a str, args: &'a [&'a str], timeout: Option<Duration>) -> CommandFuture<'a, Output> {
+            let argv = self.record(program, args);
+            Box::pin(async move {
+                if self.hangs {
+                    return Err(Self::hang(timeout).await);
+                }
+                (self.respond)(&argv)
+            })
+        }
+        
+        fn run_with_input<'a>(&'a self, program: &'a str, args: &'a [&'a str], _input: &'a [u8], timeout: Option<Duration>) -> CommandFuture<'a, ExitStatus> {
+            let argv = self.record(program, args);
+            Box::pin(async move {
+                if self.hangs {
+                    return Err(Self::hang(timeout).await);
+                }
+                (self.respond)(&argv).map(|output| output.status)
+            })
+        }
+        
+        fn spawn(&self, program: &str, args: &[&str]) -> std::io::Result<u32> {
+            let argv = self.record(program, args);
+            (self.respond)(&argv).map(|_| 0)
+        }
+    }
+    
+    /// What a command that exited with `code` and printed `stderr` returns
+    fn exited(code: i32, stderr: &str) -> Output {
+        Output { status: ExitStatus::from_raw(code << 8), stdout: Vec::new(), stderr: stderr.as_bytes().to_vec() }
+    }
+    
+    /// A ydotool handler on `runner`, with the startup probes cleared from
+    /// its calls and ydotool taken to be installed, whatever they answered
+    async fn mock_handler(runner: &Arc<MockRunner>, is_flatpak: bool) -> EchoMacroHandler {
+        let defaults = Defaults {
+            backend: Some(Backend::Ydotool),
+            use_flatpak_spawn: Some(is_flatpak),
+            daemon_retries: 0,
+            ..Defaults::default()
+        };
+        let handler = EchoMacroHandler::new(&defaults, runner.clone()).await;
+        *handler.ydotool_status.lock().unwrap() = ToolStatus { available: Some(true), checked: Instant::now() };
+        runner.clear();
+        handler
+    }
+    
+    /// A button's settings as they'd arrive from the property inspector
+    fn button_settings(json: serde_json::Value) -> TypeTextSettings {
+        serde_json::from_value(json).unwrap()
+    }
+    
+    fn argv(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+    
+    #[test]
+    fn host_argv_wraps_in_flatpak_spawn_only_inside_flatpak() {
+        assert_eq!(host_argv(false, "ydotool", &["help"]), ("ydotool", vec!["help"]));
+        assert_eq!(host_argv(true, "ydotool", &["help"]), ("flatpak-spawn", vec!["--host", "ydotool", "help"]));
+    }
+    
+    #[tokio::test]
+    async fn typing_runs_the_backend_natively_or_through_flatpak_spawn() {
+        let settings = button_settings(serde_json::json!({}));
+        
+        let runner = MockRunner::succeeding();
+        mock_handler(&runner, false).await.type_plain("hi", &settings).await.unwrap();
+        assert_eq!(runner.calls(), [argv(&["ydotool", "type", "--", "hi"])]);
+        
+        let runner = MockRunner::succeeding();
+        mock_handler(&runner, true).await.type_plain("hi", &settings).await.unwrap();
+        assert_eq!(runner.calls(), [argv(&["flatpak-spawn", "--host", "ydotool", "type", "--", "hi"])]);
+    }
+    
+    #[tokio::test]
+    async fn text_that_looks_like_options_goes_after_the_separator() {
+        let settings = button_settings(serde_json::json!({ "delay_ms": 12 }));
+        for is_flatpak in [false, true] {
+            for text in ["--delay 500", "-foo"] {
+                let runner = MockRunner::succeeding();
+                mock_handler(&runner, is_flatpak).await.type_plain(text, &settings).await.unwrap();
+                let calls = runner.calls();
+                let call = &calls[0];
+                assert_eq!(call[call.len() - 2..], argv(&["--", text]), "{:?}", call);
+                assert_eq!(call.iter().filter(|arg| *arg == "--").count(), 1, "{:?}", call);
+            }
+        }
+        // A template without the separator gets one
+        assert_eq!(expand_arg_template("ydotool", "ydotool type {text}", "-foo").unwrap(), argv(&["type", "--", "-foo"]));
+    }
+    
+    #[test]
+    fn arg_template_puts_the_text_in_its_slot() {
+        assert_eq!(expand_arg_template("ydotool", Backend::Ydotool.default_template(), "hi there").unwrap(), argv(&["type", "--", "hi there"]));
+        assert_eq!(expand_arg_template("xdotool", Backend::Xdotool.default_template(), "{text}").unwrap(), argv(&["type", "--", "{text}"]));
+        assert_eq!(
+            expand_arg_template("xdotool", "xdotool  type --clearmodifiers --delay 20 -- {text}", "x").unwrap(),
+            argv(&["type", "--clearmodifiers", "--delay", "20", "--", "x"]),
+        );
+    }
+    
+    #[test]
+    fn arg_template_is_rejected_unless_it_runs_the_backend_with_one_text_slot() {
+        let rejected = [
+            ("ydotool", "ydotool type {txt} {text}"),
+            ("ydotool", "ydotool type --file={path} {text}"),
+            ("ydotool", "ydotool type"),
+            ("ydotool", "ydotool type --"),
+            ("ydotool", "ydotool type {text} {text}"),
+            ("ydotool", "ydotool type --text={text}"),
+            ("ydotool", "ydotool type {text} --"),
+            ("ydotool", "xdotool type {text}"),
+            ("ydotool", "sh -c {text}"),
+            ("ydotool", "/usr/bin/ydotool type {text}"),
+            ("ydotool", ""),
+            ("wtype", "wtype {text}"),
+            ("sh", "sh -c {text}"),
+        ];
+        for (backend, template) in rejected {
+            assert!(expand_arg_template(backend, template, "x").is_err(), "{} accepted {:?}", backend, template);
+        }
+    }
+    
+    #[test]
+    fn url_sources_are_limited_to_allowed_hosts() {
+        let allowlist: Vec<String> = ["localhost", "127.0.0.1", "::1"].iter().map(|h| h.to_string()).collect();
+        for url in ["http://localhost/snippet", "http://LOCALHOST:8080?id=1", "http://127.0.0.1:3000/a/b", "http://[::1]:80/"] {
+            assert!(check_url_source(url, &allowlist).is_ok(), "{} was rejected", url);
+        }
+        for url in [
+            "http://127.0.0.1.evil/",
+            "http://127.0.0.1.evil",
+            "http://localhost.evil.com/",
+            "http://evil.com/?host=localhost",
+            "http://127.0.0.2/",
+            "http://0.0.0.0/",
+            "http://localhost@evil.com/",
+            "http://evil.com@localhost/",
+            "https://localhost/",
+            "http://localhost:99999/",
+            "http://:80/",
+            "ftp://localhost/",
+        ] {
+            assert!(check_url_source(url, &allowlist).is_err(), "{} was accepted", url);
+        }
+        
+        let url = check_url_source("http://[::1]:8080?q=1", &allowlist).unwrap();
+        assert_eq!(url, HttpUrl { host: "::1".to_string(), port: 8080, authority: "[::1]:8080".to_string(), path: "/?q=1".to_string() });
+    }
+    
+    #[test]
+    fn url_source_response_must_be_a_200_within_the_size_limit() {
+        let response = |status: &str, body: &[u8]| [format!("HTTP/1.0 {}\r\nContent-Type: text/plain\r\n\r\n", status).as_bytes(), body].concat();
+        
+        let full = vec![b'a'; URL_SOURCE_MAX_BYTES];
+        assert_eq!(http_response_body(&response("200 OK", &full)).unwrap().len(), URL_SOURCE_MAX_BYTES);
+        assert!(http_response_body(&response("200 OK", &[b'a'; URL_SOURCE_MAX_BYTES + 1])).is_err());
+        assert!(http_response_body(&response("404 Not Found", b"nope")).is_err());
+        assert!(http_response_body(b"HTTP/1.0 200 OK\r\n").is_err());
+        assert!(http_response_body(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n").is_err());
+        assert_eq!(http_response_body(&response("200 OK", b"")).unwrap(), b"");
+    }
+    
+    #[test]
+    fn counters_advance_independently_within_one_text() {
+        let mut state = HashMap::new();
+        assert_eq!(expand_counters("{a}-{b}-{a}", &mut state), "1-1-1");
+        assert_eq!(expand_counters("{a}-{b}-{a}", &mut state), "2-2-2");
+        assert_eq!(expand_counters("{b}", &mut state), "3");
+        assert_eq!(state, HashMap::from([("a".to_string(), 2), ("b".to_string(), 3)]));
+        // Anything that isn't a counter placeholder stays as written
+        assert_eq!(expand_counters("{} {1x} {n:3} {n:0} {a b} }{", &mut state), "{} {1x} {n:3} {n:0} {a b} }{");
+    }
+    
+    #[test]
+    fn padded_counters_wrap_once_they_outgrow_their_width() {
+        let mut state = HashMap::new();
+        assert_eq!(expand_counters("#{n:03}", &mut state), "#001");
+        state.insert("n".to_string(), 998);
+        assert_eq!(expand_counters("#{n:03}", &mut state), "#999");
+        assert_eq!(expand_counters("#{n:03}", &mut state), "#001");
+        
+        state.insert("n".to_string(), 99);
+        assert_eq!(expand_counters("{n:02} {m}", &mut state), "01 1");
+        state.insert("m".to_string(), u64::MAX);
+        assert_eq!(expand_counters("{m}", &mut state), "1");
+        assert_eq!(replace_counters("{x:05}/{y}", |name, width| format!("{}{}", name, width)), "x5/y0");
+    }
+    
+    #[tokio::test]
+    async fn persisted_counters_pick_up_where_they_left_off() {
+        let saved = button_settings(serde_json::json!({
+            "text": "Ticket {n:04}",
+            "counter_template": true,
+            "persist_counters": true,
+            "counter_state": { "n": 41 },
+        }));
+        // What persist_counters writes back reads the same after a restart
+        let reloaded: TypeTextSettings = serde_json::from_value(serde_json::to_value(&saved).unwrap()).unwrap();
+        assert_eq!(reloaded.counter_state, HashMap::from([("n".to_string(), 41)]));
+        
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        assert!(handler.deliver("ctx", &reloaded, 1, &mut PressReport::default()).await);
+        assert_eq!(runner.calls(), [argv(&["ydotool", "type", "--", "Ticket 0042"])]);
+        assert_eq!(handler.counters.lock().unwrap()["ctx"]["n"], 42);
+    }
+    
+    #[test]
+    fn jitter_stays_within_the_percentage() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for (ms, pct) in [(40, 25), (1000, 10), (100, 100), (7, 50), (u32::MAX, 100), (100, 250)] {
+            let spread = u64::from(ms) * u64::from(pct.min(100)) / 100;
+            let (low, high) = (u64::from(ms) - spread, u64::from(ms) + spread);
+            for _ in 0..1000 {
+                let value = u64::from(jitter(ms, pct, &mut rng));
+                assert!((low..=high).contains(&value), "{} outside {}..={} for {}ms ±{}%", value, low, high, ms, pct);
+            }
+        }
+        // pct 100 can reach both ends; pct 0 never changes anything
+        let draws: HashSet<u32> = (0..2000).map(|_| jitter(2, 100, &mut rng)).collect();
+        assert_eq!(draws, HashSet::from([0, 1, 2, 3, 4]));
+        assert!((0..100).all(|_| jitter(40, 0, &mut rng) == 40));
+    }
+    
+    #[test]
+    fn jitter_is_reproducible_with_a_seed() {
+        let draw = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..20).map(|_| jitter(100, 30, &mut rng)).collect::<Vec<_>>()
+        };
+        assert_eq!(draw(42), draw(42));
+        assert_ne!(draw(42), draw(43));
+    }
+    
+    #[test]
+    fn capability_summary_per_setup() {
+        use ClipboardTool::*;
+        let vt = cfg!(feature = "vt-backend");
+        // (wayland, flatpak, ydotool, backend, daemon, clipboard, backends, the rest)
+        let cases = [
+            (true, false, Some(true), Backend::Ydotool, Some(true), Some(WlClipboard),
+                "ydotool,clipboard", "backend=ydotool ydotool=available daemon=running clipboard=wl-copy"),
+            (true, false, Some(true), Backend::Ydotool, Some(false), None,
+                "ydotool", "backend=ydotool ydotool=available daemon=not-running clipboard=none"),
+            (true, true, Some(false), Backend::Ydotool, None, None,
+                "", "backend=none ydotool=missing daemon=unknown clipboard=none"),
+            (true, true, Some(true), Backend::Ydotool, None, Some(Xclip),
+                "ydotool,clipboard", "backend=ydotool ydotool=available daemon=unknown clipboard=xclip"),
+            (false, false, None, Backend::Xdotool, Some(false), Some(Xclip),
+                "ydotool,xdotool,clipboard", "backend=xdotool ydotool=unknown daemon=not-running clipboard=xclip"),
+            (false, true, Some(false), Backend::Xdotool, None, Some(Xsel),
+                "xdotool,clipboard", "backend=xdotool ydotool=missing daemon=unknown clipboard=xsel"),
+        ];
+        for (wayland, is_flatpak, ydotool_available, backend, daemon_running, clipboard_tool, backends, rest) in cases {
+            let summary = capability_summary(&Capabilities {
+                wayland,
+                is_flatpak,
+                ydotool_available,
+                backend,
+                daemon_running,
+                clipboard_tool,
+            });
+            let backends = match (backends, vt) {
+                (_, false) => backends.to_string(),
+                ("", true) => "vt".to_string(),
+                (_, true) => format!("{},vt", backends),
+            };
+            let expected = format!(
+                "Capabilities: session={} flatpak={} backends=[{}] {} features=[{}]",
+                if wayland { "wayland" } else { "x11" },
+                if is_flatpak { "yes" } else { "no" },
+                backends,
+                rest,
+                if vt { "vt-backend" } else { "" },
+            );
+            assert_eq!(summary, expected);
+        }
+    }
+    
+    fn env_of(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<std::ffi::OsString> {
+        move |name| vars.iter().find(|(key, _)| *key == name).map(|(_, value)| value.into())
+    }
+    
+    #[test]
+    fn daemon_is_judged_by_its_socket() {
+        let vars = env_of(&[("XDG_RUNTIME_DIR", "/run/user/1000")]);
+        let socket = std::path::Path::new("/run/user/1000/.ydotool_socket");
+        assert!(ydotool_daemon_running_with(&vars, |path| path == socket));
+        assert!(!ydotool_daemon_running_with(&vars, |_| false));
+        // A socket somewhere ydotool doesn't look doesn't count
+        assert!(!ydotool_daemon_running_with(&vars, |path| path == std::path::Path::new("/tmp/.ydotool_socket")));
+    }
+    
+    #[test]
+    fn ydotool_socket_env_overrides_the_default_paths() {
+        let vars = env_of(&[("YDOTOOL_SOCKET", "/custom/ydotool.sock"), ("XDG_RUNTIME_DIR", "/run/user/1000")]);
+        assert_eq!(ydotool_socket_path_with(&vars), std::path::Path::new("/custom/ydotool.sock"));
+        assert!(ydotool_daemon_running_with(&vars, |path| path == std::path::Path::new("/custom/ydotool.sock")));
+        assert!(!ydotool_daemon_running_with(&vars, |path| path == std::path::Path::new("/run/user/1000/.ydotool_socket")));
+        
+        assert_eq!(ydotool_socket_path_with(env_of(&[])), std::path::Path::new("/tmp/.ydotool_socket"));
+    }
+    
+    fn substitutions(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(from, to)| (from.to_string(), to.to_string())).collect()
+    }
+    
+    #[test]
+    fn longest_substitution_key_wins() {
+        let map = substitutions(&[("a", "1"), ("ab", "2")]);
+        assert_eq!(apply_substitutions("abaab", &map), "212");
+        assert_eq!(apply_substitutions("ba", &map), "b1");
+    
+        let map = substitutions(&[("<", "lt"), ("<<", "shl"), ("<<<", "ushl")]);
+        assert_eq!(apply_substitutions("<<<<<", &map), "ushlshl");
+    }
+    
+    #[test]
+    fn substituted_text_is_not_scanned_again() {
+        let map = substitutions(&[("a", "b"), ("b", "c")]);
+        assert_eq!(apply_substitutions("ab", &map), "bc");
+    
+        // A replacement that contains its own key doesn't loop
+        let map = substitutions(&[("x", "xx")]);
+        assert_eq!(apply_substitutions("x-x", &map), "xx-xx");
+    
+        let map = substitutions(&[("", "never"), ("é", "e"), ("\\t", "\t")]);
+        assert_eq!(apply_substitutions("café\\tok", &map), "cafe\tok");
+    }
+    
+    #[test]
+    fn text_is_never_read_as_a_subcommand_or_option() {
+        for backend in [Backend::Ydotool, Backend::Xdotool] {
+            let custom = format!("{} type --delay 5 {{text}}", backend.name());
+            for template in [backend.default_template(), custom.as_str()] {
+                for text in ["type", "key --help", "--file /etc/passwd"] {
+                    let args = expand_arg_template(backend.name(), template, text).unwrap();
+                    assert_eq!(args[0], "type");
+                    // One argument, right after the only separator
+                    assert_eq!(args[args.len() - 2..], argv(&["--", text]), "{:?}", args);
+                    assert_eq!(args.iter().filter(|arg| *arg == "--").count(), 1, "{:?}", args);
+                }
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn startup_probes_go_through_the_runner() {
+        let runner = MockRunner::succeeding();
+        let defaults = Defaults { use_flatpak_spawn: Some(true), ..Defaults::default() };
+        EchoMacroHandler::new(&defaults, runner.clone()).await;
+        let calls = runner.calls();
+        assert!(calls.contains(&argv(&["flatpak-spawn", "--host", "ydotool", "help"])));
+        assert!(calls.iter().all(|call| call[0] == "flatpak-spawn"), "{:?}", calls);
+    }
+    
+    #[tokio::test]
+    async fn clipboard_text_goes_to_the_tool_on_stdin() {
+        let runner = MockRunner::succeeding();
+        let mut handler = mock_handler(&runner, true).await;
+        handler.clipboard_tool = Some(ClipboardTool::WlClipboard);
+        handler.copy_to_clipboard("secret").await.unwrap();
+        assert_eq!(runner.calls(), [argv(&["flatpak-spawn", "--host", "wl-copy"])]);
+    }
+    
+    #[tokio::test]
+    async fn key_hold_time_separates_key_downs_from_key_ups() {
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        let settings = button_settings(serde_json::json!({ "keys": "ctrl+shift+k", "key_hold_ms": 40, "text": "" }));
+        let started = Instant::now();
+        assert!(handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert_eq!(runner.calls(), [
+            argv(&["ydotool", "key", "29:1", "42:1", "37:1"]),
+            argv(&["ydotool", "key", "37:0", "42:0", "29:0"]),
+        ]);
+        
+        // Macro key steps and append_enter are held as well
+        runner.clear();
+        let settings = button_settings(serde_json::json!({ "steps": [{ "key": "enter" }], "key_hold_ms": 40 }));
+        assert!(handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+        assert_eq!(runner.calls(), [argv(&["ydotool", "key", "28:1"]), argv(&["ydotool", "key", "28:0"])]);
+        
+        runner.clear();
+        let settings = button_settings(serde_json::json!({ "append_enter": true, "key_hold_ms": 40 }));
+        handler.append_enter(&settings).await.unwrap();
+        assert_eq!(runner.calls(), [argv(&["ydotool", "key", "28:1"]), argv(&["ydotool", "key", "28:0"])]);
+    }
+    
+    #[tokio::test]
+    async fn multiline_text_is_typed_line_by_line_with_enter_between() {
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        let settings = button_settings(serde_json::json!({ "line_delay_ms": 30 }));
+        let started = Instant::now();
+        handler.type_with_backend("one\r\n\ntwo", &settings).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(60));
+        assert_eq!(runner.calls(), [
+            argv(&["ydotool", "type", "--", "one"]),
+            argv(&["ydotool", "key", "28:1", "28:0"]),
+            argv(&["ydotool", "key", "28:1", "28:0"]),
+            argv(&["ydotool", "type", "--", "two"]),
+        ]);
+    }
+    
+    #[test]
+    fn daemon_retries_back_off_up_to_a_second() {
+        let delays: Vec<u128> = (1..=7).map(|attempt| daemon_retry_delay(attempt).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000, 1000]);
+        assert_eq!(daemon_retry_delay(u32::MAX), Duration::from_secs(1));
+    }
+    
+    #[tokio::test]
+    async fn unreachable_daemon_is_retried_then_given_up_on() {
+        let runner = MockRunner::new(|_| Ok(exited(1, "failed to connect socket")));
+        let mut handler = mock_handler(&runner, false).await;
+        handler.daemon_retries = 2;
+        assert!(matches!(handler.run_backend(&["key", "28:1", "28:0"]).await, Err(TypeError::DaemonNotRunning)));
+        assert_eq!(runner.calls().len(), 3);
+        
+        // Other failures aren't retried
+        let runner = MockRunner::new(|_| Ok(exited(1, "Unknown command")));
+        let mut handler = mock_handler(&runner, false).await;
+        handler.daemon_retries = 2;
+        assert!(handler.run_backend(&["bogus"]).await.is_err());
+        assert_eq!(runner.calls().len(), 1);
+        
+        // The first success ends the retries
+        let presses = AtomicUsize::new(0);
+        let runner = MockRunner::new(move |argv| match argv[1].as_str() {
+            "key" if presses.fetch_add(1, Ordering::SeqCst) == 0 => Ok(exited(1, "ydotoold is not running")),
+            _ => Ok(exited(0, "")),
+        });
+        let mut handler = mock_handler(&runner, false).await;
+        handler.daemon_retries = 3;
+        handler.run_backend(&["key", "28:1", "28:0"]).await.unwrap();
+        assert_eq!(runner.calls().len(), 2);
+    }
+    
+    #[tokio::test]
+    async fn chord_release_sets_the_order_of_the_key_ups() {
+        for (release, ups) in [("reverse", ["37:0", "42:0", "29:0"]), ("in_order", ["29:0", "42:0", "37:0"])] {
+            let runner = MockRunner::succeeding();
+            let handler = mock_handler(&runner, false).await;
+            let settings = button_settings(serde_json::json!({ "keys": "ctrl+shift+k", "chord_release": release, "text": "" }));
+            assert!(handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+            let mut expected = argv(&["ydotool", "key", "29:1", "42:1", "37:1"]);
+            expected.extend(argv(&ups));
+            assert_eq!(runner.calls(), [expected]);
+        }
+    }
+    
+    #[test]
+    fn gtk_unicode_input_types_the_code_point_in_hex() {
+        let ctrl_shift_u = vec![(29, true), (42, true), (22, true), (22, false), (42, false), (29, false)];
+        let space = vec![(57, true), (57, false)];
+        for (c, hex) in [('é', "e9"), ('ñ', "f1"), ('€', "20ac"), ('😀', "1f600")] {
+            assert_eq!(gtk_unicode_sequence(c), (ctrl_shift_u.clone(), hex.to_string(), space.clone()));
+        }
+    }
+    
+    #[tokio::test]
+    async fn gtk_unicode_input_only_covers_what_ydotool_cant_type() {
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        let settings = button_settings(serde_json::json!({ "gtk_unicode_input": true }));
+        handler.type_line("a😀b", &settings).await.unwrap();
+        assert_eq!(runner.calls(), [
+            argv(&["ydotool", "type", "--", "a"]),
+            argv(&["ydotool", "key", "29:1", "42:1", "22:1", "22:0", "42:0", "29:0"]),
+            argv(&["ydotool", "type", "--", "1f600"]),
+            argv(&["ydotool", "key", "57:1", "57:0"]),
+            argv(&["ydotool", "type", "--", "b"]),
+        ]);
+    }
+    
+    /// How a "type" button delivered its text, told from the commands it ran
+    fn delivery_path(calls: &[Vec<String>]) -> &'static str {
+        if calls.iter().any(|call| call.contains(&"wl-copy".to_string())) {
+            "paste"
+        } else if calls.iter().any(|call| call[1..] == argv(&["key", "29:1", "42:1", "22:1", "22:0", "42:0", "29:0"])) {
+            "gtk"
+        } else {
+            "type"
+        }
+    }
+    
+    #[tokio::test]
+    async fn unicode_strategy_picks_type_paste_or_gtk_input() {
+        let cases = [
+            // (text, unicode_strategy, gtk_unicode_input, clipboard tool found, path)
+            ("plain ascii", "paste", false, true, "type"),
+            ("plain ascii", "auto", true, true, "type"),
+            ("é", "type", false, true, "type"),
+            ("é", "paste", false, true, "paste"),
+            ("é", "auto", false, true, "paste"),
+            ("é", "auto", false, false, "type"),
+            ("ñ", "auto", true, true, "gtk"),
+            ("ñ", "type", true, true, "gtk"),
+            ("ñ", "paste", false, true, "paste"),
+            ("😀", "auto", false, true, "paste"),
+            ("😀", "auto", true, true, "gtk"),
+            ("😀", "type", false, true, "type"),
+        ];
+        for (text, strategy, gtk, clipboard, path) in cases {
+            let runner = MockRunner::succeeding();
+            let mut handler = mock_handler(&runner, false).await;
+            handler.clipboard_tool = clipboard.then_some(ClipboardTool::WlClipboard);
+            let settings = button_settings(serde_json::json!({ "text": text, "unicode_strategy": strategy, "gtk_unicode_input": gtk }));
+            assert!(handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+            assert_eq!(delivery_path(&runner.calls()), path, "{:?} with {} (gtk {}, clipboard {})", text, strategy, gtk, clipboard);
+        }
+    }
+    
+    #[tokio::test]
+    async fn without_a_hold_time_a_chord_is_one_backend_call() {
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        handler.press_chord(&[29, 37], ChordRelease::Reverse, 0).await.unwrap();
+        assert_eq!(runner.calls(), [argv(&["ydotool", "key", "29:1", "37:1", "37:0", "29:0"])]);
+    }
+    
+    #[tokio::test]
+    async fn unreachable_daemon_is_told_apart_from_other_failures() {
+        let runner = MockRunner::new(|_| Ok(exited(2, "failed to connect socket `/run/user/1000/.ydotool_socket'")));
+        let handler = mock_handler(&runner, false).await;
+        assert!(matches!(handler.run_backend(&["key", "28:1", "28:0"]).await, Err(TypeError::DaemonNotRunning)));
+        
+        let runner = MockRunner::new(|_| Ok(exited(1, "Usage: ydotool <cmd> <args>")));
+        let handler = mock_handler(&runner, false).await;
+        assert!(matches!(
+            handler.run_backend(&["bogus"]).await,
+            Err(TypeError::CommandFailed { backend: Backend::Ydotool, code: Some(1), .. })
+        ));
+    }
+    
+    #[tokio::test]
+    async fn missing_host_tool_is_recognized_under_flatpak_spawn() {
+        let missing = [
+            (127, ""),
+            (127, "bash: line 1: ydotool: command not found"),
+            (1, "ydotool: Command not found"),
+            (255, "flatpak-spawn: execv ydotool: No such file or directory"),
+        ];
+        for (code, stderr) in missing {
+            let runner = MockRunner::new(move |_| Ok(exited(code, stderr)));
+            let handler = mock_handler(&runner, true).await;
+            let result = handler.run_backend(&["key", "28:1", "28:0"]).await;
+            assert!(matches!(result, Err(TypeError::HostToolMissing("ydotool"))), "{} {:?}: {:?}", code, stderr, result);
+            assert_eq!(runner.calls(), [argv(&["flatpak-spawn", "--host", "ydotool", "key", "28:1", "28:0"])]);
+        }
+        
+        // Natively the shell isn't in between, so a 127 is ydotool's own
+        let runner = MockRunner::new(|_| Ok(exited(127, "")));
+        let handler = mock_handler(&runner, false).await;
+        assert!(matches!(
+            handler.run_backend(&["key", "28:1", "28:0"]).await,
+            Err(TypeError::CommandFailed { backend: Backend::Ydotool, code: Some(127), .. })
+        ));
+        
+        assert!(matches!(
+            TypeError::from_output(Backend::Xdotool, true, Some(127), ""),
+            TypeError::HostToolMissing("xdotool")
+        ));
+        assert!(matches!(
+            TypeError::from_output(Backend::Ydotool, true, Some(1), "flatpak-spawn: Portal call failed"),
+            TypeError::FlatpakPermission
+        ));
+    }
+    
+    #[tokio::test]
+    async fn clipboard_tool_is_probed_in_order_for_the_session() {
+        // (wayland, installed, probed in order, chosen)
+        let cases = [
+            (true, "wl-copy xclip xsel", "wl-copy", Some(ClipboardTool::WlClipboard)),
+            (true, "xclip xsel", "wl-copy xclip", Some(ClipboardTool::Xclip)),
+            (true, "xsel", "wl-copy xclip xsel", Some(ClipboardTool::Xsel)),
+            (false, "wl-copy xclip xsel", "xclip", Some(ClipboardTool::Xclip)),
+            (false, "wl-copy xsel", "xclip xsel", Some(ClipboardTool::Xsel)),
+            (false, "wl-copy", "xclip xsel", None),
+        ];
+        for is_flatpak in [false, true] {
+            for (wayland, installed, probed, chosen) in cases {
+                let runner = MockRunner::new(move |argv| {
+                    let tool = if argv[0] == "flatpak-spawn" { &argv[2] } else { &argv[0] };
+                    Ok(exited(if installed.split(' ').any(|name| name == tool) { 0 } else { 127 }, ""))
+                });
+                let handler = mock_handler(&runner, is_flatpak).await;
+                assert_eq!(handler.probe_clipboard_tool(wayland).await, chosen, "{:?} wayland={}", installed, wayland);
+                
+                let expected: Vec<Vec<String>> = probed
+                    .split(' ')
+                    .map(|name| {
+                        let (program, args) = ClipboardTool::from_name(name).unwrap().probe_command();
+                        let (program, args) = host_argv(is_flatpak, program, args);
+                        std::iter::once(program).chain(args).map(str::to_string).collect()
+                    })
+                    .collect();
+                assert_eq!(runner.calls(), expected);
+            }
+        }
+    }
+    
+    #[test]
+    fn home_prefixes_are_expanded() {
+        let home = Some(std::ffi::OsStr::new("/home/me"));
+        for path in ["~/notes/a.txt", "$HOME/notes/a.txt", "${HOME}/notes/a.txt"] {
+            assert_eq!(expand_home_in(path, home), std::path::Path::new("/home/me/notes/a.txt"), "{}", path);
+        }
+        for path in ["/etc/motd", "notes/a.txt", "~other/a.txt", "a/~/b", "$HOMEDIR/a", "~"] {
+            assert_eq!(expand_home_in(path, home), std::path::Path::new(path), "{}", path);
+        }
+        assert_eq!(expand_home_in("~/a.txt", None), std::path::Path::new("~/a.txt"));
+    }
+    
+    #[tokio::test]
+    async fn file_source_errors_carry_the_path() {
+        let dir = env::temp_dir().join(format!("echo-macro-test-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str, bytes: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, bytes).unwrap();
+            path
+        };
+        let signature = file("signature.txt", b"Regards,\nMe\r\n");
+        let binary = file("binary.bin", &[0xff, 0xfe, 0x00]);
+        let large = file("large.txt", &vec![b'a'; FILE_SOURCE_MAX_BYTES as usize + 1]);
+        let missing = dir.join("missing.txt");
+        
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        assert_eq!(handler.read_text_file(signature.to_str().unwrap()).await.unwrap(), "Regards,\nMe");
+        assert_eq!(handler.read_text_file(missing.to_str().unwrap()).await, Err(FileReadError::NotFound(missing.clone())));
+        assert!(matches!(handler.read_text_file(binary.to_str().unwrap()).await, Err(FileReadError::Other(path, _)) if path == binary));
+        assert!(matches!(handler.read_text_file(large.to_str().unwrap()).await, Err(FileReadError::Other(path, _)) if path == large));
+        // Natively there's no host to fall back to
+        assert!(runner.calls().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+        
+        // Inside Flatpak a file the sandbox can't see is read on the host
+        let outside = "/run/host/only/notes.txt";
+        let runner = MockRunner::new(|argv| {
+            let path = argv.last().unwrap();
+            Ok(match path.as_str() {
+                "/run/host/only/notes.txt" => Output { stdout: b"from the host\n".to_vec(), ..exited(0, "") },
+                "/run/host/only/locked.txt" => exited(1, &format!("cat: {}: Permission denied", path)),
+                _ => exited(1, &format!("cat: {}: No such file or directory", path)),
+            })
+        });
+        let handler = mock_handler(&runner, true).await;
+        assert_eq!(handler.read_text_file(outside).await.unwrap(), "from the host");
+        assert_eq!(runner.calls(), [argv(&["flatpak-spawn", "--host", "cat", "--", outside])]);
+        assert_eq!(
+            handler.read_text_file("/run/host/only/locked.txt").await,
+            Err(FileReadError::PermissionDenied("/run/host/only/locked.txt".into()))
+        );
+        assert_eq!(
+            handler.read_text_file("/run/host/only/gone.txt").await,
+            Err(FileReadError::NotFound("/run/host/only/gone.txt".into()))
+        );
+    }
+    
+    #[test]
+    fn file_path_replaces_the_inline_text() {
+        let settings = button_settings(serde_json::json!({ "text": "", "file_path": "~/signature.txt" }));
+        assert!(settings.has_text());
+        assert_eq!(settings.file_path(), Some("~/signature.txt"));
+        assert!(settings.for_long_press().file_path().is_none());
+        assert_eq!(button_settings(serde_json::json!({ "file_path": " " })).file_path(), None);
+    }
+    
+    /// A runner for wl-clipboard whose clipboard holds `previous` text, or
+    /// nothing when None
+    fn clipboard_runner(previous: Option<&'static str>) -> Arc<MockRunner> {
+        MockRunner::new(move |argv| {
+            let read = |stdout: &str| Ok(Output { stdout: stdout.as_bytes().to_vec(), ..exited(0, "") });
+            match (argv[0].as_str(), previous) {
+                ("wl-paste", None) => Ok(exited(1, "Nothing is copied")),
+                ("wl-paste", Some(_)) if argv[1] == "--list-types" => read("text/plain;charset=utf-8\ntext/plain\n"),
+                ("wl-paste", Some(text)) => read(text),
+                _ => Ok(exited(0, "")),
+            }
+        })
+    }
+    
+    #[tokio::test]
+    async fn paste_restores_the_clipboard_before_returning() {
+        let settings = button_settings(serde_json::json!({ "method": "paste" }));
+        let ctrl_v = argv(&["ydotool", "key", "29:1", "47:1", "47:0", "29:0"]);
+        
+        let runner = clipboard_runner(Some("copied earlier"));
+        let mut handler = mock_handler(&runner, false).await;
+        handler.clipboard_tool = Some(ClipboardTool::WlClipboard);
+        let started = Instant::now();
+        handler.paste_text("hunter2", &settings).await.unwrap();
+        assert!(started.elapsed() >= PASTE_RESTORE_DELAY);
+        let calls = runner.calls();
+        let after_paste = &calls[calls.iter().position(|call| *call == ctrl_v).unwrap() + 1..];
+        assert_eq!(after_paste, [argv(&["wl-copy"])]);
+        
+        // Nothing to put back: the pasted text is cleared rather than left behind
+        let runner = clipboard_runner(None);
+        let mut handler = mock_handler(&runner, false).await;
+        handler.clipboard_tool = Some(ClipboardTool::WlClipboard);
+        handler.paste_text("hunter2", &settings).await.unwrap();
+        let calls = runner.calls();
+        let after_paste = &calls[calls.iter().position(|call| *call == ctrl_v).unwrap() + 1..];
+        assert_eq!(after_paste, [argv(&["wl-copy", "--clear"])]);
+    }
+    
+    #[tokio::test]
+    async fn next_press_waits_for_the_clipboard_restore() {
+        let runner = clipboard_runner(Some("copied earlier"));
+        let mut handler = mock_handler(&runner, false).await;
+        handler.clipboard_tool = Some(ClipboardTool::WlClipboard);
+        let paste = button_settings(serde_json::json!({ "method": "paste", "text": "hunter2" }));
+        let typed = button_settings(serde_json::json!({ "text": "next" }));
+        
+        let (pasted, typed) = tokio::join!(handler.type_text("paste", &paste, 1), handler.type_text("type", &typed, 1));
+        assert!(pasted && typed);
+        let calls = runner.calls();
+        let restore = calls.iter().rposition(|call| *call == argv(&["wl-copy"])).unwrap();
+        let next = calls.iter().position(|call| call[0] == "ydotool" && call[1] == "type").unwrap();
+        assert!(restore < next, "{:?}", calls);
+    }
+    
+    /// One plugin-wide default: where it goes in a layer, a command line and
+    /// an environment value, and what the field reads as for each of them
+    /// and for the built-in value
+    struct DefaultCase {
+        slot: fn(&mut DefaultsLayer) -> &mut Option<String>,
+        cli: &'static str,
+        env: &'static str,
+        read: fn(&Defaults) -> String,
+        from_cli: &'static str,
+        from_env: &'static str,
+        built_in: &'static str,
+    }
+    
+    #[test]
+    fn command_line_wins_over_environment_over_built_in() {
+        let cases = [
+            DefaultCase { slot: |l| &mut l.backend, cli: "xdotool", env: "ydotool", read: |d| format!("{:?}", d.backend), from_cli: "Some(Xdotool)", from_env: "Some(Ydotool)", built_in: "None" },
+            DefaultCase { slot: |l| &mut l.delay_ms, cli: "5", env: "7", read: |d| format!("{:?}", d.delay_ms), from_cli: "Some(5)", from_env: "Some(7)", built_in: "None" },
+            DefaultCase { slot: |l| &mut l.log_level, cli: "debug", env: "warn", read: |d| format!("{:?}", d.log_level), from_cli: "Debug", from_env: "Warn", built_in: "Info" },
+            DefaultCase { slot: |l| &mut l.default_text, cli: "cli", env: "env", read: |d| format!("{:?}", d.default_text), from_cli: "Some(\"cli\")", from_env: "Some(\"env\")", built_in: "None" },
+            DefaultCase { slot: |l| &mut l.daemon_retries, cli: "1", env: "2", read: |d| format!("{:?}", d.daemon_retries), from_cli: "1", from_env: "2", built_in: "3" },
+            DefaultCase { slot: |l| &mut l.command_timeout_ms, cli: "0", env: "250", read: |d| format!("{:?}", d.command_timeout), from_cli: "None", from_env: "Some(250ms)", built_in: "Some(5s)" },
+            DefaultCase { slot: |l| &mut l.start_daemon, cli: "on", env: "off", read: |d| format!("{:?}", d.start_daemon), from_cli: "Some(true)", from_env: "Some(false)", built_in: "None" },
+            DefaultCase { slot: |l| &mut l.ydotool_bin, cli: "/opt/ydotool", env: "/usr/local/bin/ydotool", read: |d| format!("{:?}", d.ydotool_bin), from_cli: "Some(\"/opt/ydotool\")", from_env: "Some(\"/usr/local/bin/ydotool\")", built_in: "None" },
+            DefaultCase { slot: |l| &mut l.log_text_policy, cli: "full", env: "plain", read: |d| format!("{:?}", d.log_text_policy), from_cli: "Some(Full)", from_env: "Some(Plain)", built_in: "None" },
+            DefaultCase { slot: |l| &mut l.dry_run, cli: "yes", env: "no", read: |d| format!("{:?}", d.dry_run), from_cli: "true", from_env: "false", built_in: "false" },
+            DefaultCase { slot: |l| &mut l.use_flatpak_spawn, cli: "off", env: "on", read: |d| format!("{:?}", d.use_flatpak_spawn), from_cli: "Some(false)", from_env: "Some(true)", built_in: "None" },
+        ];
+        for case in cases {
+            let mut cli = DefaultsLayer::default();
+            *(case.slot)(&mut cli) = Some(case.cli.to_string());
+            let mut env = DefaultsLayer::default();
+            *(case.slot)(&mut env) = Some(case.env.to_string());
+            let none = DefaultsLayer::default();
+            
+            assert_eq!((case.read)(&resolve_defaults(&cli, &env).unwrap()), case.from_cli);
+            assert_eq!((case.read)(&resolve_defaults(&none, &env).unwrap()), case.from_env);
+            assert_eq!((case.read)(&resolve_defaults(&none, &none).unwrap()), case.built_in);
+        }
+    }
+    
+    #[tokio::test]
+    async fn global_settings_sit_between_environment_and_built_in() {
+        let runner = MockRunner::succeeding();
+        let mut handler = mock_handler(&runner, false).await;
+        assert_eq!(handler.default_text(), DEFAULT_TEXT);
+        assert_eq!(handler.log_text_policy(), LogTextPolicy::Masked);
+        
+        *handler.global_settings.write().unwrap() = GlobalSettings {
+            delay_ms: Some(9),
+            default_text: Some("global".to_string()),
+            log_text_policy: Some(LogTextPolicy::LengthOnly),
+            ..GlobalSettings::default()
+        };
+        assert_eq!(handler.default_text(), "global");
+        assert_eq!(handler.log_text_policy(), LogTextPolicy::LengthOnly);
+        handler.type_plain("x", &button_settings(serde_json::json!({}))).await.unwrap();
+        
+        handler.default_text = Some("env".to_string());
+        handler.log_text_policy = Some(LogTextPolicy::Full);
+        handler.default_delay_ms = Some(3);
+        assert_eq!(handler.default_text(), "env");
+        assert_eq!(handler.log_text_policy(), LogTextPolicy::Full);
+        handler.type_plain("x", &button_settings(serde_json::json!({}))).await.unwrap();
+        // A button's own delay wins over every plugin-wide one
+        handler.type_plain("x", &button_settings(serde_json::json!({ "delay_ms": 1 }))).await.unwrap();
+        
+        let delays: Vec<String> = runner.calls().iter().map(|call| call[3].clone()).collect();
+        assert_eq!(delays, ["9", "3", "1"]);
+    }
+    
+    #[test]
+    fn unknown_and_malformed_options_are_rejected() {
+        let args = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        for bad in [&["--bogus"][..], &["--delay-ms"], &["--backend"], &["-port", "1", "--nope=1"]] {
+            assert!(parse_cli_args(&args(bad)).is_err(), "{:?}", bad);
+        }
+        for (flag, value) in [("--backend", "wtype"), ("--delay-ms", "fast"), ("--log-level", "loud"), ("--daemon-retries", "-1"),
+                              ("--command-timeout-ms", "1.5"), ("--start-daemon", "maybe"), ("--log-text-policy", "secret"),
+                              ("--dry-run", "2"), ("--use-flatpak-spawn", "auto")] {
+            let mut forms = vec![vec![format!("{}={}", flag, value)]];
+            // A switch given without `=` takes no value
+            if !CLI_SWITCHES.contains(&&flag[2..]) {
+                forms.push(vec![flag.to_string(), value.to_string()]);
+            }
+            for words in forms {
+                let Ok(CliCommand::Run(cli)) = parse_cli_args(&words) else {
+                    panic!("{:?} should parse", words);
+                };
+                assert!(resolve_defaults(&cli, &DefaultsLayer::default()).is_err(), "{:?}", words);
+            }
+        }
+        
+        // OpenDeck's own single-dash arguments are skipped with their values
+        let Ok(CliCommand::Run(cli)) = parse_cli_args(&args(&["-port", "--dry-run", "--start-daemon", "-info", "{}"])) else {
+            panic!("should parse");
+        };
+        assert_eq!(cli.dry_run, None);
+        assert_eq!(cli.start_daemon.as_deref(), Some("true"));
+        assert_eq!(parse_cli_args(&args(&["--help"])), Ok(CliCommand::Help));
+    }
+    
+    #[test]
+    fn invalid_log_level_is_only_fatal_on_the_command_line() {
+        let bad = DefaultsLayer { log_level: Some("loud".to_string()), ..DefaultsLayer::default() };
+        assert!(resolve_defaults(&bad, &DefaultsLayer::default()).is_err());
+        
+        let defaults = resolve_defaults(&DefaultsLayer::default(), &bad).unwrap();
+        assert_eq!(defaults.log_level, simplelog::LevelFilter::Info);
+        assert_eq!(defaults.warnings.len(), 1);
+        
+        let good = DefaultsLayer { log_level: Some("debug".to_string()), ..DefaultsLayer::default() };
+        let defaults = resolve_defaults(&good, &bad).unwrap();
+        assert_eq!(defaults.log_level, simplelog::LevelFilter::Debug);
+        assert!(defaults.warnings.is_empty());
+    }
+    
+    #[tokio::test]
+    async fn system_runner_kills_a_child_that_runs_too_long() {
+        let pid_file = env::temp_dir().join(format!("echo-macro-test-{}.pid", std::process::id()));
+        let script = format!("echo $$ > {}; exec sleep 30", pid_file.display());
+        let started = Instant::now();
+        let result = SystemCommandRunner.run("sh", &["-c", &script], Some(Duration::from_millis(500))).await;
+        
+        let e = result.expect_err("the child should have timed out");
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let pid = std::fs::read_to_string(&pid_file).expect("the child wrote its pid");
+        let _ = std::fs::remove_file(&pid_file);
+        // Killed and reaped - not even a zombie is left
+        assert!(!std::path::Path::new(&format!("/proc/{}", pid.trim())).exists());
+    }
+    
+    #[tokio::test]
+    async fn system_runner_collects_output_within_the_timeout() {
+        let output = SystemCommandRunner
+            .run("sh", &["-c", "echo out; echo err >&2; exit 3"], Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+    }
+    
+    #[tokio::test]
+    async fn a_button_saved_without_text_types_the_default() {
+        // What the property inspector sends when only the method was changed
+        let settings = button_settings(serde_json::json!({ "method": "type" }));
+        assert_eq!(settings.problem(), None);
+        assert_eq!(settings.effective_text(DEFAULT_TEXT), Some(DEFAULT_TEXT));
+        
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        assert!(handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+        assert_eq!(runner.calls(), [argv(&["ydotool", "type", "--", DEFAULT_TEXT])]);
+        
+        // Cleared in the text box, it is a mistake worth flagging
+        assert!(button_settings(serde_json::json!({ "method": "type", "text": "" })).problem().is_some());
+    }
+    
+    #[tokio::test]
+    async fn a_hanging_tool_times_out_instead_of_blocking() {
+        let limit = Duration::from_millis(100);
+        let hanging = MockRunner::hanging();
+        let mut handler = mock_handler(&MockRunner::succeeding(), false).await;
+        handler.runner = hanging.clone();
+        handler.command_timeout = Some(limit);
+        handler.clipboard_tool = Some(ClipboardTool::WlClipboard);
+        
+        let result = handler.run_backend(&["key", "28:1", "28:0"]).await;
+        assert!(matches!(result, Err(TypeError::Timeout("ydotool", _))), "{:?}", result);
+        let result = handler.copy_to_clipboard("text").await;
+        assert!(matches!(result, Err(TypeError::Timeout("wl-copy", _))), "{:?}", result);
+        
+        // Startup commands are bounded too, so plugin_ready can't hang
+        let startup = async {
+            assert!(!probe_ydotool(&*hanging, true, "ydotool", Some(limit)).await);
+            release_stuck_keys(&*hanging, true, "ydotool", Some(limit)).await;
+            assert!(!ydotoold_socket_present(&*hanging, true, Some(limit)).await);
+        };
+        tokio::time::timeout(Duration::from_secs(5), startup).await.expect("startup commands hung");
+    }
+    
+    #[tokio::test]
+    async fn system_runner_kills_a_stdin_reader_that_runs_too_long() {
+        let started = Instant::now();
+        let result = SystemCommandRunner.run_with_input("sleep", &["30"], b"text", Some(Duration::from_millis(200))).await;
+        assert_eq!(result.expect_err("sleep should have timed out").kind(), std::io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        
+        let status = SystemCommandRunner.run_with_input("cat", &[], b"text", Some(Duration::from_secs(5))).await.unwrap();
+        assert!(status.success());
+    }
+    
+    /// A runner whose clipboard tool fails and everything else succeeds
+    fn failing_clipboard_runner() -> Arc<MockRunner> {
+        MockRunner::new(|argv| Ok(if argv[0].starts_with("wl-") { exited(1, "") } else { exited(0, "") }))
+    }
+    
+    #[tokio::test]
+    async fn failing_clipboard_alerts_or_falls_back_to_typing() {
+        let typed = argv(&["ydotool", "type", "--", "note"]);
+        for method in ["clipboard_only", "paste"] {
+            let runner = failing_clipboard_runner();
+            let mut handler = mock_handler(&runner, false).await;
+            handler.clipboard_tool = Some(ClipboardTool::WlClipboard);
+            let settings = button_settings(serde_json::json!({ "method": method, "text": "note" }));
+            assert_eq!(settings.on_clipboard_failure, ClipboardFailurePolicy::Alert);
+            assert!(!handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await, "{}", method);
+            assert!(!runner.calls().contains(&typed), "{}", method);
+            
+            let runner = failing_clipboard_runner();
+            let mut handler = mock_handler(&runner, false).await;
+            handler.clipboard_tool = Some(ClipboardTool::WlClipboard);
+            let settings = button_settings(serde_json::json!({ "method": method, "text": "note", "on_clipboard_failure": "fallback_to_type" }));
+            assert!(handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await, "{}", method);
+            assert!(runner.calls().contains(&typed), "{}", method);
+        }
+        
+        let runner = failing_clipboard_runner();
+        let mut handler = mock_handler(&runner, false).await;
+        handler.clipboard_tool = Some(ClipboardTool::WlClipboard);
+        let settings = button_settings(serde_json::json!({ "method": "paste", "text": "note" }));
+        assert!(matches!(handler.paste_text("note", &settings).await, Err(TypeError::ClipboardFailed)));
+    }
+    
+    #[tokio::test]
+    async fn clipboard_fallback_moves_the_pointer_like_typing_does() {
+        let runner = failing_clipboard_runner();
+        let mut handler = mock_handler(&runner, false).await;
+        handler.clipboard_tool = Some(ClipboardTool::WlClipboard);
+        let settings = button_settings(serde_json::json!({
+            "method": "clipboard_only",
+            "text": "note",
+            "on_clipboard_failure": "fallback_to_type",
+            "pre_type_mouse_move": [10, 20],
+        }));
+        assert!(handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+        let calls = runner.calls();
+        let moved = calls.iter().position(|call| *call == argv(&["ydotool", "mousemove", "--absolute", "-x", "10", "-y", "20"]));
+        let typed = calls.iter().position(|call| *call == argv(&["ydotool", "type", "--", "note"]));
+        assert!(moved.is_some() && moved < typed, "{:?}", calls);
+    }
+    
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn fifo_messages_survive_a_timeout_mid_line() {
+        use std::io::Write;
+        let path = env::temp_dir().join(format!("echo-macro-test-{}.fifo", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert!(Command::new("mkfifo").arg(&path).status().unwrap().success());
+        let path_str = path.to_str().unwrap();
+        let mut receiver = open_fifo(path_str).unwrap();
+        let mut writer = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let mut partial = Vec::new();
+        let timeout = Duration::from_millis(200);
+        
+        writer.write_all(b"first\nsecond\r\n").unwrap();
+        assert_eq!(read_fifo_message(&mut receiver, &mut partial, path_str, timeout).await.unwrap(), Some("first".to_string()));
+        assert_eq!(read_fifo_message(&mut receiver, &mut partial, path_str, timeout).await.unwrap(), Some("second".to_string()));
+        
+        assert_eq!(read_fifo_message(&mut receiver, &mut partial, path_str, timeout).await.unwrap(), None);
+        assert!(partial.is_empty());
+        
+        writer.write_all(b"spl").unwrap();
+        assert_eq!(read_fifo_message(&mut receiver, &mut partial, path_str, timeout).await.unwrap(), None);
+        assert_eq!(partial, b"spl");
+        writer.write_all(b"it\n").unwrap();
+        assert_eq!(read_fifo_message(&mut receiver, &mut partial, path_str, timeout).await.unwrap(), Some("split".to_string()));
+        assert!(partial.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+    
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn fifo_is_closed_once_no_button_reads_it() {
+        use std::io::Write;
+        let path = env::temp_dir().join(format!("echo-macro-test-{}-shared.fifo", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert!(Command::new("mkfifo").arg(&path).status().unwrap().success());
+        let path_str = path.to_str().unwrap();
+        let handler = mock_handler(&MockRunner::succeeding(), false).await;
+        
+        // Opening for writing waits until the plugin opens the FIFO
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || std::fs::OpenOptions::new().write(true).open(path).unwrap().write_all(b"one\ntwo\n").unwrap())
+        };
+        assert_eq!(handler.read_fifo("a", path_str).await.unwrap(), Some("one".to_string()));
+        assert_eq!(handler.read_fifo("b", path_str).await.unwrap(), Some("two".to_string()));
+        writer.join().unwrap();
+        
+        handler.release_fifos("a").await;
+        assert!(handler.fifos.lock().await.contains_key(path_str));
+        handler.release_fifos("b").await;
+        assert!(handler.fifos.lock().await.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+    
+    #[test]
+    fn unreadable_global_settings_keep_the_previous_ones() {
+        let store = RwLock::new(GlobalSettings::default());
+        store_global_settings(&store, serde_json::json!({ "dry_run": true, "use_flatpak_spawn": false, "delay_ms": 7 })).unwrap();
+        let stored = store.read().unwrap().clone();
+        assert!(stored.dry_run);
+        
+        for bad in [
+            serde_json::json!({ "dry_run": "yes" }),
+            serde_json::json!({ "dry_run": true, "delay_ms": -1 }),
+            serde_json::json!({ "log_text_policy": "everything" }),
+            serde_json::json!("not an object"),
+        ] {
+            assert!(store_global_settings(&store, bad.clone()).is_err(), "{}", bad);
+            assert_eq!(*store.read().unwrap(), stored, "{}", bad);
+        }
+        
+        store_global_settings(&store, serde_json::json!({})).unwrap();
+        assert_eq!(*store.read().unwrap(), GlobalSettings::default());
+    }
+    
+    #[tokio::test]
+    async fn global_backend_and_ydotool_bin_apply_unless_forced() {
+        let runner = MockRunner::succeeding();
+        let mut handler = mock_handler(&runner, false).await;
+        let settings = button_settings(serde_json::json!({ "text": "hi" }));
+        *handler.global_settings.write().unwrap() = serde_json::from_value(serde_json::json!({ "ydotool_bin": "/opt/ydotool" })).unwrap();
+        
+        // Forced by the command line or environment, as mock_handler's are
+        handler.ydotool_bin_forced = true;
+        assert!(handler.type_text("ctx", &settings, 1).await);
+        handler.ydotool_bin_forced = false;
+        assert!(handler.type_text("ctx", &settings, 1).await);
+        assert_eq!(runner.calls(), [argv(&["ydotool", "type", "--", "hi"]), argv(&["/opt/ydotool", "type", "--", "hi"])]);
+        
+        runner.clear();
+        *handler.global_settings.write().unwrap() = serde_json::from_value(serde_json::json!({ "backend": "xdotool" })).unwrap();
+        assert!(handler.type_text("ctx", &settings, 1).await);
+        handler.backend_forced = false;
+        assert!(handler.type_text("ctx", &settings, 1).await);
+        let typed: Vec<String> = runner.calls().iter().filter(|call| call[1] == "type").map(|call| call[0].clone()).collect();
+        assert_eq!(typed, ["ydotool", "xdotool"]);
+    }
+    
+    /// The global handler for a Flatpak host where ydotoold isn't running
+    fn daemon_global_handler(runner: &Arc<MockRunner>, start_daemon: Option<bool>) -> EchoMacroGlobalHandler {
+        EchoMacroGlobalHandler {
+            is_flatpak: true,
+            clipboard_tool: None,
+            ydotool_status: Arc::new(Mutex::new(ToolStatus { available: Some(true), checked: Instant::now() })),
+            backend: Backend::Ydotool,
+            backend_forced: false,
+            start_daemon,
+            ydotool_bin: "ydotool".to_string(),
+            dry_run: false,
+            global_settings: Arc::new(RwLock::new(GlobalSettings::default())),
+            runner: runner.clone(),
+            command_timeout: None,
+        }
+    }
+    
+    #[tokio::test]
+    async fn global_start_daemon_starts_ydotoold_when_the_settings_arrive() {
+        let socket_missing = || MockRunner::new(|argv| Ok(if argv[2] == "test" { exited(1, "") } else { exited(0, "") }));
+        let started = |runner: &MockRunner| runner.calls().iter().any(|call| call[2..] == argv(&["systemctl", "--user", "start", YDOTOOLD_UNITS[0]]));
+        
+        let runner = socket_missing();
+        let global = daemon_global_handler(&runner, None);
+        global.start_daemon_if_asked().await;
+        assert!(runner.calls().is_empty());
+        store_global_settings(&global.global_settings, serde_json::json!({ "start_daemon": true })).unwrap();
+        global.start_daemon_if_asked().await;
+        assert!(started(&runner), "{:?}", runner.calls());
+        
+        // The command line's --start-daemon=off wins
+        let runner = socket_missing();
+        let global = daemon_global_handler(&runner, Some(false));
+        store_global_settings(&global.global_settings, serde_json::json!({ "start_daemon": true })).unwrap();
+        global.start_daemon_if_asked().await;
+        assert!(runner.calls().is_empty());
+        
+        // Nothing to start for xdotool
+        let runner = socket_missing();
+        let global = daemon_global_handler(&runner, None);
+        store_global_settings(&global.global_settings, serde_json::json!({ "start_daemon": true, "backend": "xdotool" })).unwrap();
+        global.start_daemon_if_asked().await;
+        assert!(runner.calls().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn clipboard_errors_tell_a_missing_tool_from_a_failing_one() {
+        let exits = |code: i32| MockRunner::new(move |_| Ok(exited(code, "")));
+        let mut handler = mock_handler(&exits(127), true).await;
+        handler.clipboard_tool = Some(ClipboardTool::Xclip);
+        
+        handler.runner = exits(127);
+        let result = handler.copy_to_clipboard("text").await;
+        assert!(matches!(result, Err(TypeError::HostToolMissing("xclip"))), "{:?}", result);
+        handler.runner = exits(1);
+        let result = handler.copy_to_clipboard("text").await;
+        assert!(matches!(result, Err(TypeError::ClipboardFailed)), "{:?}", result);
+        let result = handler.clear_clipboard().await;
+        assert!(matches!(result, Err(TypeError::ClipboardFailed)), "{:?}", result);
+        handler.runner = MockRunner::new(|_| Err(std::io::ErrorKind::NotFound.into()));
+        let result = handler.copy_to_clipboard("text").await;
+        assert!(matches!(result, Err(TypeError::HostToolMissing("xclip"))), "{:?}", result);
+        
+        handler.clipboard_tool = Some(ClipboardTool::Xsel);
+        let result = handler.set_clipboard(b"\x89PNG", Some("image/png")).await;
+        assert!(matches!(result, Err(TypeError::InvalidArguments(_))), "{:?}", result);
+        handler.clipboard_tool = None;
+        let result = handler.copy_to_clipboard("text").await;
+        assert!(matches!(result, Err(TypeError::HostToolMissing(_))), "{:?}", result);
+    }
+    
+    #[test]
+    fn webhook_payload_has_exactly_the_documented_fields() {
+        let report = PressReport { masked: Some("h*****2 (7 chars)".to_string()), chars: 7 };
+        let payload = webhook_payload("ctx", "com.example.type", &report, true, 1_700_000_000);
+        assert_eq!(payload, serde_json::json!({
+            "context": "ctx",
+            "action": "com.example.type",
+            "masked_text": "h*****2 (7 chars)",
+            "length": 7,
+            "success": true,
+            "timestamp": 1_700_000_000u64,
+        }));
+    }
+    
+    #[tokio::test]
+    async fn webhook_payload_only_carries_text_masked_by_the_policy() {
+        let settings = button_settings(serde_json::json!({ "text": "hunter2" }));
+        for policy in [LogTextPolicy::Masked, LogTextPolicy::Full, LogTextPolicy::LengthOnly, LogTextPolicy::Plain] {
+            let runner = MockRunner::succeeding();
+            let mut handler = mock_handler(&runner, false).await;
+            handler.log_text_policy = Some(policy);
+            let mut report = PressReport::default();
+            assert!(handler.deliver("ctx", &settings, 1, &mut report).await);
+            
+            let payload = webhook_payload("ctx", "action", &report, true, 0);
+            // Plain is for the log only - the webhook gets the masked form
+            let expected = if policy == LogTextPolicy::Plain { LogTextPolicy::Masked } else { policy };
+            assert_eq!(payload["masked_text"], expected.describe("hunter2"), "{:?}", policy);
+            assert_eq!(payload["length"], 7);
+            assert!(!payload.to_string().contains("hunter2"), "{:?}: {}", policy, payload);
+        }
+    }
+    
+    #[tokio::test]
+    async fn webhook_payload_reports_a_failed_press() {
+        let runner = MockRunner::new(|_| Ok(exited(1, "ydotool: bad argument")));
+        let handler = mock_handler(&runner, false).await;
+        let mut report = PressReport::default();
+        let success = handler.deliver("ctx", &button_settings(serde_json::json!({ "text": "hunter2" })), 1, &mut report).await;
+        assert!(!success);
+        
+        let payload = webhook_payload("ctx", "action", &report, success, 0);
+        assert_eq!(payload["success"], false);
+        assert_eq!(payload["masked_text"], LogTextPolicy::Masked.describe("hunter2"));
+        assert_eq!(payload["length"], 7);
+        assert!(!payload.to_string().contains("hunter2"));
+        
+        // A press that fails before the text is known reports none of it
+        let payload = webhook_payload("ctx", "action", &PressReport::default(), false, 0);
+        assert_eq!(payload["masked_text"], serde_json::Value::Null);
+        assert_eq!(payload["length"], 0);
+    }
+    
+    #[tokio::test]
+    async fn sensitive_buttons_never_show_the_first_or_last_char() {
+        let policies = [LogTextPolicy::Masked, LogTextPolicy::Full, LogTextPolicy::LengthOnly, LogTextPolicy::Plain];
+        let long = format!("Q{}Z", "x".repeat(40));
+        for text in ["Q", "QZ", "QxZ", long.as_str()] {
+            let leaks = |shown: &str| shown.contains('Q') || shown.contains('Z');
+            let typed = button_settings(serde_json::json!({ "text": text, "sensitive": true }));
+            let steps = button_settings(serde_json::json!({ "steps": [{ "type": text }], "sensitive": true }));
+            for policy in policies {
+                for settings in [&typed, &steps] {
+                    let runner = MockRunner::succeeding();
+                    let mut handler = mock_handler(&runner, false).await;
+                    handler.log_text_policy = Some(policy);
+                    let mut report = PressReport::default();
+                    assert!(handler.deliver("ctx", settings, 1, &mut report).await);
+                    
+                    if let Some(masked) = &report.masked {
+                        assert!(!leaks(masked), "{:?} {:?}: {}", text, policy, masked);
+                    }
+                    assert_eq!(title_preview(settings, policy), None);
+                    for call in runner.calls() {
+                        let args: Vec<&str> = call[1..].iter().map(String::as_str).collect();
+                        let command = dry_run_command(&call[0], &args);
+                        assert!(!leaks(&command), "{:?} {:?}: {}", text, policy, command);
+                    }
+                }
+            }
+            // What the log shows for a sensitive button, whatever the policy
+            assert!(!leaks(&LogTextPolicy::Full.describe(text)));
+            assert!(!leaks(&LogTextPolicy::LengthOnly.describe(text)));
+        }
+    }
+    
+    fn snippet_map(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+    
+    #[test]
+    fn snippet_references_resolve_nested_and_report_cycles_and_missing_ones() {
+        let snippets = snippet_map(&[
+            ("greeting", "Hello {snippet:name}!"),
+            ("name", "World"),
+            ("a", "A {snippet:b}"),
+            ("b", "B {snippet:a}"),
+            ("self", "{snippet:self}"),
+            ("broken", "see {snippet:nowhere}"),
+        ]);
+        assert_eq!(resolve_snippet("greeting", &snippets, &mut Vec::new()).unwrap(), "Hello World!");
+        
+        match resolve_snippet("a", &snippets, &mut Vec::new()) {
+            Err(SnippetError::Cycle(chain)) => assert_eq!(chain, ["a", "b", "a"]),
+            other => panic!("{:?}", other),
+        }
+        match resolve_snippet("self", &snippets, &mut Vec::new()) {
+            Err(SnippetError::Cycle(chain)) => assert_eq!(chain, ["self", "self"]),
+            other => panic!("{:?}", other),
+        }
+        
+        match resolve_snippet("missing", &snippets, &mut Vec::new()) {
+            Err(SnippetError::Missing(name)) => assert_eq!(name, "missing"),
+            other => panic!("{:?}", other),
+        }
+        // A reference inside a snippet names the one that's missing
+        match resolve_snippet("broken", &snippets, &mut Vec::new()) {
+            Err(SnippetError::Missing(name)) => assert_eq!(name, "nowhere"),
+            other => panic!("{:?}", other),
+        }
+    }
+    
+    #[test]
+    fn active_snippets_fall_back_when_no_set_is_chosen() {
+        let library = |json: serde_json::Value| serde_json::from_value::<SnippetLibrary>(json).unwrap();
+        let flat = serde_json::json!({ "sig": "Regards", "addr": "Main St" });
+        for env_set in [None, Some(""), Some("  ")] {
+            let (set, snippets) = library(flat.clone()).active_snippets(env_set).unwrap();
+            assert_eq!(set, DEFAULT_SNIPPET_SET);
+            assert_eq!(snippets, snippet_map(&[("sig", "Regards"), ("addr", "Main St")]), "{:?}", env_set);
+        }
+        
+        let sets = |active: serde_json::Value| library(serde_json::json!({
+            "active": active,
+            "sets": {
+                "default": { "sig": "Regards", "addr": "Main St" },
+                "work": { "sig": "Best" },
+                "empty": {},
+            },
+        }));
+        for active in [serde_json::Value::Null, serde_json::json!(""), serde_json::json!(" ")] {
+            let (set, snippets) = sets(active.clone()).active_snippets(None).unwrap();
+            assert_eq!(set, DEFAULT_SNIPPET_SET, "{}", active);
+            assert_eq!(snippets, snippet_map(&[("sig", "Regards"), ("addr", "Main St")]), "{}", active);
+        }
+        // An empty set shows the whole default set
+        let (set, snippets) = sets(serde_json::json!("empty")).active_snippets(None).unwrap();
+        assert_eq!(set, "empty");
+        assert_eq!(snippets, snippet_map(&[("sig", "Regards"), ("addr", "Main St")]));
+        // A chosen set overrides the default's snippets, and the environment wins over the file
+        let (_, snippets) = sets(serde_json::json!("empty")).active_snippets(Some("work")).unwrap();
+        assert_eq!(snippets, snippet_map(&[("sig", "Best"), ("addr", "Main St")]));
+        
+        assert_eq!(sets(serde_json::json!("home")).active_snippets(None).unwrap_err(), "home");
+        assert_eq!(library(flat).active_snippets(Some("work")).unwrap_err(), "work");
+    }
+    
+    #[test]
+    fn collapse_whitespace_folds_runs_of_blanks() {
+        // (input, keeping newlines, with collapse_newlines)
+        let cases = [
+            ("a\tb", "a b", "a b"),
+            ("a \t \tb", "a b", "a b"),
+            ("a     b  c", "a b c", "a b c"),
+            ("a\nb", "a\nb", "a b"),
+            ("a  \n\n  b", "a \n\n b", "a b"),
+            ("a\r\n\tb", "a\r\n b", "a b"),
+            // No trim step - a leading or trailing run becomes one space
+            ("  a b\t", " a b ", " a b "),
+            ("\n a\n", "\n a\n", " a "),
+            ("", "", ""),
+            ("a b", "a b", "a b"),
+        ];
+        for (input, kept, joined) in cases {
+            assert_eq!(collapse_whitespace(input, false), kept, "{:?}", input);
+            assert_eq!(collapse_whitespace(input, true), joined, "{:?}", input);
+        }
+    }
+    
+    #[test]
+    fn break_long_words_splits_only_words_over_the_limit() {
+        assert_eq!(break_long_words("abcd ef", 4, "|"), "abcd ef");
+        assert_eq!(break_long_words("abcde ef", 4, "|"), "abcd|e ef");
+        assert_eq!(break_long_words("abcdefghi", 4, "|"), "abcd|efgh|i");
+        // Whitespace of any kind ends a word
+        assert_eq!(break_long_words("abcd\tabcd\nabcde", 4, "|"), "abcd\tabcd\nabcd|e");
+        
+        // Counted in chars, so a break never lands inside one
+        assert_eq!(break_long_words("ééééé", 4, "|"), "éééé|é");
+        assert_eq!(break_long_words("日本語日本", 2, "\u{200B}"), "日本\u{200B}語日\u{200B}本");
+        assert_eq!(break_long_words("👍👍👍 ok", 3, "-"), "👍👍👍 ok");
+        assert_eq!(break_long_words("a👍b👍", 3, "-"), "a👍b-👍");
+    }
+    
+    #[test]
+    fn prefix_lines_handles_empty_input_trailing_newlines_and_crlf() {
+        let cases = [
+            // (input, empty lines prefixed, empty lines skipped)
+            ("", ">", ""),
+            ("a", "> a", "> a"),
+            ("a\n", "> a\n", "> a\n"),
+            ("a\nb\n", "> a\n> b\n", "> a\n> b\n"),
+            ("a\n\n", "> a\n>\n", "> a\n\n"),
+            ("\n", ">\n", "\n"),
+            ("a\r\nb", "> a\r\n> b", "> a\r\n> b"),
+            ("a\r\n", "> a\r\n", "> a\r\n"),
+            ("a\r\n\r\nb\r\n", "> a\r\n>\r\n> b\r\n", "> a\r\n\r\n> b\r\n"),
+        ];
+        for (input, prefixed, skipped) in cases {
+            assert_eq!(prefix_lines(input, "> ", EmptyLinePolicy::Prefix), prefixed, "{:?}", input);
+            assert_eq!(prefix_lines(input, "> ", EmptyLinePolicy::Skip), skipped, "{:?}", input);
+        }
+    }
+    
+    #[tokio::test]
+    async fn presses_in_the_batch_window_are_typed_in_one_go() {
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        let settings = button_settings(serde_json::json!({ "text": "hi", "batch_window_ms": 100 }));
+        
+        // Well inside the 30ms debounce, yet none of them is dropped
+        for _ in 0..3 {
+            assert_eq!(handler.admit_press("ctx", &settings), PressAdmission::Batched);
+        }
+        assert!(runner.calls().is_empty());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(runner.calls(), [argv(&["ydotool", "type", "--", "hihihi"])]);
+        
+        // Without a batch window the second quick press is a bounce
+        let settings = button_settings(serde_json::json!({ "text": "hi" }));
+        assert_eq!(handler.admit_press("other", &settings), PressAdmission::Accepted);
+        assert!(matches!(handler.admit_press("other", &settings), PressAdmission::Ignored(_)));
+    }
+    
+    #[tokio::test]
+    async fn timed_hold_presses_waits_and_releases() {
+        let times = Arc::new(Mutex::new(Vec::new()));
+        let recorded = times.clone();
+        let runner = MockRunner::new(move |_| {
+            recorded.lock().unwrap().push(Instant::now());
+            Ok(exited(0, ""))
+        });
+        let handler = mock_handler(&runner, false).await;
+        times.lock().unwrap().clear();
+        let settings = button_settings(serde_json::json!({ "method": "timed_hold", "hold_key": "shift", "hold_duration_ms": 60 }));
+        
+        assert!(handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+        assert_eq!(runner.calls(), [argv(&["ydotool", "key", "42:1"]), argv(&["ydotool", "key", "42:0"])]);
+        let held = {
+            let times = times.lock().unwrap();
+            times[1] - times[0]
+        };
+        assert!(held >= Duration::from_millis(60), "{:?}", held);
+        
+        // Nothing is pressed without a key it knows
+        runner.clear();
+        let settings = button_settings(serde_json::json!({ "method": "timed_hold", "hold_key": "nope" }));
+        assert!(!handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+        assert!(runner.calls().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn a_locked_button_ignores_presses_until_the_lock_expires() {
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        let settings = button_settings(serde_json::json!({ "text": "hi", "debounce_ms": 0 }));
+        
+        handler.locked_until.lock().unwrap().insert("ctx".to_string(), Instant::now() + Duration::from_millis(100));
+        assert_eq!(handler.admit_press("ctx", &settings), PressAdmission::Locked);
+        assert_eq!(handler.admit_press("ctx", &settings), PressAdmission::Locked);
+        // Only that button is locked
+        assert_eq!(handler.admit_press("other", &settings), PressAdmission::Accepted);
+        
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(handler.admit_press("ctx", &settings), PressAdmission::Accepted);
+        assert_eq!(handler.admit_press("ctx", &settings), PressAdmission::Accepted);
+    }
+    
+    #[test]
+    fn unknown_action_uuids_are_not_taken_for_ours() {
+        assert_eq!(known_action(&action_uuid(TYPE_TEXT_ACTION)), Some(TYPE_TEXT_ACTION));
+        assert_eq!(known_action(&action_uuid(PRESENTATION_MODE_ACTION)), Some(PRESENTATION_MODE_ACTION));
+        assert_eq!(known_action(&action_uuid(HEALTH_CHECK_ACTION)), Some(HEALTH_CHECK_ACTION));
+        
+        for unknown in [
+            action_uuid("type-text-v2"),
+            action_uuid(""),
+            format!("{}type-text", PLUGIN_ID),
+            format!("{}.extra.type-text", PLUGIN_ID),
+            "com.example.other.type-text".to_string(),
+            TYPE_TEXT_ACTION.to_string(),
+            String::new(),
+        ] {
+            assert_eq!(known_action(&unknown), None, "{:?}", unknown);
+        }
+    }
+    
+    #[test]
+    fn permission_hints_name_the_running_app() {
+        let hint = |vars| flatpak_override_hint("org.freedesktop.Flatpak", &host_app_id_with(env_of(vars)));
+        assert_eq!(
+            hint(&[("FLATPAK_ID", "org.example.DeckFork")]),
+            "flatpak override --user --talk-name=org.freedesktop.Flatpak org.example.DeckFork"
+        );
+        // Outside a Flatpak, or with a blank id, the built-in app id is shown
+        for vars in [&[][..], &[("FLATPAK_ID", " ")]] {
+            assert!(hint(vars).ends_with(&format!(" {}", DEFAULT_HOST_APP_ID)), "{}", hint(vars));
+        }
+    }
+    
+    #[tokio::test]
+    async fn the_pointer_goes_back_after_typing_whether_or_not_it_worked() {
+        let settings = button_settings(serde_json::json!({
+            "text": "hi",
+            "pre_type_mouse_move": [500, 300],
+            "restore_mouse_position": true,
+        }));
+        let moved = argv(&["ydotool", "mousemove", "--absolute", "-x", "500", "-y", "300"]);
+        let restored = argv(&["ydotool", "mousemove", "--absolute", "-x", "10", "-y", "20"]);
+        let typed = argv(&["ydotool", "type", "--", "hi"]);
+        for type_works in [true, false] {
+            let runner = MockRunner::new(move |argv| match (argv[0].as_str(), argv[1].as_str()) {
+                ("xdotool", "getmouselocation") => Ok(Output { stdout: b"X=10\nY=20\nSCREEN=0\nWINDOW=1\n".to_vec(), ..exited(0, "") }),
+                ("ydotool", "type") if !type_works => Ok(exited(1, "ydotool: bad argument")),
+                _ => Ok(exited(0, "")),
+            });
+            let handler = mock_handler(&runner, false).await;
+            let delivered = handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await;
+            assert_eq!(delivered, type_works);
+            
+            let calls = runner.calls();
+            let typed_at = calls.iter().position(|call| *call == typed).unwrap();
+            assert!(calls[..typed_at].contains(&moved), "{:?}", calls);
+            assert_eq!(calls.last(), Some(&restored), "type worked: {}: {:?}", type_works, calls);
+        }
+    }
+    
+    #[tokio::test]
+    async fn typing_is_skipped_only_when_the_selection_matches() {
+        let selection = |stdout: &'static str, code: i32| MockRunner::new(move |argv| {
+            Ok(if argv[0] == "xclip" { Output { stdout: stdout.as_bytes().to_vec(), ..exited(code, "") } } else { exited(0, "") })
+        });
+        let mut handler = mock_handler(&MockRunner::succeeding(), false).await;
+        handler.clipboard_tool = Some(ClipboardTool::Xclip);
+        
+        handler.runner = selection("hello", 0);
+        assert!(handler.selection_matches("hello", true).await);
+        for (runner, x11, why) in [
+            (selection("hello", 0), false, "not X11"),
+            (selection("hello world", 0), true, "different text"),
+            (selection("", 1), true, "nothing selected"),
+        ] {
+            handler.runner = runner.clone();
+            assert!(!handler.selection_matches("hello", x11).await, "{}", why);
+            assert_eq!(runner.calls().is_empty(), !x11, "{}", why);
+        }
+        // A selection tool that can't run means typing goes ahead
+        handler.runner = MockRunner::new(|_| Err(std::io::ErrorKind::NotFound.into()));
+        assert!(!handler.selection_matches("hello", true).await);
+        handler.clipboard_tool = None;
+        handler.runner = selection("hello", 0);
+        assert!(!handler.selection_matches("hello", true).await);
+    }
+    
+    #[tokio::test]
+    async fn undo_boundary_key_is_pressed_right_before_the_text() {
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        let settings = button_settings(serde_json::json!({ "text": "hi", "pre_undo_boundary_key": "f12" }));
+        assert!(handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+        let code = keycode("f12").unwrap();
+        assert_eq!(runner.calls(), [
+            argv(&["ydotool", "key", &format!("{}:1", code), &format!("{}:0", code)]),
+            argv(&["ydotool", "type", "--", "hi"]),
+        ]);
+        
+        // A boundary key that fails to press stops the text, and an unknown one is never sent
+        let runner = MockRunner::new(|argv| Ok(if argv[1] == "key" { exited(1, "ydotool: error") } else { exited(0, "") }));
+        let handler = mock_handler(&runner, false).await;
+        assert!(!handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+        assert!(runner.calls().iter().all(|call| call[1] != "type"), "{:?}", runner.calls());
+        
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        let settings = button_settings(serde_json::json!({ "text": "hi", "pre_undo_boundary_key": "nope" }));
+        assert!(!handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+        assert!(runner.calls().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn clipboard_only_runs_just_the_copy_command() {
+        let cases = [
+            (ClipboardTool::WlClipboard, argv(&["wl-copy"])),
+            (ClipboardTool::Xclip, argv(&["xclip", "-selection", "clipboard"])),
+            (ClipboardTool::Xsel, argv(&["xsel", "--clipboard", "--input"])),
+        ];
+        let settings = button_settings(serde_json::json!({ "method": "clipboard_only", "text": "note" }));
+        for (tool, copy) in cases {
+            let runner = MockRunner::succeeding();
+            let mut handler = mock_handler(&runner, false).await;
+            handler.clipboard_tool = Some(tool);
+            assert!(handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await, "{:?}", tool);
+            assert_eq!(runner.calls(), [copy], "{:?}", tool);
+        }
+        
+        let runner = MockRunner::succeeding();
+        let mut handler = mock_handler(&runner, false).await;
+        handler.clipboard_tool = None;
+        assert!(!handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+        assert!(runner.calls().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn stuck_keys_are_released_at_start_unless_ydotool_is_missing() {
+        assert!(should_release_stuck_keys(true, Some(true)));
+        assert!(should_release_stuck_keys(true, None));
+        assert!(!should_release_stuck_keys(true, Some(false)));
+        assert!(!should_release_stuck_keys(false, Some(true)));
+        
+        let runner = MockRunner::succeeding();
+        release_stuck_keys(&*runner, true, "/opt/ydotool", None).await;
+        let mut expected = argv(&["flatpak-spawn", "--host", "/opt/ydotool"]);
+        expected.extend(key_reset_args());
+        assert_eq!(runner.calls(), [expected]);
+        assert!(key_reset_args().iter().skip(1).all(|arg| arg.ends_with(":0")), "only key-ups are sent");
+        
+        // A missing or failing ydotool is only logged
+        let runner = MockRunner::new(|_| Err(std::io::Error::from(std::io::ErrorKind::NotFound)));
+        release_stuck_keys(&*runner, false, "ydotool", None).await;
+        let runner = MockRunner::new(|_| Ok(exited(1, "failed to connect socket")));
+        release_stuck_keys(&*runner, false, "ydotool", None).await;
+        assert_eq!(runner.calls().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn appearing_warms_the_daemon_at_most_once_per_interval() {
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        let settings = button_settings(serde_json::json!({ "text": "hi", "warm_daemon_on_appear": true }));
+        let warm_up = argv(&["ydotool", "key", &format!("{}:0", keycode("shift").unwrap())]);
+        
+        handler.appeared("a", &settings);
+        handler.appeared("b", &settings);
+        handler.appeared("a", &settings);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while runner.calls().is_empty() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(runner.calls(), std::slice::from_ref(&warm_up));
+        
+        // Due again once the interval has passed
+        *handler.last_warmup.lock().unwrap() = Instant::now().checked_sub(DAEMON_WARMUP_INTERVAL);
+        handler.appeared("a", &settings);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while runner.calls().len() < 2 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(runner.calls(), [warm_up.clone(), warm_up]);
+        
+        // Off by default
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        handler.appeared("a", &button_settings(serde_json::json!({ "text": "hi" })));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(runner.calls().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn disappearing_forgets_the_buttons_state() {
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        for context in ["gone", "kept"] {
+            handler.counters.lock().unwrap().insert(context.to_string(), HashMap::from([("n".to_string(), 3)]));
+            handler.snippet_rotations.lock().unwrap().insert(context.to_string(), SnippetRotation { fingerprint: 1, last: 0 });
+            handler.pending_batches.lock().unwrap().insert(context.to_string(), 2);
+            handler.last_press.lock().unwrap().insert(context.to_string(), Instant::now());
+        }
+        
+        handler.forget_context("gone").await;
+        assert!(!handler.counters.lock().unwrap().contains_key("gone"));
+        assert!(!handler.snippet_rotations.lock().unwrap().contains_key("gone"));
+        assert!(!handler.pending_batches.lock().unwrap().contains_key("gone"));
+        assert!(!handler.last_press.lock().unwrap().contains_key("gone"));
+        assert!(handler.counters.lock().unwrap().contains_key("kept"));
+        assert!(handler.snippet_rotations.lock().unwrap().contains_key("kept"));
+    }
+    
+    #[tokio::test]
+    async fn presentation_mode_stops_every_button_from_typing() {
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        let settings = button_settings(serde_json::json!({ "text": "hi" }));
+        // Each event gets its own clone of the handler, so the switch must be shared
+        let toggled = handler.clone();
+        toggled.presentation_mode.store(true, Ordering::SeqCst);
+        
+        for context in ["a", "b"] {
+            assert!(!handler.deliver(context, &settings, 1, &mut PressReport::default()).await, "{}", context);
+        }
+        let keys = button_settings(serde_json::json!({ "keys": "ctrl+c" }));
+        assert!(!handler.deliver("c", &keys, 1, &mut PressReport::default()).await);
+        assert!(runner.calls().is_empty());
+        
+        toggled.presentation_mode.store(false, Ordering::SeqCst);
+        assert!(handler.deliver("a", &settings, 1, &mut PressReport::default()).await);
+        assert_eq!(runner.calls(), [argv(&["ydotool", "type", "--", "hi"])]);
+    }
+    
+    #[tokio::test]
+    async fn appearing_again_keeps_state_unless_asked_to_reset() {
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        let mut settings = button_settings(serde_json::json!({
+            "text": "{n}",
+            "counter_template": true,
+            "persist_counters": true,
+            "counter_state": { "n": 41 },
+        }));
+        let rotation = SnippetRotation { fingerprint: 1, last: 2 };
+        let typed = || {
+            let calls = runner.calls();
+            runner.clear();
+            calls
+        };
+        
+        assert!(handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+        assert_eq!(typed(), [argv(&["ydotool", "type", "--", "42"])]);
+        handler.snippet_rotations.lock().unwrap().insert("ctx".to_string(), rotation);
+        handler.appeared("ctx", &settings);
+        assert!(handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+        assert_eq!(typed(), [argv(&["ydotool", "type", "--", "43"])]);
+        assert_eq!(handler.snippet_rotations.lock().unwrap().get("ctx"), Some(&rotation));
+        
+        // The persisted state isn't reloaded either
+        settings.reset_state_on_appear = true;
+        handler.appeared("ctx", &settings);
+        assert!(handler.snippet_rotations.lock().unwrap().get("ctx").is_none());
+        assert!(handler.deliver("ctx", &settings, 1, &mut PressReport::default()).await);
+        assert_eq!(typed(), [argv(&["ydotool", "type", "--", "1"])]);
+    }
+    
+    #[tokio::test]
+    async fn bells_ring_only_for_the_outcomes_switched_on() {
+        for (success, on_success, on_failure, sound) in [
+            (true, false, false, None),
+            (false, false, false, None),
+            (true, true, false, Some("complete")),
+            (false, true, false, None),
+            (true, false, true, None),
+            (false, false, true, Some("dialog-error")),
+            (true, true, true, Some("complete")),
+            (false, true, true, Some("dialog-error")),
+        ] {
+            let settings = button_settings(serde_json::json!({ "bell_on_success": on_success, "bell_on_failure": on_failure }));
+            assert_eq!(bell_sound(&settings, success), sound, "success {} / on_success {} / on_failure {}", success, on_success, on_failure);
+            
+            let runner = MockRunner::succeeding();
+            let handler = mock_handler(&runner, true).await;
+            handler.ring_bell(&settings, success);
+            let deadline = Instant::now() + Duration::from_millis(500);
+            while sound.is_some() && runner.calls().is_empty() && Instant::now() < deadline {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let expected: Vec<_> = sound.map(|s| argv(&["flatpak-spawn", "--host", "canberra-gtk-play", &format!("--id={}", s)])).into_iter().collect();
+            assert_eq!(runner.calls(), expected, "success {} / on_success {} / on_failure {}", success, on_success, on_failure);
+        }
+        
+        // A missing canberra-gtk-play is only logged
+        let runner = MockRunner::new(|_| Err(std::io::Error::from(std::io::ErrorKind::NotFound)));
+        let mut handler = mock_handler(&MockRunner::succeeding(), false).await;
+        handler.runner = runner.clone();
+        handler.ring_bell(&button_settings(serde_json::json!({ "bell_on_failure": true })), false);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(runner.calls().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn a_waiting_press_does_its_pre_delay_before_its_turn() {
+        let runner = MockRunner::succeeding();
+        let handler = mock_handler(&runner, false).await;
+        let turn = handler.typing_turn.clone().lock_owned().await;
+        
+        let started = Instant::now();
+        let waiting = handler.clone();
+        let press = tokio::spawn(async move {
+            let settings = button_settings(serde_json::json!({ "text": "hi", "pre_delay_ms": 300 }));
+            waiting.deliver("b", &settings, 1, &mut PressReport::default()).await
+        });
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(runner.calls().is_empty(), "typed while another press had the turn");
+        drop(turn);
+        
+        assert!(press.await.unwrap());
+        assert_eq!(runner.calls(), [argv(&["ydotool", "type", "--", "hi"])]);
+        // Waiting for the turn first would have added the whole pre-delay on top
+        assert!(started.elapsed() < Duration::from_millis(500), "took {:?}", started.elapsed());
+    }
+    
+    #[tokio::test]
+    async fn long_texts_hold_the_turn_as_long_as_the_command_limit() {
+        let runner = MockRunner::succeeding();
+        let mut handler = mock_handler(&runner, false).await;
+        handler.command_timeout = Some(Duration::from_secs(5));
+        let settings = button_settings(serde_json::json!({ "delay_ms": 12, "line_delay_ms": 100 }));
+        
+        let short = handler.typing_time_limit("hi", &settings);
+        assert_eq!(short, command_time_limit(Duration::from_secs(5), Backend::Ydotool, &["--key-delay", "12", "--", "hi"]));
+        assert!(short < TYPING_TURN_TIMEOUT);
+        
+        let long = "x".repeat(1000) + "\n" + &"y".repeat(1000);
+        let limit = handler.typing_time_limit(&long, &settings);
+        assert_eq!(limit, command_time_limit(Duration::from_secs(5), Backend::Ydotool, &["--key-delay", "12", "--", &long]) + Duration::from_millis(100));
+        assert!(limit > TYPING_TURN_TIMEOUT);
+        
+        // Whoever takes the turn next publishes its limit for those waiting behind it
+        drop(handler.wait_for_turn(limit).await.unwrap());
+        assert_eq!(*handler.turn_limit.lock().unwrap(), limit);
+        
+        handler.command_timeout = None;
+        assert_eq!(handler.typing_time_limit(&long, &settings), Duration::ZERO);
+    }
+}